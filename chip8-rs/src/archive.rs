@@ -0,0 +1,241 @@
+use std::io::{Cursor, Read};
+
+use tracing::warn;
+
+use chip8::platform::Platform;
+
+/// Extensions of the files in an archive that are taken for ROMs
+const ROM_EXTENSIONS: [&str; 3] = ["ch8", "sc8", "xo8"];
+
+/// A ROM file inside a zip archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Path of the file inside the archive
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Entry {
+    /// The name without the directories leading up to it
+    pub fn file_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+}
+
+/// Whether `bytes` are a zip archive rather than a ROM
+pub fn is_zip(bytes: &[u8]) -> bool {
+    // Local file header, or the end of central directory record of an empty archive
+    bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06")
+}
+
+/// Reads the ROMs in a zip archive, in the order they are stored. Other files are skipped, as
+/// are files too large to fit in the memory of any platform.
+pub fn entries(bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(|err| err.to_string())?;
+        if !file.is_file() || !is_rom(file.name()) {
+            continue;
+        }
+
+        // The sizes in the archive can't be trusted, a zip bomb could claim anything
+        let name = file.name().to_string();
+        let max = Platform::XoChip.max_rom_size();
+        if file.size() > max as u64 {
+            warn!(
+                "Skipping {}, {} bytes is too large for a ROM",
+                name,
+                file.size()
+            );
+            continue;
+        }
+        let mut bytes = Vec::with_capacity(file.size() as usize);
+        file.by_ref()
+            .take(max as u64 + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|err| format!("could not extract {}: {}", name, err))?;
+        if bytes.len() > max {
+            warn!("Skipping {}, it is too large for a ROM", name);
+            continue;
+        }
+        entries.push(Entry { name, bytes });
+    }
+    Ok(entries)
+}
+
+fn is_rom(name: &str) -> bool {
+    // macOS stores resource forks under __MACOSX with the same names as the real files
+    if name.starts_with("__MACOSX/") {
+        return false;
+    }
+    name.rsplit_once('.').is_some_and(|(_, extension)| {
+        ROM_EXTENSIONS
+            .iter()
+            .any(|rom| extension.eq_ignore_ascii_case(rom))
+    })
+}
+
+/// Picks a ROM by its index in `entries`, its path, its file name or its file name without
+/// the extension. Without a `selector` the archive has to hold exactly one ROM.
+pub fn select(entries: Vec<Entry>, selector: Option<&str>) -> Result<Entry, String> {
+    let index = match selector {
+        None if entries.len() == 1 => Some(0),
+        None if entries.is_empty() => return Err("archive holds no CHIP-8 ROMs".into()),
+        None => {
+            let names = entries
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| format!("\n  {:>3}  {}", index, entry.name))
+                .collect::<String>();
+            return Err(format!(
+                "archive holds {} ROMs, pick one with --entry:{}",
+                entries.len(),
+                names
+            ));
+        }
+        Some(selector) => match selector.parse::<usize>() {
+            Ok(index) => Some(index).filter(|&index| index < entries.len()),
+            Err(_) => entries
+                .iter()
+                .position(|entry| entry.name == selector)
+                .or_else(|| {
+                    entries
+                        .iter()
+                        .position(|entry| entry.file_name().eq_ignore_ascii_case(selector))
+                })
+                .or_else(|| {
+                    entries.iter().position(|entry| {
+                        entry
+                            .file_name()
+                            .rsplit_once('.')
+                            .is_some_and(|(stem, _)| stem.eq_ignore_ascii_case(selector))
+                    })
+                }),
+        },
+    };
+    match index {
+        Some(index) => Ok(entries.into_iter().nth(index).unwrap()),
+        None => Err(format!(
+            "archive has no ROM {}",
+            selector.unwrap_or_default()
+        )),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+    use zip::CompressionMethod;
+
+    use super::*;
+
+    /// Builds a zip archive holding `files`, deflating every other one
+    pub fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (index, (name, bytes)) in files.iter().enumerate() {
+            let method = if index % 2 == 0 {
+                CompressionMethod::Stored
+            } else {
+                CompressionMethod::Deflated
+            };
+            writer
+                .start_file(*name, FileOptions::default().compression_method(method))
+                .unwrap();
+            writer.write_all(bytes).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_entries() {
+        let bytes = zip(&[
+            ("README.txt", b"not a ROM"),
+            ("games/Pong.ch8", &[0x12, 0x00]),
+            ("__MACOSX/games/._Pong.ch8", &[0x00]),
+            ("games/Blinky.SC8", &[0x00, 0xE0, 0x12, 0x02]),
+        ]);
+        assert!(is_zip(&bytes));
+        assert!(!is_zip(&[0x12, 0x00]));
+
+        let entries = entries(&bytes).unwrap();
+        assert_eq!(
+            entries,
+            [
+                Entry {
+                    name: "games/Pong.ch8".into(),
+                    bytes: vec![0x12, 0x00],
+                },
+                Entry {
+                    name: "games/Blinky.SC8".into(),
+                    bytes: vec![0x00, 0xE0, 0x12, 0x02],
+                },
+            ]
+        );
+        assert_eq!(entries[0].file_name(), "Pong.ch8");
+
+        // Compresses to almost nothing but is larger than any ROM
+        let large = vec![0; Platform::XoChip.max_rom_size() + 1];
+        let bytes = zip(&[("small.ch8", &[0x00, 0xE0]), ("large.xo8", &large)]);
+        let names = super::entries(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["small.ch8"]);
+
+        // Still caught when the central directory lies about the size
+        let mut bytes = bytes;
+        let directory = bytes
+            .windows(4)
+            .rposition(|window| window == b"PK\x01\x02")
+            .unwrap();
+        bytes[directory + 24..directory + 28].copy_from_slice(&2u32.to_le_bytes());
+        let names = super::entries(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["small.ch8"]);
+
+        assert!(super::entries(b"PK\x03\x04 truncated").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let entries = entries(&zip(&[
+            ("games/pong.ch8", &[1]),
+            ("games/blinky.sc8", &[2]),
+        ]))
+        .unwrap();
+        let select = |selector| select(entries.clone(), selector).map(|entry| entry.bytes[0]);
+
+        assert_eq!(select(Some("1")), Ok(2));
+        assert_eq!(select(Some("games/pong.ch8")), Ok(1));
+        assert_eq!(select(Some("BLINKY.sc8")), Ok(2));
+        assert_eq!(select(Some("pong")), Ok(1));
+        assert_eq!(select(Some("2")), Err("archive has no ROM 2".into()));
+        assert_eq!(
+            select(Some("tetris")),
+            Err("archive has no ROM tetris".into())
+        );
+        assert_eq!(
+            select(None),
+            Err(concat!(
+                "archive holds 2 ROMs, pick one with --entry:\n",
+                "    0  games/pong.ch8\n",
+                "    1  games/blinky.sc8"
+            )
+            .into())
+        );
+
+        let single = vec![entries[1].clone()];
+        assert_eq!(super::select(single, None), Ok(entries[1].clone()));
+        assert_eq!(
+            super::select(Vec::new(), None),
+            Err("archive holds no CHIP-8 ROMs".into())
+        );
+    }
+}
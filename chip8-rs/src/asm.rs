@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use chip8::asm::{self, AsmError};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct AsmArgs {
+    /// Write the ROM to a file, defaults to the source file with a .ch8 extension
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Assembly source file
+    #[arg(value_parser, required = true)]
+    file: String,
+}
+
+pub fn run(args: AsmArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let source = Path::new(&args.file);
+    let rom = asm::assemble_file(source).map_err(with_context)?;
+
+    let output = args
+        .output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension("ch8"));
+    std::fs::write(&output, &rom)?;
+    println!("Wrote {} bytes to {}", rom.len(), output.display());
+
+    Ok(())
+}
+
+/// Appends the offending source line to the error with the span underlined
+fn with_context(err: AsmError) -> String {
+    let Some(line) = err
+        .file
+        .as_ref()
+        .and_then(|file| std::fs::read_to_string(file).ok())
+        .and_then(|source| source.lines().nth(err.span.line - 1).map(str::to_owned))
+    else {
+        return err.to_string();
+    };
+
+    format!(
+        "{}\n{:>5} | {}\n      | {}{}",
+        err,
+        err.span.line,
+        line,
+        " ".repeat(err.span.column - 1),
+        "^".repeat(err.span.len.max(1))
+    )
+}
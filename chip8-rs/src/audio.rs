@@ -0,0 +1,57 @@
+use rodio::buffer::SamplesBuffer;
+use rodio::{OutputStream, Sink};
+use tracing::error;
+
+use chip8::audio::{AudioFrame, AudioSink, Synth, SAMPLE_RATE};
+
+/// How many frames of audio may be queued before new ones are dropped, keeps the latency
+/// bounded when the emulator runs ahead of the sound card
+const MAX_QUEUED_FRAMES: usize = 4;
+
+/// Plays the buzzer on the default output device
+pub struct SpeakerSink {
+    // Playback stops once the stream is dropped
+    _stream: OutputStream,
+    sink: Sink,
+    synth: Synth,
+}
+
+impl SpeakerSink {
+    /// Opens the default output device, returns `None` if there is none
+    pub fn open() -> Option<SpeakerSink> {
+        let result = OutputStream::try_default()
+            .map_err(|err| err.to_string())
+            .and_then(|(stream, handle)| {
+                let sink = Sink::try_new(&handle).map_err(|err| err.to_string())?;
+                Ok((stream, sink))
+            });
+
+        match result {
+            Ok((stream, sink)) => Some(SpeakerSink {
+                _stream: stream,
+                sink,
+                synth: Synth::new(SAMPLE_RATE),
+            }),
+            Err(err) => {
+                error!(
+                    "Could not open audio device, running without sound: {}",
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+impl AudioSink for SpeakerSink {
+    fn frame(&mut self, frame: &AudioFrame) {
+        if self.sink.len() >= MAX_QUEUED_FRAMES {
+            return;
+        }
+
+        let mut samples = Vec::new();
+        self.synth.render(frame, &mut samples);
+        self.sink
+            .append(SamplesBuffer::new(1, self.synth.sample_rate(), samples));
+    }
+}
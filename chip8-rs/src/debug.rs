@@ -0,0 +1,397 @@
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use chip8::data::Address;
+use chip8::debugger::{Comparison, Condition, Debugger, Operand, Watch};
+use chip8::system::System;
+
+const HELP: &str = "\
+Commands:
+  c, continue              Resume execution
+  p, pause                 Pause execution
+  s, step                  Run one instruction
+  n, next                  Run one instruction, stepping over subroutine calls
+  f, finish                Run until the current subroutine returns
+  b, break ADDR [if COND]  Set a breakpoint, e.g. `b 0x204 if V3 == 0x10` or `b 0x300 if I >= 0x400`
+  b, break draw|key        Toggle pausing after every DRW or LD Vx, K
+  d, delete ADDR           Remove a breakpoint
+  w, watch ADDR [r|w|rw]   Pause when an instruction accesses a byte, writes by default
+  u, unwatch ADDR          Remove a watchpoint
+  i, info                  List breakpoints and watchpoints
+  r, regs                  Show registers, timers and the stack
+  x ADDR [LEN]             Dump memory
+  q, quit                  Close the emulator
+Numbers are decimal, or hexadecimal with a 0x prefix.";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Continue,
+    Pause,
+    Step,
+    Next,
+    Finish,
+    Break(Address, Option<Condition>),
+    BreakOnDraw,
+    BreakOnKeyWait,
+    Delete(Address),
+    Watch(Address, Watch),
+    Unwatch(Address),
+    Info,
+    Registers,
+    Examine(Address, usize),
+    Help,
+    Quit,
+}
+
+/// A debugger prompt on stdin, read on a separate thread so the window stays responsive
+pub struct Repl {
+    lines: Receiver<String>,
+    faulted: bool,
+}
+
+impl Repl {
+    pub fn spawn() -> Repl {
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!(
+            "Debugger attached, paused at the entry point. Type `help` for a list of commands."
+        );
+        Repl {
+            lines,
+            faulted: false,
+        }
+    }
+
+    /// Runs the commands typed since the last call. Returns `false` once the user quits.
+    pub fn poll(&mut self, system: &mut System) -> bool {
+        loop {
+            let line = match self.lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => return true,
+                // Stdin was closed, leave the emulator running
+                Err(TryRecvError::Disconnected) => return true,
+            };
+            if line.trim().is_empty() {
+                prompt();
+                continue;
+            }
+
+            match parse(&line) {
+                Ok(Command::Quit) => return false,
+                Ok(command) => execute(system, command),
+                Err(err) => println!("{}", err),
+            }
+            prompt();
+        }
+    }
+
+    /// Reports why the CPU stopped, if it stopped since the last call
+    pub fn report(&mut self, system: &mut System) {
+        if let Some(err) = system.fault() {
+            if !self.faulted {
+                self.faulted = true;
+                println!("CPU halted: {}", err);
+                print_location(system);
+                prompt();
+            }
+            return;
+        }
+        self.faulted = false;
+
+        let reason = system.debugger_mut().and_then(Debugger::take_break);
+        if let Some(reason) = reason {
+            println!("{}", reason);
+            print_location(system);
+            prompt();
+        }
+    }
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = std::io::stdout().flush();
+}
+
+fn execute(system: &mut System, command: Command) {
+    match command {
+        Command::Registers => return print_registers(system),
+        Command::Examine(addr, len) => return print_memory(system, addr, len),
+        Command::Help => return println!("{}", HELP),
+        _ => {}
+    }
+
+    let Some(debugger) = system.debugger_mut() else {
+        return;
+    };
+    match command {
+        Command::Continue => debugger.resume(),
+        Command::Pause => debugger.pause(),
+        Command::Step => debugger.step(),
+        Command::Next => debugger.step_over(),
+        Command::Finish => debugger.step_out(),
+        Command::Break(addr, condition) => {
+            debugger.set_breakpoint(addr, condition);
+            println!("Breakpoint at 0x{:03X}", addr);
+        }
+        Command::BreakOnDraw => {
+            let enabled = !debugger.break_on_draw();
+            debugger.set_break_on_draw(enabled);
+            println!("Break on draw {}", if enabled { "on" } else { "off" });
+        }
+        Command::BreakOnKeyWait => {
+            let enabled = !debugger.break_on_key_wait();
+            debugger.set_break_on_key_wait(enabled);
+            println!("Break on key wait {}", if enabled { "on" } else { "off" });
+        }
+        Command::Delete(addr) => {
+            if !debugger.remove_breakpoint(addr) {
+                println!("No breakpoint at 0x{:03X}", addr);
+            }
+        }
+        Command::Watch(addr, watch) => {
+            debugger.set_watchpoint(addr, watch);
+            println!("Watchpoint at 0x{:03X}", addr);
+        }
+        Command::Unwatch(addr) => {
+            if !debugger.remove_watchpoint(addr) {
+                println!("No watchpoint at 0x{:03X}", addr);
+            }
+        }
+        Command::Info => {
+            for (addr, condition) in debugger.breakpoints() {
+                match condition {
+                    Some(condition) => println!("Breakpoint at 0x{:03X} if {}", addr, condition),
+                    None => println!("Breakpoint at 0x{:03X}", addr),
+                }
+            }
+            for (addr, watch) in debugger.watchpoints() {
+                println!("Watchpoint at 0x{:03X} ({})", addr, watch_name(watch));
+            }
+            if debugger.break_on_draw() {
+                println!("Break on draw");
+            }
+            if debugger.break_on_key_wait() {
+                println!("Break on key wait");
+            }
+        }
+        Command::Registers | Command::Examine(..) | Command::Help | Command::Quit => unreachable!(),
+    }
+}
+
+fn print_location(system: &System) {
+    let pc = system.cpu().registers.pc;
+    match system.cpu().instruction_at(pc) {
+        Some(instruction) => println!("0x{:03X}: {}", pc, instruction),
+        None => println!("0x{:03X}: ???", pc),
+    }
+}
+
+fn print_registers(system: &System) {
+    let cpu = system.cpu();
+    println!(
+        "PC 0x{:03X}  I 0x{:03X}  SP {}  DT {}  ST {}",
+        cpu.registers.pc,
+        cpu.registers.i,
+        cpu.registers.sp,
+        cpu.delay_timer(),
+        cpu.sound_timer()
+    );
+    for (row, values) in cpu.registers.v.chunks(8).enumerate() {
+        let values = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X} {:02X}", row * 8 + i, value))
+            .collect::<Vec<_>>();
+        println!("{}", values.join("  "));
+    }
+    let stack = cpu
+        .stack()
+        .iter()
+        .map(|addr| format!("0x{:03X}", addr))
+        .collect::<Vec<_>>();
+    println!(
+        "Stack {}/{} [{}]",
+        stack.len(),
+        cpu.stack_depth(),
+        stack.join(", ")
+    );
+    print_location(system);
+}
+
+fn print_memory(system: &System, addr: Address, len: usize) {
+    let bytes = system.cpu().read_memory(addr, len);
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let values = row
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>();
+        println!("0x{:03X}: {}", addr as usize + i * 16, values.join(" "));
+    }
+}
+
+fn watch_name(watch: Watch) -> &'static str {
+    match watch {
+        Watch::Read => "r",
+        Watch::Write => "w",
+        Watch::ReadWrite => "rw",
+    }
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let (name, rest) = match line.trim().split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (line.trim(), ""),
+    };
+    let args = rest.split_whitespace().collect::<Vec<_>>();
+
+    let command = match (name, args.as_slice()) {
+        ("c" | "continue", []) => Command::Continue,
+        ("p" | "pause", []) => Command::Pause,
+        ("s" | "step", []) => Command::Step,
+        ("n" | "next", []) => Command::Next,
+        ("f" | "finish", []) => Command::Finish,
+        ("b" | "break", ["draw"]) => Command::BreakOnDraw,
+        ("b" | "break", ["key"]) => Command::BreakOnKeyWait,
+        ("b" | "break", [addr]) => Command::Break(parse_number(addr)?, None),
+        ("b" | "break", [addr, "if", ..]) => {
+            let condition = rest.split_once("if").map_or("", |(_, cond)| cond);
+            Command::Break(parse_number(addr)?, Some(parse_condition(condition)?))
+        }
+        ("d" | "delete", [addr]) => Command::Delete(parse_number(addr)?),
+        ("w" | "watch", [addr]) => Command::Watch(parse_number(addr)?, Watch::Write),
+        ("w" | "watch", [addr, kind]) => {
+            let watch = match *kind {
+                "r" => Watch::Read,
+                "w" => Watch::Write,
+                "rw" => Watch::ReadWrite,
+                _ => {
+                    return Err(format!(
+                        "Unknown watchpoint kind `{}`, expected r, w or rw",
+                        kind
+                    ))
+                }
+            };
+            Command::Watch(parse_number(addr)?, watch)
+        }
+        ("u" | "unwatch", [addr]) => Command::Unwatch(parse_number(addr)?),
+        ("i" | "info", []) => Command::Info,
+        ("r" | "regs", []) => Command::Registers,
+        ("x", [addr]) => Command::Examine(parse_number(addr)?, 16),
+        ("x", [addr, len]) => Command::Examine(parse_number(addr)?, parse_number(len)? as usize),
+        ("h" | "help", []) => Command::Help,
+        ("q" | "quit", []) => Command::Quit,
+        _ => {
+            return Err(format!(
+                "Invalid command `{}`, type `help` for a list of commands",
+                line.trim()
+            ))
+        }
+    };
+    Ok(command)
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let result = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| format!("Invalid number `{}`", text))
+}
+
+/// Parses `V3 == 0x10`, `I >= 0x300` and so on, spaces around the comparison are optional
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let invalid = || {
+        format!(
+            "Invalid condition `{}`, expected e.g. `V3 == 0x10`",
+            text.trim()
+        )
+    };
+
+    let start = text.find(['=', '!', '<', '>']).ok_or_else(invalid)?;
+    let end = text[start..]
+        .find(|c| !matches!(c, '=' | '!' | '<' | '>'))
+        .map_or(text.len(), |len| start + len);
+    let (operand, comparison, value) =
+        (text[..start].trim(), &text[start..end], text[end..].trim());
+
+    let operand = match operand.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        register => {
+            let index = register.strip_prefix('V').ok_or_else(invalid)?;
+            match u8::from_str_radix(index, 16) {
+                Ok(x) if index.len() == 1 => Operand::V(x),
+                _ => return Err(invalid()),
+            }
+        }
+    };
+    let comparison = match comparison {
+        "==" => Comparison::Eq,
+        "!=" => Comparison::Ne,
+        "<" => Comparison::Lt,
+        "<=" => Comparison::Le,
+        ">" => Comparison::Gt,
+        ">=" => Comparison::Ge,
+        _ => return Err(invalid()),
+    };
+
+    Ok(Condition {
+        operand,
+        comparison,
+        value: parse_number(value)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("c"), Ok(Command::Continue));
+        assert_eq!(parse("  next "), Ok(Command::Next));
+        assert_eq!(parse("b 0x204"), Ok(Command::Break(0x204, None)));
+        assert_eq!(parse("break draw"), Ok(Command::BreakOnDraw));
+        assert_eq!(
+            parse("w 768 rw"),
+            Ok(Command::Watch(0x300, Watch::ReadWrite))
+        );
+        assert_eq!(parse("x 0x300"), Ok(Command::Examine(0x300, 16)));
+        assert!(parse("b 0xZZZ").is_err());
+        assert!(parse("w 0x300 x").is_err());
+        assert!(parse("jump").is_err());
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            parse("b 0x204 if V3 == 0x10"),
+            Ok(Command::Break(
+                0x204,
+                Some(Condition {
+                    operand: Operand::V(3),
+                    comparison: Comparison::Eq,
+                    value: 0x10
+                })
+            ))
+        );
+        assert_eq!(
+            parse_condition("i>=768"),
+            Ok(Condition {
+                operand: Operand::I,
+                comparison: Comparison::Ge,
+                value: 0x300
+            })
+        );
+        assert!(parse_condition("V3 = 1").is_err());
+        assert!(parse_condition("V10 == 1").is_err());
+        assert!(parse_condition("V3").is_err());
+    }
+}
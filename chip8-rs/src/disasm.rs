@@ -0,0 +1,54 @@
+use std::io::Write;
+
+use chip8::database::Database;
+use chip8::disasm;
+use chip8::platform::Platform;
+use chip8::state;
+
+use crate::platform::PlatformArg;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct DisasmArgs {
+    /// Instruction set to decode, defaults to the one listed in the ROM database or CHIP-8
+    #[arg(long, value_enum)]
+    platform: Option<PlatformArg>,
+
+    /// Follow jumps and calls from the entry point, listing unreachable bytes as data
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Write the listing to a file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// ROM file to disassemble
+    #[arg(value_parser, required = true)]
+    file: String,
+}
+
+pub fn run(args: DisasmArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let rom = std::fs::read(&args.file)?;
+    let platform = match args.platform {
+        Some(platform) => platform.into(),
+        None => Database::bundled()
+            .lookup(&state::rom_hash(&rom))
+            .map_or(Platform::Chip8, |info| info.platform),
+    };
+
+    let lines = if args.recursive {
+        disasm::disassemble_recursive(&rom, platform)
+    } else {
+        disasm::disassemble(&rom, platform)
+    };
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+use gilrs::{EventType, GamepadId, Gilrs};
+use tracing::{error, info};
+
+use crate::keymap::Button;
+
+/// Button presses from every connected controller, controllers can come and go at any time
+pub struct Gamepads {
+    gilrs: Gilrs,
+    /// Buttons currently held, released when their controller disconnects
+    held: HashSet<(GamepadId, Button)>,
+}
+
+impl Gamepads {
+    /// Starts listening for controllers, returns `None` if the platform has no gamepad support
+    pub fn open() -> Option<Gamepads> {
+        match Gilrs::new() {
+            Ok(gilrs) => {
+                for (_, gamepad) in gilrs.gamepads() {
+                    info!("Gamepad connected: {}", gamepad.name());
+                }
+                Some(Gamepads {
+                    gilrs,
+                    held: HashSet::new(),
+                })
+            }
+            Err(err) => {
+                error!("Could not set up gamepads, running without them: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Returns the buttons pressed (`true`) and released (`false`) since the last call
+    pub fn poll(&mut self) -> Vec<(Button, bool)> {
+        let mut changes = Vec::new();
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(button) = convert(button) {
+                        if self.held.insert((event.id, button)) {
+                            changes.push((button, true));
+                        }
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = convert(button) {
+                        if self.held.remove(&(event.id, button)) {
+                            changes.push((button, false));
+                        }
+                    }
+                }
+                EventType::Connected => {
+                    info!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name());
+                }
+                EventType::Disconnected => {
+                    info!(
+                        "Gamepad disconnected: {}",
+                        self.gilrs.gamepad(event.id).name()
+                    );
+                    self.held.retain(|&(id, button)| {
+                        if id == event.id {
+                            changes.push((button, false));
+                        }
+                        id != event.id
+                    });
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+fn convert(button: gilrs::Button) -> Option<Button> {
+    use gilrs::Button as B;
+
+    Some(match button {
+        B::South => Button::South,
+        B::East => Button::East,
+        B::North => Button::North,
+        B::West => Button::West,
+        B::LeftTrigger => Button::LeftTrigger,
+        B::LeftTrigger2 => Button::LeftTrigger2,
+        B::RightTrigger => Button::RightTrigger,
+        B::RightTrigger2 => Button::RightTrigger2,
+        B::Select => Button::Select,
+        B::Start => Button::Start,
+        B::Mode => Button::Mode,
+        B::LeftThumb => Button::LeftThumb,
+        B::RightThumb => Button::RightThumb,
+        B::DPadUp => Button::DPadUp,
+        B::DPadDown => Button::DPadDown,
+        B::DPadLeft => Button::DPadLeft,
+        B::DPadRight => Button::DPadRight,
+        _ => return None,
+    })
+}
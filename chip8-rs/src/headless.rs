@@ -0,0 +1,186 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use chip8::display::Pixel;
+use chip8::system::System;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// `#` for pixels that are on, `.` for pixels that are off
+    Ascii,
+    /// Plain text portable bitmap
+    Pbm,
+    /// Greyscale PNG
+    Png,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct HeadlessArgs {
+    /// Run without a window and dump the final frame, e.g. on CI machines
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Number of frames to run in headless mode, stopping early if the program exits or faults
+    #[arg(long, default_value_t = 600, requires = "headless")]
+    frames: u64,
+
+    /// Write the final frame to a file instead of stdout
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    dump: Option<PathBuf>,
+
+    /// Format of the final frame, guessed from the --dump extension by default
+    #[arg(long, value_enum, requires = "headless")]
+    format: Option<DumpFormat>,
+
+    /// Record the sound to a WAV file
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    pub wav: Option<PathBuf>,
+}
+
+impl HeadlessArgs {
+    fn format(&self) -> DumpFormat {
+        let extension = self
+            .dump
+            .as_deref()
+            .and_then(Path::extension)
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        self.format.unwrap_or(match extension.as_deref() {
+            Some("pbm") => DumpFormat::Pbm,
+            Some("png") => DumpFormat::Png,
+            _ => DumpFormat::Ascii,
+        })
+    }
+}
+
+/// Runs `system` as fast as possible for the requested number of frames and dumps the display.
+///
+/// The frame is written even if the CPU faults, the fault is returned afterwards.
+pub fn run(system: &mut System, args: &HeadlessArgs) -> Result<(), Box<dyn std::error::Error>> {
+    while system.frame() < args.frames && system.fault().is_none() && !system.exited() {
+        system.run_frame();
+    }
+    system.finish_audio()?;
+
+    let (width, height) = system.resolution();
+    let pixels = system.pixels();
+    let bytes = match args.format() {
+        DumpFormat::Ascii => to_ascii(&pixels, width).into_bytes(),
+        DumpFormat::Pbm => to_pbm(&pixels, width, height).into_bytes(),
+        DumpFormat::Png => to_png(&pixels, width, height)?,
+    };
+
+    match &args.dump {
+        Some(path) => std::fs::write(path, bytes)?,
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&bytes)?;
+            stdout.flush()?;
+        }
+    }
+
+    match system.fault() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+fn to_ascii(pixels: &[Pixel], width: usize) -> String {
+    let mut out = String::with_capacity(pixels.len() + pixels.len() / width);
+    for row in pixels.chunks(width) {
+        out.extend(row.iter().map(|p| match p {
+            Pixel::On => '#',
+            Pixel::Off => '.',
+        }));
+        out.push('\n');
+    }
+    out
+}
+
+fn to_pbm(pixels: &[Pixel], width: usize, height: usize) -> String {
+    let mut out = format!("P1\n{} {}\n", width, height);
+    for row in pixels.chunks(width) {
+        let values = row
+            .iter()
+            .map(|p| match p {
+                Pixel::On => "1",
+                Pixel::Off => "0",
+            })
+            .collect::<Vec<_>>();
+        out.push_str(&values.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+fn to_png(pixels: &[Pixel], width: usize, height: usize) -> Result<Vec<u8>, png::EncodingError> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data = pixels
+        .iter()
+        .map(|p| match p {
+            Pixel::On => 0xFF,
+            Pixel::Off => 0x00,
+        })
+        .collect::<Vec<u8>>();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXELS: [Pixel; 6] = [
+        Pixel::On,
+        Pixel::Off,
+        Pixel::Off,
+        Pixel::Off,
+        Pixel::On,
+        Pixel::On,
+    ];
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(to_ascii(&PIXELS, 3), "#..\n.##\n");
+    }
+
+    #[test]
+    fn test_to_pbm() {
+        assert_eq!(to_pbm(&PIXELS, 3, 2), "P1\n3 2\n1 0 0\n0 1 1\n");
+    }
+
+    #[test]
+    fn test_to_png() {
+        let bytes = to_png(&PIXELS, 3, 2).unwrap();
+        assert_eq!(&bytes[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_format() {
+        let args = |dump: Option<&str>, format| HeadlessArgs {
+            headless: true,
+            frames: 1,
+            dump: dump.map(PathBuf::from),
+            format,
+            wav: None,
+        };
+
+        assert_eq!(args(None, None).format(), DumpFormat::Ascii);
+        assert_eq!(args(Some("frame.PNG"), None).format(), DumpFormat::Png);
+        assert_eq!(args(Some("frame.pbm"), None).format(), DumpFormat::Pbm);
+        assert_eq!(
+            args(Some("frame.png"), Some(DumpFormat::Ascii)).format(),
+            DumpFormat::Ascii
+        );
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::de::value::StrDeserializer;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use winit::keyboard::KeyCode;
+
+use chip8::rom::RomImage;
+use chip8::state::RomHash;
+
+use crate::slots;
+
+/// The physical keys of the main block, row by row, named after their position on a US layout
+const ROWS: [&[KeyCode]; 4] = {
+    use KeyCode::*;
+    [
+        &[
+            Digit1, Digit2, Digit3, Digit4, Digit5, Digit6, Digit7, Digit8, Digit9, Digit0, Minus,
+            Equal,
+        ],
+        &[
+            KeyQ,
+            KeyW,
+            KeyE,
+            KeyR,
+            KeyT,
+            KeyY,
+            KeyU,
+            KeyI,
+            KeyO,
+            KeyP,
+            BracketLeft,
+            BracketRight,
+        ],
+        &[
+            KeyA, KeyS, KeyD, KeyF, KeyG, KeyH, KeyJ, KeyK, KeyL, Semicolon, Quote,
+        ],
+        &[
+            KeyZ, KeyX, KeyC, KeyV, KeyB, KeyN, KeyM, Comma, Period, Slash,
+        ],
+    ]
+};
+
+// A 4x4 grid on the left side of the keyboard, by position so it's the same on every layout
+// Keyboard keys (US layout); Chip-8 keys
+// 1, 2, 3, 4;    1, 2, 3, C
+// Q, W, E, R;    4, 5, 6, D
+// A, S, D, F;    7, 8, 9, E
+// Z, X, C, V;    A, 0, B, F
+const DEFAULT_KEYS: [(u8, KeyCode); 16] = [
+    (0x1, KeyCode::Digit1),
+    (0x2, KeyCode::Digit2),
+    (0x3, KeyCode::Digit3),
+    (0xC, KeyCode::Digit4),
+    (0x4, KeyCode::KeyQ),
+    (0x5, KeyCode::KeyW),
+    (0x6, KeyCode::KeyE),
+    (0xD, KeyCode::KeyR),
+    (0x7, KeyCode::KeyA),
+    (0x8, KeyCode::KeyS),
+    (0x9, KeyCode::KeyD),
+    (0xE, KeyCode::KeyF),
+    (0xA, KeyCode::KeyZ),
+    (0x0, KeyCode::KeyX),
+    (0xB, KeyCode::KeyC),
+    (0xF, KeyCode::KeyV),
+];
+
+// D-pad on the WASD keys of the grid, face buttons on Q and E
+const DEFAULT_BUTTONS: [(u8, Button); 6] = [
+    (0x5, Button::DPadUp),
+    (0x7, Button::DPadLeft),
+    (0x8, Button::DPadDown),
+    (0x9, Button::DPadRight),
+    (0x6, Button::South),
+    (0x4, Button::East),
+];
+
+const DEFAULT_HOTKEYS: [(Hotkey, KeyCode); 6] = [
+    (Hotkey::Pause, KeyCode::KeyP),
+    (Hotkey::Reset, KeyCode::F12),
+    (Hotkey::SaveState, KeyCode::F10),
+    (Hotkey::LoadState, KeyCode::F11),
+    (Hotkey::Rewind, KeyCode::Backspace),
+    (Hotkey::FastForward, KeyCode::Tab),
+];
+
+const DEFAULT_HOTKEY_BUTTONS: [(Hotkey, Button); 3] = [
+    (Hotkey::Pause, Button::Start),
+    (Hotkey::Rewind, Button::LeftTrigger),
+    (Hotkey::FastForward, Button::RightTrigger),
+];
+
+/// Prefix of gamepad button names in the config file, e.g. `GamepadSouth`
+const BUTTON_PREFIX: &str = "Gamepad";
+
+/// Keyboard layout used to read the single character key names in the config file
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Qwerty,
+    Azerty,
+    Dvorak,
+}
+
+impl Layout {
+    /// The characters printed on the keys of [`ROWS`]
+    fn labels(self) -> [&'static str; 4] {
+        match self {
+            Layout::Qwerty => ["1234567890-=", "qwertyuiop[]", "asdfghjkl;'", "zxcvbnm,./"],
+            Layout::Azerty => ["1234567890)=", "azertyuiop^$", "qsdfghjklmù", "wxcvbn,;:!"],
+            Layout::Dvorak => ["1234567890[]", "',.pyfgcrl/=", "aoeuidhtns-", ";qjkxbmwvz"],
+        }
+    }
+
+    /// Looks up a key by the character printed on it, or by its position name, e.g. `KeyQ`,
+    /// `Space` or `F5`
+    fn key(self, name: &str) -> Option<KeyCode> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            let c = c.to_lowercase().next()?;
+            return self.labels().iter().zip(ROWS).find_map(|(labels, keys)| {
+                labels
+                    .chars()
+                    .position(|label| label == c)
+                    .map(|index| keys[index])
+            });
+        }
+
+        let deserializer: StrDeserializer<serde::de::value::Error> = name.into_deserializer();
+        KeyCode::deserialize(deserializer).ok()
+    }
+}
+
+/// A gamepad button, named by position on an Xbox style controller
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+/// A keyboard key or gamepad button
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    Key(KeyCode),
+    Button(Button),
+}
+
+impl From<KeyCode> for Input {
+    fn from(code: KeyCode) -> Input {
+        Input::Key(code)
+    }
+}
+
+impl From<Button> for Input {
+    fn from(button: Button) -> Input {
+        Input::Button(button)
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Key(code) => write!(f, "{:?}", code),
+            Input::Button(button) => write!(f, "{}{:?}", BUTTON_PREFIX, button),
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Hotkey {
+    /// Stops and resumes the emulation
+    Pause,
+    /// Restarts the ROM
+    Reset,
+    /// Saves to the quick save slot
+    SaveState,
+    /// Loads the quick save slot
+    LoadState,
+    /// Plays the game backwards while held
+    Rewind,
+    /// Runs at a higher speed while held
+    FastForward,
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Hotkey::Pause => "pause",
+            Hotkey::Reset => "reset",
+            Hotkey::SaveState => "save state",
+            Hotkey::LoadState => "load state",
+            Hotkey::Rewind => "rewind",
+            Hotkey::FastForward => "fast forward",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something a key can be bound to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Action {
+    Key(u8),
+    Hotkey(Hotkey),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Key(key) => write!(f, "key {:X}", key),
+            Action::Hotkey(hotkey) => write!(f, "{}", hotkey),
+        }
+    }
+}
+
+/// One key or button name, or a list of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Names {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Names {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            Names::One(name) => std::slice::from_ref(name),
+            Names::Many(names) => names,
+        }
+    }
+}
+
+/// Bindings that replace the defaults, in the config file or for a single ROM
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Bindings {
+    /// Chip-8 key as a hex digit to key names
+    keys: BTreeMap<String, Names>,
+    hotkeys: BTreeMap<Hotkey, Names>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    layout: Layout,
+    keys: BTreeMap<String, Names>,
+    hotkeys: BTreeMap<Hotkey, Names>,
+    /// Per-ROM bindings keyed by the SHA-1 of the ROM in hex, applied on top of the others
+    roms: HashMap<String, Bindings>,
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The name in `[keys]` isn't a hex digit
+    InvalidKey(String),
+    /// The key name doesn't exist on the layout, or isn't a gamepad button
+    UnknownKey(String),
+    /// Keys bound to more than one action, one message each
+    Conflicts(Vec<String>),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::Io(path, err) => write!(f, "Could not read {}: {}", path.display(), err),
+            KeymapError::Parse(path, err) => {
+                write!(f, "Invalid config {}: {}", path.display(), err)
+            }
+            KeymapError::InvalidKey(name) => {
+                write!(f, "Invalid Chip-8 key '{}', expected 0-F", name)
+            }
+            KeymapError::UnknownKey(name) => write!(f, "Unknown key or button '{}'", name),
+            KeymapError::Conflicts(conflicts) => {
+                write!(f, "Conflicting key bindings:")?;
+                for conflict in conflicts {
+                    write!(f, "\n  {}", conflict)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+/// Maps keyboard keys and gamepad buttons to Chip-8 keys and emulator hotkeys
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    actions: HashMap<Input, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::resolve(&ConfigFile::default(), &[0; 20]).expect("Default bindings are valid")
+    }
+}
+
+impl Keymap {
+    /// `<config dir>/chip8-rs/config.toml`, e.g. `~/.config/chip8-rs/config.toml` on Linux
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("chip8-rs").join("config.toml"))
+    }
+
+    /// Loads the bindings for `rom` from `path`, or from [`Keymap::default_path`] if it exists.
+    ///
+    /// Without a config file the defaults are used.
+    pub fn load(path: Option<&Path>, rom: &RomImage) -> Result<Keymap, KeymapError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Keymap::default_path().filter(|path| path.exists()) {
+                Some(path) => path,
+                None => return Ok(Keymap::default()),
+            },
+        };
+
+        let source =
+            std::fs::read_to_string(&path).map_err(|err| KeymapError::Io(path.clone(), err))?;
+        let config = toml::from_str(&source).map_err(|err| KeymapError::Parse(path, err))?;
+        Keymap::resolve(&config, &rom.sha1())
+    }
+
+    fn resolve(config: &ConfigFile, rom_hash: &RomHash) -> Result<Keymap, KeymapError> {
+        let defaults = DEFAULT_KEYS
+            .iter()
+            .map(|&(key, code)| (Action::Key(key), code.into()))
+            .chain(
+                DEFAULT_BUTTONS
+                    .iter()
+                    .map(|&(key, button)| (Action::Key(key), button.into())),
+            )
+            .chain(
+                DEFAULT_HOTKEYS
+                    .iter()
+                    .map(|&(hotkey, code)| (Action::Hotkey(hotkey), code.into())),
+            )
+            .chain(
+                DEFAULT_HOTKEY_BUTTONS
+                    .iter()
+                    .map(|&(hotkey, button)| (Action::Hotkey(hotkey), button.into())),
+            );
+        let mut bindings = BTreeMap::<Action, Vec<Input>>::new();
+        for (action, input) in defaults {
+            bindings.entry(action).or_default().push(input);
+        }
+
+        let hash = rom_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let rom = config
+            .roms
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&hash))
+            .map(|(_, bindings)| bindings);
+
+        let layers = [(&config.keys, &config.hotkeys)]
+            .into_iter()
+            .chain(rom.map(|rom| (&rom.keys, &rom.hotkeys)));
+        for (keys, hotkeys) in layers {
+            for (key, names) in keys {
+                let key = match u8::from_str_radix(key, 16) {
+                    Ok(digit) if key.len() == 1 => digit,
+                    _ => return Err(KeymapError::InvalidKey(key.clone())),
+                };
+                bind(&mut bindings, Action::Key(key), names, config.layout)?;
+            }
+            for (&hotkey, names) in hotkeys {
+                bind(&mut bindings, Action::Hotkey(hotkey), names, config.layout)?;
+            }
+        }
+
+        let mut actions = HashMap::new();
+        let mut conflicts = Vec::new();
+        for (action, inputs) in bindings {
+            for input in inputs {
+                let slot = match input {
+                    Input::Key(code) => slots::slot(code),
+                    Input::Button(_) => None,
+                };
+                if let Some(slot) = slot {
+                    conflicts.push(format!(
+                        "{} is bound to {} and save slot {}",
+                        input, action, slot
+                    ));
+                } else if let Some(other) = actions.insert(input, action) {
+                    conflicts.push(format!("{} is bound to {} and {}", input, other, action));
+                }
+            }
+        }
+
+        if conflicts.is_empty() {
+            Ok(Keymap { actions })
+        } else {
+            Err(KeymapError::Conflicts(conflicts))
+        }
+    }
+
+    /// The Chip-8 key bound to a keyboard key or gamepad button
+    pub fn key(&self, input: impl Into<Input>) -> Option<u8> {
+        match self.actions.get(&input.into()) {
+            Some(Action::Key(key)) => Some(*key),
+            _ => None,
+        }
+    }
+
+    pub fn hotkey(&self, input: impl Into<Input>) -> Option<Hotkey> {
+        match self.actions.get(&input.into()) {
+            Some(Action::Hotkey(hotkey)) => Some(*hotkey),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces the inputs of `action` with `names`.
+///
+/// Keys only replace keys and buttons only replace buttons, so rebinding a Chip-8 key to another
+/// keyboard key keeps its gamepad button. An empty list unbinds both.
+fn bind(
+    bindings: &mut BTreeMap<Action, Vec<Input>>,
+    action: Action,
+    names: &Names,
+    layout: Layout,
+) -> Result<(), KeymapError> {
+    let inputs = names
+        .as_slice()
+        .iter()
+        .map(|name| lookup(layout, name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bound = bindings.entry(action).or_default();
+    if inputs.is_empty() {
+        bound.clear();
+    }
+    let is_button = |input: &Input| matches!(input, Input::Button(_));
+    bound.retain(|old| !inputs.iter().any(|new| is_button(new) == is_button(old)));
+    bound.extend(inputs);
+    Ok(())
+}
+
+fn lookup(layout: Layout, name: &str) -> Result<Input, KeymapError> {
+    let input = match name.strip_prefix(BUTTON_PREFIX) {
+        Some(button) => {
+            let deserializer: StrDeserializer<serde::de::value::Error> = button.into_deserializer();
+            Button::deserialize(deserializer).ok().map(Input::Button)
+        }
+        None => layout.key(name).map(Input::Key),
+    };
+    input.ok_or_else(|| KeymapError::UnknownKey(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str, rom_hash: &RomHash) -> Result<Keymap, KeymapError> {
+        let config = toml::from_str(source).unwrap();
+        Keymap::resolve(&config, rom_hash)
+    }
+
+    #[test]
+    fn test_default() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key(KeyCode::Digit4), Some(0xC));
+        assert_eq!(keymap.key(KeyCode::KeyX), Some(0x0));
+        assert_eq!(keymap.key(KeyCode::KeyP), None);
+        assert_eq!(keymap.hotkey(KeyCode::KeyP), Some(Hotkey::Pause));
+        assert_eq!(keymap.hotkey(KeyCode::Backspace), Some(Hotkey::Rewind));
+        assert_eq!(keymap.key(Button::DPadUp), Some(0x5));
+        assert_eq!(keymap.hotkey(Button::Start), Some(Hotkey::Pause));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let source = r#"
+            [keys]
+            5 = "i"
+            2 = ["2", "GamepadNorth"]
+            8 = []
+        "#;
+
+        let keymap = parse(source, &[0; 20]).unwrap();
+        // Rebinding the keyboard key keeps the button and the other way around
+        assert_eq!(keymap.key(KeyCode::KeyI), Some(0x5));
+        assert_eq!(keymap.key(Button::DPadUp), Some(0x5));
+        assert_eq!(keymap.key(KeyCode::Digit2), Some(0x2));
+        assert_eq!(keymap.key(Button::North), Some(0x2));
+        assert_eq!(keymap.key(KeyCode::KeyS), None);
+        assert_eq!(keymap.key(Button::DPadDown), None);
+
+        let err = parse("[keys]\n5 = \"GamepadJump\"", &[0; 20]).unwrap_err();
+        assert!(matches!(err, KeymapError::UnknownKey(key) if key == "GamepadJump"));
+
+        match parse("[hotkeys]\nreset = \"GamepadSouth\"", &[0; 20]) {
+            Err(KeymapError::Conflicts(conflicts)) => {
+                assert_eq!(conflicts, ["GamepadSouth is bound to key 6 and reset"])
+            }
+            other => panic!("Expected conflicts, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_layouts() {
+        assert_eq!(Layout::Qwerty.key("q"), Some(KeyCode::KeyQ));
+        assert_eq!(Layout::Azerty.key("A"), Some(KeyCode::KeyQ));
+        assert_eq!(Layout::Azerty.key("m"), Some(KeyCode::Semicolon));
+        assert_eq!(Layout::Dvorak.key("'"), Some(KeyCode::KeyQ));
+        assert_eq!(Layout::Dvorak.key("s"), Some(KeyCode::Semicolon));
+        assert_eq!(Layout::Dvorak.key("Space"), Some(KeyCode::Space));
+        assert_eq!(Layout::Dvorak.key("F5"), Some(KeyCode::F5));
+        assert_eq!(Layout::Qwerty.key("Nope"), None);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let source = r#"
+            layout = "azerty"
+
+            [keys]
+            5 = "z"
+            8 = "Space"
+
+            [hotkeys]
+            pause = "Escape"
+
+            [roms.0102030000000000000000000000000000000000.keys]
+            5 = "ArrowUp"
+        "#;
+
+        let keymap = parse(source, &[0; 20]).unwrap();
+        assert_eq!(keymap.key(KeyCode::KeyW), Some(0x5));
+        assert_eq!(keymap.key(KeyCode::Space), Some(0x8));
+        assert_eq!(keymap.key(KeyCode::KeyS), None);
+        assert_eq!(keymap.hotkey(KeyCode::Escape), Some(Hotkey::Pause));
+        assert_eq!(keymap.hotkey(KeyCode::KeyP), None);
+
+        let mut hash = [0; 20];
+        hash[..3].copy_from_slice(&[1, 2, 3]);
+        let keymap = parse(source, &hash).unwrap();
+        assert_eq!(keymap.key(KeyCode::ArrowUp), Some(0x5));
+        assert_eq!(keymap.key(KeyCode::KeyW), None);
+        assert_eq!(keymap.key(KeyCode::Space), Some(0x8));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let source = r#"
+            [keys]
+            0 = "1"
+            2 = "F3"
+
+            [hotkeys]
+            rewind = "v"
+        "#;
+
+        match parse(source, &[0; 20]) {
+            Err(KeymapError::Conflicts(conflicts)) => assert_eq!(
+                conflicts,
+                [
+                    "Digit1 is bound to key 0 and key 1",
+                    "F3 is bound to key 2 and save slot 3",
+                    "KeyV is bound to key F and rewind",
+                ]
+            ),
+            other => panic!("Expected conflicts, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = parse("[keys]\n10 = \"q\"", &[0; 20]).unwrap_err();
+        assert!(matches!(err, KeymapError::InvalidKey(key) if key == "10"));
+
+        let err = parse("[keys]\n1 = \"NoSuchKey\"", &[0; 20]).unwrap_err();
+        assert!(matches!(err, KeymapError::UnknownKey(key) if key == "NoSuchKey"));
+
+        assert!(toml::from_str::<ConfigFile>("[hotkeys]\njump = \"Space\"").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use chip8::database::Database;
+use chip8::state;
+
+use crate::archive::{self, Entry};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ListArgs {
+    /// Zip archive of ROMs, or a single ROM file
+    #[arg(value_parser, required = true)]
+    file: String,
+}
+
+pub fn run(args: ListArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(&args.file);
+    let bytes = std::fs::read(path)?;
+    let entries = if archive::is_zip(&bytes) {
+        archive::entries(&bytes).map_err(|err| format!("{}: {}", path.display(), err))?
+    } else {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        vec![Entry {
+            name: name.into_owned(),
+            bytes,
+        }]
+    };
+
+    for line in listing(&entries, Database::bundled()) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// One line per entry: the index `--entry` takes, the name, the size, the SHA-1 and the title
+/// from the ROM database if it knows the ROM
+fn listing(entries: &[Entry], database: &Database) -> Vec<String> {
+    let width = entries
+        .iter()
+        .map(|entry| entry.name.chars().count())
+        .max()
+        .unwrap_or_default();
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let hash = state::rom_hash(&entry.bytes);
+            let title = database
+                .lookup(&hash)
+                .map(|info| info.title)
+                .unwrap_or_default();
+            let line = format!(
+                "{:>3}  {:<width$}  {:>6} B  {}  {}",
+                index,
+                entry.name,
+                entry.bytes.len(),
+                state::rom_hash_hex(&hash),
+                title,
+                width = width
+            );
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let pong = Entry {
+            name: "games/pong.ch8".into(),
+            bytes: vec![0x12, 0x00],
+        };
+        let hash = state::rom_hash_hex(&state::rom_hash(&pong.bytes));
+        let json = format!(
+            r#"[{{ "title": "Pong", "roms": {{ "{}": {{ "platforms": ["originalChip8"] }} }} }}]"#,
+            hash
+        );
+        let database = Database::parse(&json).unwrap();
+        let entries = [
+            pong,
+            Entry {
+                name: "x.sc8".into(),
+                bytes: vec![0; 1024],
+            },
+        ];
+
+        assert_eq!(
+            listing(&entries, &database),
+            [
+                format!("  0  games/pong.ch8       2 B  {}  Pong", hash),
+                format!(
+                    "  1  x.sc8             1024 B  {}",
+                    "60cacbf3d72e1e7834203da608037b1bf83b40e8"
+                ),
+            ]
+        );
+    }
+}
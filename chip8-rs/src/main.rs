@@ -1,18 +1,48 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use pixels_wgpu::data::Color;
 use pixels_wgpu::renderer;
 use pixels_wgpu::renderer::PixelRenderer;
-use std::io::Read;
-use tracing::error;
+use tracing::{error, info};
 use winit::dpi::LogicalSize;
 use winit::event::Event;
-use winit::event_loop::EventLoop;
-use winit::window::WindowBuilder;
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::{Window, WindowBuilder};
 
+use chip8::audio::WavSink;
+use chip8::database::RomInfo;
+use chip8::debugger::Debugger;
 use chip8::display::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
-use chip8::system::SystemBuilder;
+use chip8::gdb::GdbStub;
+use chip8::rom::RomImage;
+use chip8::scheduler::FRAME_DURATION;
+use chip8::system::System;
+
+use keymap::{Hotkey, Input, Keymap};
+use rom::LoadedRom;
+
+mod archive;
+mod asm;
+#[cfg(feature = "audio")]
+mod audio;
+mod debug;
+mod disasm;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+mod headless;
+mod keymap;
+mod list;
+mod movie;
+mod platform;
+mod quirks;
+mod render_audio;
+mod rom;
+mod slots;
+mod trace;
+mod trace_diff;
 
 const DEFAULT_PIXEL_SIZE: f32 = 20.0;
+/// Speed while the fast forward hotkey is held
+const FAST_FORWARD_SPEED: f32 = 4.0;
 const PIXEL_ON_COLOR: Color = Color {
     r: 1.0,
     g: 1.0,
@@ -28,18 +58,90 @@ const PIXEL_OFF_COLOR: Color = Color {
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Run a ROM (default)
+    Run(RunArgs),
+
+    /// Disassemble a ROM
+    Disasm(disasm::DisasmArgs),
+
+    /// Assemble a source file into a ROM
+    Asm(asm::AsmArgs),
+
+    /// Run a ROM in lockstep with a reference trace and report the first divergence
+    TraceDiff(trace_diff::TraceDiffArgs),
+
+    /// Run a ROM without a window and record the buzzer to a WAV file
+    RenderAudio(render_audio::RenderAudioArgs),
+
+    /// List the ROMs in a zip archive with their sizes, hashes and titles
+    List(list::ListArgs),
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct RunArgs {
     /// Display verbose output
     #[arg(short, long)]
     verbose: bool,
 
-    /// Display debug info
-    #[arg(short, long)]
+    /// Pause at the entry point and control the CPU from a debugger prompt on stdin
+    #[arg(short, long, conflicts_with = "headless")]
     debug: bool,
 
-    /// ROM file to run
+    /// Wait for a GDB client on this local port before starting, paused at the entry point
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["headless", "debug"])]
+    gdb: Option<u16>,
+
+    /// Instruction set to emulate, defaults to the one listed in the ROM database or CHIP-8
+    #[arg(long, value_enum)]
+    platform: Option<platform::PlatformArg>,
+
+    /// Instructions executed per 60 Hz frame, defaults to the speed listed in the ROM database
+    #[arg(long)]
+    ipf: Option<u32>,
+
+    /// Nested subroutine calls allowed before the program faults, defaults to 12 on CHIP-8 and
+    /// 16 on SUPER-CHIP and XO-CHIP
+    #[arg(long, value_name = "DEPTH", value_parser = clap::value_parser!(u8).range(1..=16))]
+    stack_depth: Option<u8>,
+
+    /// Run without sound
+    #[arg(long)]
+    mute: bool,
+
+    /// Key bindings config file, defaults to chip8-rs/config.toml in the user config directory
+    #[arg(long, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+
+    #[command(flatten)]
+    quirks: quirks::QuirksArgs,
+
+    #[command(flatten)]
+    headless: headless::HeadlessArgs,
+
+    #[command(flatten)]
+    trace: trace::TraceArgs,
+
+    #[command(flatten)]
+    movie: movie::MovieArgs,
+
+    /// ROM in a zip archive to run, by name or by its index in `list`
+    #[arg(long, value_name = "NAME|INDEX")]
+    entry: Option<String>,
+
+    /// ROM file or zip archive of ROMs to run
     #[arg(value_parser, required = true)]
-    file: String,
+    file: Option<String>,
 }
 
 #[tokio::main]
@@ -54,41 +156,116 @@ pub async fn main() {
     let args = Args::parse();
     dbg!(args.clone());
 
-    // Read ROM file
-    let file = std::fs::File::open(&args.file).expect("Could not open file");
-    let mut reader = std::io::BufReader::new(file);
-    let mut rom_buffer = Vec::new();
+    let result = match args.command.unwrap_or(Command::Run(args.run)) {
+        Command::Run(run_args) if run_args.headless.headless => run_headless(run_args),
+        Command::Run(run_args) => run(run_args).await,
+        Command::Disasm(disasm_args) => disasm::run(disasm_args),
+        Command::Asm(asm_args) => asm::run(asm_args),
+        Command::TraceDiff(trace_diff_args) => trace_diff::run(trace_diff_args),
+        Command::RenderAudio(render_audio_args) => render_audio::run(render_audio_args),
+        Command::List(list_args) => list::run(list_args),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    reader
-        .read_to_end(&mut rom_buffer)
-        .expect("Could not read file");
-    let rom = rom_buffer.as_slice();
+impl RunArgs {
+    fn rom(&self) -> Result<LoadedRom, String> {
+        let file = self.file.as_deref().expect("ROM file is required");
+        rom::load(file, self.platform.map(Into::into), self.entry.as_deref())
+    }
+
+    fn system(
+        &self,
+        rom: &RomImage,
+        info: Option<&RomInfo>,
+    ) -> Result<System, Box<dyn std::error::Error>> {
+        let mut builder = rom::builder(rom, info, self.platform, self.ipf, &self.quirks);
+        if let Some(stack_depth) = self.stack_depth {
+            builder = builder.stack_depth(stack_depth.into());
+        }
+        if self.debug || self.gdb.is_some() {
+            let mut debugger = Debugger::new();
+            debugger.pause();
+            builder = builder.debugger(debugger);
+        }
+        builder = self.movie.apply(builder, rom)?;
+        if let Some(tracer) = self.trace.tracer()? {
+            builder = builder.tracer(tracer);
+        }
+        if let Some(path) = &self.headless.wav {
+            builder = builder.audio_sink(WavSink::create(path)?);
+        } else if !self.headless.headless && !self.mute {
+            #[cfg(feature = "audio")]
+            if let Some(speaker) = audio::SpeakerSink::open() {
+                builder = builder.audio_sink(speaker);
+            }
+        }
+        Ok(builder.run())
+    }
+}
+
+fn run_headless(args: RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let LoadedRom { rom, info, .. } = args.rom()?;
+    let mut system = args.system(&rom, info.as_ref())?;
+    let result = headless::run(&mut system, &args.headless);
+    args.movie.save(&system)?;
+    result
+}
+
+async fn run(args: RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let LoadedRom { rom, info, path } = args.rom()?;
+    let rom_path = path.as_path();
+    let keymap = Keymap::load(args.config.as_deref(), &rom)?;
+    let colors = info.as_ref().and_then(|info| info.colors.as_ref()).map_or(
+        (PIXEL_ON_COLOR, PIXEL_OFF_COLOR),
+        |colors| {
+            let color = |index: usize, default| colors.pixels.get(index).map_or(default, to_color);
+            (color(1, PIXEL_ON_COLOR), color(0, PIXEL_OFF_COLOR))
+        },
+    );
+    if let Some(info) = info.as_ref().filter(|info| !info.keys.is_empty()) {
+        let keys = info
+            .keys
+            .iter()
+            .map(|(action, key)| format!("{} {:X}", action, key))
+            .collect::<Vec<_>>();
+        info!("Keys for {}: {}", info.title, keys.join(", "));
+    }
 
     // Initialize CPU
-    let system_builder = SystemBuilder::new(rom);
+    let mut system = args.system(&rom, info.as_ref())?;
+    let rotation = info.as_ref().map_or(0, |info| info.screen_rotation);
 
     // Create window
     let event_loop = EventLoop::new().expect("Could not create event loop");
     let window = WindowBuilder::new()
-        .with_title("Chip8")
-        .with_inner_size(LogicalSize::new(
-            DISPLAY_WIDTH as f32 * DEFAULT_PIXEL_SIZE,
-            DISPLAY_HEIGHT as f32 * DEFAULT_PIXEL_SIZE,
-        ))
+        .with_title(match &info {
+            Some(info) => format!("Chip8 - {}", info.title),
+            None => "Chip8".into(),
+        })
+        .with_inner_size(LogicalSize::<f32>::from(rotate_size(
+            (
+                DISPLAY_WIDTH as f32 * DEFAULT_PIXEL_SIZE,
+                DISPLAY_HEIGHT as f32 * DEFAULT_PIXEL_SIZE,
+            ),
+            rotation,
+        )))
         .build(&event_loop)
         .expect("Could not create window");
 
-    let mut renderer = PixelRenderer::new(
-        &window,
-        DISPLAY_HEIGHT,
-        DISPLAY_WIDTH,
-        DEFAULT_PIXEL_SIZE,
-        PIXEL_ON_COLOR,
-        PIXEL_OFF_COLOR,
-    )
-    .await;
+    let mut resolution = (DISPLAY_WIDTH, DISPLAY_HEIGHT);
+    let mut renderer = create_renderer(&window, resolution, rotation, colors).await;
 
-    let system = system_builder.run();
+    let mut modifiers = winit::keyboard::ModifiersState::empty();
+    let mut controls = Controls::default();
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = gamepad::Gamepads::open();
+    let mut repl = args.debug.then(debug::Repl::spawn);
+    let mut gdb = args.gdb.map(wait_for_gdb).transpose()?;
 
     // Main loop
     let _ = event_loop.run(|event, event_target| {
@@ -101,55 +278,59 @@ pub async fn main() {
                 use winit::event::WindowEvent;
 
                 match event {
+                    WindowEvent::ModifiersChanged(new_modifiers) => {
+                        modifiers = new_modifiers.state();
+                    }
                     WindowEvent::KeyboardInput {
                         device_id: _,
                         event: key_event,
                         is_synthetic: _,
                     } => {
                         use winit::event::ElementState;
-                        use winit::keyboard::{KeyCode, PhysicalKey};
-                        // Keymap:
-                        // on a US layout keyboard, a 4x4 grid on the left side of the keyboard
-                        // Keyboard keys; Chip-8 keys
-                        // 1, 2, 3, 4;    1, 2, 3, C
-                        // Q, W, E, R;    4, 5, 6, D
-                        // A, S, D, F;    7, 8, 9, E
-                        // Z, X, C, V;    A, 0, B, F
-
-                        if let Some(key) = match key_event.physical_key {
-                            PhysicalKey::Code(code) => match code {
-                                KeyCode::Digit1 => Some(0x1),
-                                KeyCode::Digit2 => Some(0x2),
-                                KeyCode::Digit3 => Some(0x3),
-                                KeyCode::Digit4 => Some(0xC),
-                                KeyCode::KeyQ => Some(0x4),
-                                KeyCode::KeyW => Some(0x5),
-                                KeyCode::KeyE => Some(0x6),
-                                KeyCode::KeyR => Some(0xD),
-                                KeyCode::KeyA => Some(0x7),
-                                KeyCode::KeyS => Some(0x8),
-                                KeyCode::KeyD => Some(0x9),
-                                KeyCode::KeyF => Some(0xE),
-                                KeyCode::KeyZ => Some(0xA),
-                                KeyCode::KeyX => Some(0x0),
-                                KeyCode::KeyC => Some(0xB),
-                                KeyCode::KeyV => Some(0xF),
-                                _ => None,
-                            },
-                            _ => None,
-                        } {
-                            match key_event.state {
-                                ElementState::Pressed => system.key_down(key as u8),
-                                ElementState::Released => system.key_up(key as u8),
+                        use winit::keyboard::PhysicalKey;
+
+                        let PhysicalKey::Code(code) = key_event.physical_key else {
+                            return;
+                        };
+                        let pressed = key_event.state == ElementState::Pressed;
+
+                        // Save states: Shift+F1-F9 saves to a slot, F1-F9 loads it
+                        if let Some(slot) = slots::slot(code) {
+                            if pressed && !key_event.repeat {
+                                if modifiers.shift_key() {
+                                    slots::save(&system, rom_path, slot);
+                                } else {
+                                    slots::load(&mut system, rom_path, slot);
+                                }
                             }
+                            return;
                         }
+
+                        controls.input(
+                            &mut system,
+                            &keymap,
+                            rom_path,
+                            code.into(),
+                            pressed,
+                            key_event.repeat,
+                        );
                     }
                     WindowEvent::CloseRequested => event_target.exit(),
                     WindowEvent::Resized(physical_size) => renderer.resize(*physical_size),
                     WindowEvent::RedrawRequested => {
+                        // Recreate the pixel grid when the program switches resolution
+                        if system.resolution() != resolution {
+                            resolution = system.resolution();
+                            renderer = tokio::task::block_in_place(|| {
+                                tokio::runtime::Handle::current().block_on(create_renderer(
+                                    &window, resolution, rotation, colors,
+                                ))
+                            });
+                            renderer.resize(window.inner_size());
+                        }
+
                         // Pixel rendering
-                        let pixels = system
-                            .pixels()
+                        let pixels = rotate(system.pixels(), resolution, rotation)
                             .into_iter()
                             .map(|p| match p {
                                 chip8::display::Pixel::On => renderer::Pixel::On,
@@ -174,9 +355,179 @@ pub async fn main() {
                     _ => {}
                 }
             }
-            Event::AboutToWait => window.request_redraw(),
+            Event::AboutToWait => {
+                if let Some(repl) = &mut repl {
+                    if !repl.poll(&mut system) {
+                        event_target.exit();
+                    }
+                }
+
+                if let Some(stub) = &mut gdb {
+                    match stub.poll(&mut system) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            println!("GDB disconnected");
+                            gdb = None;
+                        }
+                        Err(err) => {
+                            error!("GDB connection failed: {}", err);
+                            gdb = None;
+                        }
+                    }
+                }
+
+                #[cfg(feature = "gamepad")]
+                if let Some(gamepads) = &mut gamepads {
+                    for (button, pressed) in gamepads.poll() {
+                        let input = button.into();
+                        controls.input(&mut system, &keymap, rom_path, input, pressed, false);
+                    }
+                }
+
+                if controls.rewinding {
+                    if let Err(err) = system.update_rewind() {
+                        error!("Could not rewind: {}", err);
+                    }
+                } else {
+                    system.update();
+                }
+
+                if let Some(repl) = &mut repl {
+                    repl.report(&mut system);
+                }
+
+                // Keep the window open after a fault or exit while debugging, so it can be
+                // inspected
+                let debugging = repl.is_some() || gdb.is_some();
+                if !debugging && (system.fault().is_some() || system.exited()) {
+                    event_target.exit();
+                } else {
+                    window.request_redraw();
+                    event_target.set_control_flow(ControlFlow::WaitUntil(
+                        std::time::Instant::now() + FRAME_DURATION,
+                    ));
+                }
+            }
             Event::Resumed => window.request_redraw(),
             _ => {}
         }
     });
+
+    args.movie.save(&system)?;
+    match system.fault() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// Listens on a local port and blocks until a GDB client connects
+fn wait_for_gdb(port: u16) -> std::io::Result<GdbStub> {
+    let context = |what: &'static str| {
+        move |err: std::io::Error| {
+            std::io::Error::new(err.kind(), format!("Could not {} GDB: {}", what, err))
+        }
+    };
+    let listener =
+        std::net::TcpListener::bind(("127.0.0.1", port)).map_err(context("listen for"))?;
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let (stream, _) = listener.accept().map_err(context("accept"))?;
+    GdbStub::new(stream).map_err(context("set up the connection to"))
+}
+
+/// Emulator state driven by the hotkeys
+#[derive(Default)]
+struct Controls {
+    rewinding: bool,
+    fast_forward: bool,
+    paused: bool,
+}
+
+impl Controls {
+    /// Feeds a key or button to the Chip-8 keypad or to the hotkey it is bound to
+    fn input(
+        &mut self,
+        system: &mut System,
+        keymap: &Keymap,
+        rom_path: &std::path::Path,
+        input: Input,
+        pressed: bool,
+        repeat: bool,
+    ) {
+        if let Some(hotkey) = keymap.hotkey(input) {
+            match hotkey {
+                // Held hotkeys
+                Hotkey::Rewind => self.rewinding = pressed,
+                Hotkey::FastForward => self.fast_forward = pressed,
+                _ if !pressed || repeat => {}
+                Hotkey::Pause => self.paused = !self.paused,
+                Hotkey::Reset => system.reset(),
+                Hotkey::SaveState => slots::save(system, rom_path, 0),
+                Hotkey::LoadState => slots::load(system, rom_path, 0),
+            }
+            system.set_speed(if self.paused {
+                0.0
+            } else if self.fast_forward {
+                FAST_FORWARD_SPEED
+            } else {
+                1.0
+            });
+        } else if let Some(key) = keymap.key(input) {
+            if pressed {
+                system.key_down(key);
+            } else {
+                system.key_up(key);
+            }
+        }
+    }
+}
+
+/// Creates a renderer for a `width` by `height` grid turned `rotation` degrees clockwise that
+/// fills the window, drawing pixels with the `(on, off)` colours
+async fn create_renderer(
+    window: &Window,
+    (width, height): (usize, usize),
+    rotation: u16,
+    (on, off): (Color, Color),
+) -> PixelRenderer {
+    // Keep the window size constant, high resolution modes just get smaller pixels
+    let pixel_size = DEFAULT_PIXEL_SIZE * DISPLAY_WIDTH as f32 / width as f32;
+    let (width, height) = rotate_size((width, height), rotation);
+
+    PixelRenderer::new(window, height, width, pixel_size, on, off).await
+}
+
+/// Swaps width and height of a screen turned on its side
+fn rotate_size<T>((width, height): (T, T), rotation: u16) -> (T, T) {
+    match rotation {
+        90 | 270 => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// Turns the rows of a `width` by `height` screen `rotation` degrees clockwise
+fn rotate<T: Copy>(pixels: Vec<T>, (width, height): (usize, usize), rotation: u16) -> Vec<T> {
+    if !matches!(rotation, 90 | 180 | 270) {
+        return pixels;
+    }
+    let mut rotated = pixels.clone();
+    for y in 0..height {
+        for x in 0..width {
+            let index = match rotation {
+                90 => x * height + (height - 1 - y),
+                180 => (height - 1 - y) * width + (width - 1 - x),
+                _ => (width - 1 - x) * height + y,
+            };
+            rotated[index] = pixels[y * width + x];
+        }
+    }
+    rotated
+}
+
+fn to_color(&[r, g, b]: &[u8; 3]) -> Color {
+    Color {
+        r: (f32::from(r) / 255.0).into(),
+        g: (f32::from(g) / 255.0).into(),
+        b: (f32::from(b) / 255.0).into(),
+        a: 1.0,
+    }
 }
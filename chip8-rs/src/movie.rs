@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use tracing::info;
+
+use chip8::movie::Movie;
+use chip8::rom::RomImage;
+use chip8::system::{System, SystemBuilder};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct MovieArgs {
+    /// Record the keypad of every frame to a movie file, written when the emulator exits
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Play back a movie recorded with --record, ignoring the keyboard until it ends
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// Seed for the random number generator, random by default
+    #[arg(long, conflicts_with = "replay")]
+    seed: Option<u64>,
+}
+
+impl MovieArgs {
+    /// Sets up recording or playback, the movie overrides the platform, quirks and speed
+    pub fn apply<'a>(
+        &self,
+        mut builder: SystemBuilder<'a>,
+        rom: &RomImage,
+    ) -> Result<SystemBuilder<'a>, Box<dyn std::error::Error>> {
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        if self.record.is_some() {
+            builder = builder.record_movie();
+        }
+        if let Some(path) = &self.replay {
+            let bytes = std::fs::read(path)?;
+            let movie = Movie::decode(&bytes, rom.sha1())
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            info!("Replaying {} frames from {}", movie.len(), path.display());
+            builder = builder.replay(movie);
+        }
+        Ok(builder)
+    }
+
+    /// Writes the movie recorded so far, if recording
+    pub fn save(&self, system: &System) -> std::io::Result<()> {
+        if let (Some(path), Some(movie)) = (&self.record, system.movie()) {
+            std::fs::write(path, movie.encode())?;
+            info!("Recorded {} frames to {}", movie.len(), path.display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,24 @@
+use clap::ValueEnum;
+
+use chip8::platform::Platform;
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum PlatformArg {
+    /// The original CHIP-8 instruction set
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1
+    Schip,
+    /// XO-CHIP
+    Xochip,
+}
+
+impl From<PlatformArg> for Platform {
+    fn from(platform: PlatformArg) -> Platform {
+        match platform {
+            PlatformArg::Chip8 => Platform::Chip8,
+            PlatformArg::Schip => Platform::SuperChip,
+            PlatformArg::Xochip => Platform::XoChip,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use clap::ValueEnum;
+
+use chip8::quirks::Quirks;
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum Preset {
+    /// All quirks disabled
+    #[default]
+    None,
+    /// COSMAC VIP
+    Vip,
+    /// CHIP-48 and SUPER-CHIP
+    Schip,
+    /// XO-CHIP
+    Xochip,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct QuirksArgs {
+    /// Quirks preset, individual quirks can be overridden with the flags below. Defaults to the
+    /// quirks listed in the ROM database, or none at all
+    #[arg(long, value_enum)]
+    quirks: Option<Preset>,
+
+    /// 8XY6/8XYE shift Vy into Vx instead of shifting Vx in place
+    #[arg(long, value_name = "BOOL")]
+    shift_uses_vy: Option<bool>,
+
+    /// FX55/FX65 increment I past the last register transferred
+    #[arg(long, value_name = "BOOL")]
+    load_store_increments_i: Option<bool>,
+
+    /// BNNN jumps to NNN + VX instead of NNN + V0
+    #[arg(long, value_name = "BOOL")]
+    jump_uses_vx: Option<bool>,
+
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    #[arg(long, value_name = "BOOL")]
+    vf_reset: Option<bool>,
+
+    /// Clip sprites at the screen edges instead of wrapping
+    #[arg(long, value_name = "BOOL")]
+    clip_sprites: Option<bool>,
+
+    /// DXYN waits for the next frame before continuing
+    #[arg(long, value_name = "BOOL")]
+    display_wait: Option<bool>,
+}
+
+impl QuirksArgs {
+    /// The quirks given on the command line on top of `base`, `None` if there weren't any
+    pub fn quirks(&self, base: Quirks) -> Option<Quirks> {
+        let mut quirks = match self.quirks {
+            Some(Preset::None) => Quirks::default(),
+            Some(Preset::Vip) => Quirks::VIP,
+            Some(Preset::Schip) => Quirks::SCHIP,
+            Some(Preset::Xochip) => Quirks::XO_CHIP,
+            None => base,
+        };
+
+        let overrides = [
+            (self.shift_uses_vy, &mut quirks.shift_uses_vy),
+            (
+                self.load_store_increments_i,
+                &mut quirks.load_store_increments_i,
+            ),
+            (self.jump_uses_vx, &mut quirks.jump_uses_vx),
+            (self.vf_reset, &mut quirks.vf_reset),
+            (self.clip_sprites, &mut quirks.clip_sprites),
+            (self.display_wait, &mut quirks.display_wait),
+        ];
+        let mut changed = self.quirks.is_some();
+        for (flag, quirk) in overrides {
+            if let Some(value) = flag {
+                *quirk = value;
+                changed = true;
+            }
+        }
+
+        changed.then_some(quirks)
+    }
+}
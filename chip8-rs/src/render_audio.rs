@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use chip8::audio::WavSink;
+
+use crate::platform::PlatformArg;
+use crate::quirks::QuirksArgs;
+use crate::rom::LoadedRom;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct RenderAudioArgs {
+    /// Instruction set to emulate, defaults to the one listed in the ROM database or CHIP-8
+    #[arg(long, value_enum)]
+    platform: Option<PlatformArg>,
+
+    /// Instructions executed per 60 Hz frame, defaults to the speed listed in the ROM database
+    #[arg(long)]
+    ipf: Option<u32>,
+
+    #[command(flatten)]
+    quirks: QuirksArgs,
+
+    /// Number of frames to render, stopping early if the program exits or faults
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+
+    /// Write the WAV file here, defaults to the ROM file with a .wav extension
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// ROM in a zip archive to run, by name or by its index in `list`
+    #[arg(long, value_name = "NAME|INDEX")]
+    entry: Option<String>,
+
+    /// ROM file or zip archive of ROMs to run
+    rom: String,
+}
+
+/// Runs the ROM as fast as possible and records the buzzer to a WAV file.
+///
+/// Every frame adds exactly 1/60 s of samples, so the same ROM always renders the same file. A
+/// fault still writes the audio up to that point but returns an error.
+pub fn run(args: RenderAudioArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let LoadedRom { rom, info, path } = crate::rom::load(
+        &args.rom,
+        args.platform.map(Into::into),
+        args.entry.as_deref(),
+    )?;
+    let output = args
+        .output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| path.with_extension("wav"));
+
+    let mut system =
+        crate::rom::builder(&rom, info.as_ref(), args.platform, args.ipf, &args.quirks)
+            .rewind_frames(0)
+            .audio_sink(WavSink::create(&output)?)
+            .run();
+    while system.frame() < args.frames && system.fault().is_none() && !system.exited() {
+        system.run_frame();
+    }
+    system.finish_audio()?;
+    println!(
+        "Wrote {} frames of audio to {}",
+        system.frame(),
+        output.display()
+    );
+
+    match system.fault() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: RenderAudioArgs,
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("chip8-render-audio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("beep.ch8");
+        // LD V0, 0x03; LD ST, V0; JP 0x204
+        std::fs::write(&rom, [0x60, 0x03, 0xF0, 0x18, 0x12, 0x04]).unwrap();
+
+        let cli = Cli::parse_from(["render-audio", "--frames", "10", rom.to_str().unwrap()]);
+        run(cli.args).unwrap();
+
+        let mut reader = hound::WavReader::open(dir.join("beep.wav")).unwrap();
+        let samples = reader
+            .samples::<i16>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Three frames of buzzer followed by seven of silence
+        let per_frame = samples.len() / 10;
+        assert_eq!(per_frame, 735);
+        assert!(samples[..3 * per_frame].iter().all(|&s| s != 0));
+        assert!(samples[3 * per_frame..].iter().all(|&s| s == 0));
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use chip8::cartridge::Cartridge;
+use chip8::database::{Database, RomInfo};
+use chip8::platform::Platform;
+use chip8::quirks::Quirks;
+use chip8::rom::RomImage;
+use chip8::system::SystemBuilder;
+
+use crate::archive;
+use crate::platform::PlatformArg;
+use crate::quirks::QuirksArgs;
+
+/// A ROM read from disk, along with what is known about it
+#[derive(Clone, Debug)]
+pub struct LoadedRom {
+    pub rom: RomImage,
+    /// The options of an Octo cartridge or the ROM's entry in the ROM database
+    pub info: Option<RomInfo>,
+    /// Where the ROM's save states and other files go: the ROM file itself, or for a ROM in an
+    /// archive a file named after both next to the archive
+    pub path: PathBuf,
+}
+
+/// Reads and checks a ROM file, logging anything unusual about it.
+///
+/// Zip archives are searched for ROMs, picking the one `entry` names, see [`archive::select`].
+/// Without a `platform` the ROM is checked against the one the ROM database or cartridge lists
+/// for it.
+pub fn load(
+    path: impl AsRef<Path>,
+    platform: Option<Platform>,
+    entry: Option<&str>,
+) -> Result<LoadedRom, String> {
+    let path = path.as_ref();
+    let bytes =
+        std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+
+    let (bytes, name, file_name, rom_path) = if archive::is_zip(&bytes) {
+        let entry = archive::entries(&bytes)
+            .and_then(|entries| archive::select(entries, entry))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let rom_path = path.with_file_name(format!("{}-{}", stem, entry.file_name()));
+        let name = format!("{} ({})", path.display(), entry.name);
+        let file_name = PathBuf::from(entry.file_name());
+        (entry.bytes, name, file_name, rom_path)
+    } else if entry.is_some() {
+        return Err(format!(
+            "{}: not a zip archive, only archives have entries",
+            path.display()
+        ));
+    } else {
+        (
+            bytes,
+            path.display().to_string(),
+            path.to_path_buf(),
+            path.to_path_buf(),
+        )
+    };
+
+    if Cartridge::is_gif(&bytes) {
+        let cartridge = Cartridge::decode(&bytes).map_err(|err| format!("{}: {}", name, err))?;
+        let rom = match platform {
+            Some(platform) => RomImage::new(cartridge.rom.bytes().to_vec(), platform)
+                .map_err(|err| format!("{}: {}", name, err))?,
+            None => cartridge.rom,
+        };
+        // Cartridges don't store a title, the file name is the closest thing
+        let mut info = cartridge.info;
+        if let Some(stem) = file_name.file_stem() {
+            info.title = stem.to_string_lossy().into_owned();
+        }
+        return Ok(LoadedRom {
+            rom,
+            info: Some(info),
+            path: rom_path,
+        });
+    }
+
+    let rom = match platform {
+        Some(platform) => RomImage::new(bytes, platform),
+        None => RomImage::detect(bytes),
+    }
+    .map_err(|err| format!("{}: {}", name, err))?;
+
+    for warning in rom.warnings() {
+        warn!("{}: {}", name, warning);
+    }
+    let info = Database::bundled().lookup(&rom.sha1());
+    Ok(LoadedRom {
+        rom,
+        info,
+        path: rom_path,
+    })
+}
+
+/// Sets up `rom` with the settings from `info`, overridden by the ones given on the command
+/// line
+pub fn builder<'a>(
+    rom: &'a RomImage,
+    info: Option<&RomInfo>,
+    platform: Option<PlatformArg>,
+    instructions_per_frame: Option<u32>,
+    quirks: &QuirksArgs,
+) -> SystemBuilder<'a> {
+    let mut builder = SystemBuilder::new(rom);
+    if let Some(info) = info {
+        builder = builder.rom_info(info);
+    }
+    if let Some(platform) = platform {
+        builder = builder.platform(platform.into());
+    }
+    if let Some(instructions_per_frame) = instructions_per_frame {
+        builder = builder.instructions_per_frame(instructions_per_frame);
+    }
+    let base = info.map_or_else(Quirks::default, |info| info.quirks);
+    if let Some(quirks) = quirks.quirks(base) {
+        builder = builder.quirks(quirks);
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::zip;
+
+    fn temp_path(extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chip8-rom-{}.{}", std::process::id(), extension))
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let err = load("does/not/exist.ch8", None, None).unwrap_err();
+        assert!(err.starts_with("Could not read does/not/exist.ch8: "));
+
+        let path = temp_path("ch8");
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n").unwrap();
+        let err = load(&path, Some(Platform::Chip8), None).unwrap_err();
+        assert!(err.ends_with(": file looks like a PNG image, not a CHIP-8 ROM"));
+
+        let err = load(&path, None, Some("0")).unwrap_err();
+        assert!(err.ends_with(": not a zip archive, only archives have entries"));
+
+        // GIFs are taken for Octo cartridges
+        std::fs::write(&path, b"GIF89a").unwrap();
+        let err = load(&path, None, None).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.ends_with(": invalid GIF: unexpected end of GIF"));
+    }
+
+    #[test]
+    fn test_load_cartridge() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../res/cartridges/bounce.gif");
+        let loaded = load(path, None, None).unwrap();
+        assert_eq!(loaded.info.unwrap().title, "bounce");
+        assert_eq!(loaded.rom.platform(), Platform::XoChip);
+        assert_eq!(loaded.path, Path::new(path));
+
+        let loaded = load(path, Some(Platform::Chip8), None).unwrap();
+        assert_eq!(loaded.rom.platform(), Platform::Chip8);
+    }
+
+    #[test]
+    fn test_load_archive() {
+        let path = temp_path("zip");
+        std::fs::write(
+            &path,
+            zip(&[
+                ("roms/pong.ch8", &[0x12, 0x00]),
+                ("roms/blinky.sc8", &[0x00, 0xFF, 0x12, 0x02]),
+            ]),
+        )
+        .unwrap();
+        let loaded = load(&path, Some(Platform::SuperChip), Some("blinky"));
+        let err = load(&path, None, None).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.rom.bytes(), [0x00, 0xFF, 0x12, 0x02]);
+        assert_eq!(
+            loaded.path,
+            path.with_file_name(format!("chip8-rom-{}-blinky.sc8", std::process::id()))
+        );
+        assert!(err.contains(": archive holds 2 ROMs, pick one with --entry:"));
+    }
+
+    #[test]
+    fn test_load_archived_cartridge() {
+        let path = temp_path("cartridges.zip");
+        let bounce = include_bytes!("../../res/cartridges/bounce.gif");
+        std::fs::write(&path, zip(&[("octo/ball.xo8", bounce)])).unwrap();
+        let loaded = load(&path, None, None);
+        std::fs::remove_file(&path).unwrap();
+
+        // Named after the entry rather than the archive
+        assert_eq!(loaded.unwrap().info.unwrap().title, "ball");
+    }
+}
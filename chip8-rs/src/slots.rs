@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{error, info};
+use winit::keyboard::KeyCode;
+
+use chip8::system::System;
+
+/// Save state slots live next to the ROM, `game.ch8` gets `game.state1` through `game.state9`,
+/// plus `game.state0` for the quick save hotkeys
+pub fn path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("state{}", slot))
+}
+
+/// Maps F1 to F9 to slots 1 to 9
+pub fn slot(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::F1 => Some(1),
+        KeyCode::F2 => Some(2),
+        KeyCode::F3 => Some(3),
+        KeyCode::F4 => Some(4),
+        KeyCode::F5 => Some(5),
+        KeyCode::F6 => Some(6),
+        KeyCode::F7 => Some(7),
+        KeyCode::F8 => Some(8),
+        KeyCode::F9 => Some(9),
+        _ => None,
+    }
+}
+
+pub fn save(system: &System, rom: &Path, slot: u8) {
+    let path = path(rom, slot);
+    match std::fs::write(&path, system.save_state()) {
+        Ok(()) => info!("Saved state to {}", path.display()),
+        Err(err) => error!("Could not save state to {}: {}", path.display(), err),
+    }
+}
+
+pub fn load(system: &mut System, rom: &Path, slot: u8) {
+    let path = path(rom, slot);
+    let result = std::fs::read(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| system.load_state(&bytes).map_err(|err| err.to_string()));
+    match result {
+        Ok(()) => info!("Loaded state from {}", path.display()),
+        Err(err) => error!("Could not load state from {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path(Path::new("roms/game.ch8"), 3),
+            PathBuf::from("roms/game.state3")
+        );
+        assert_eq!(path(Path::new("game"), 1), PathBuf::from("game.state1"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use chip8::data::Address;
+use chip8::trace::{TraceFormat, Tracer};
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum TraceFormatArg {
+    /// `PC | OPCODE | MNEMONIC | BEFORE | AFTER` lines
+    Text,
+    /// JSON Lines
+    Json,
+}
+
+impl From<TraceFormatArg> for TraceFormat {
+    fn from(format: TraceFormatArg) -> TraceFormat {
+        match format {
+            TraceFormatArg::Text => TraceFormat::Text,
+            TraceFormatArg::Json => TraceFormat::Json,
+        }
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct TraceArgs {
+    /// Log every executed instruction with the registers before and after it, `-` for stdout
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Format of the trace, JSON Lines for `.json` and `.jsonl` files and text otherwise
+    #[arg(long, value_enum, requires = "trace")]
+    trace_format: Option<TraceFormatArg>,
+
+    /// Only trace instructions in an address range, e.g. `0x200-0x2FF`
+    #[arg(long, value_name = "START-END", value_parser = parse_range, requires = "trace")]
+    trace_range: Option<RangeInclusive<Address>>,
+}
+
+impl TraceArgs {
+    pub fn tracer(&self) -> std::io::Result<Option<Tracer>> {
+        let Some(path) = &self.trace else {
+            return Ok(None);
+        };
+
+        let format = self.format(path);
+        let mut tracer = if path == Path::new("-") {
+            Tracer::new(std::io::stdout(), format)
+        } else {
+            Tracer::new(BufWriter::new(File::create(path)?), format)
+        };
+        if let Some(range) = &self.trace_range {
+            tracer = tracer.range(range.clone());
+        }
+        Ok(Some(tracer))
+    }
+
+    fn format(&self, path: &Path) -> TraceFormat {
+        let json = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("json") || ext.eq_ignore_ascii_case("jsonl")
+            });
+        match self.trace_format {
+            Some(format) => format.into(),
+            None if json => TraceFormat::Json,
+            None => TraceFormat::Text,
+        }
+    }
+}
+
+fn parse_range(text: &str) -> Result<RangeInclusive<Address>, String> {
+    let (start, end) = text
+        .split_once('-')
+        .ok_or_else(|| "expected START-END".to_string())?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+    if start > end {
+        return Err(format!("0x{:X} is after 0x{:X}", start, end));
+    }
+    Ok(start..=end)
+}
+
+fn parse_address(text: &str) -> Result<Address, String> {
+    let text = text.trim();
+    let result = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => Address::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| format!("invalid address `{}`", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x200-0x2FF"), Ok(0x200..=0x2FF));
+        assert_eq!(parse_range("512-512"), Ok(0x200..=0x200));
+        assert!(parse_range("0x300-0x200").is_err());
+        assert!(parse_range("0x200").is_err());
+    }
+}
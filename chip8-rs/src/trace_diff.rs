@@ -0,0 +1,515 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use chip8::data::{Address, OpCode};
+use chip8::debugger::Debugger;
+use chip8::display::Pixel;
+use chip8::system::System;
+
+use crate::platform::PlatformArg;
+use crate::quirks::QuirksArgs;
+use crate::rom::LoadedRom;
+
+/// Frames waited for a single instruction before giving up, e.g. when the ROM waits for a key
+const MAX_WAIT_FRAMES: u32 = 600;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct TraceDiffArgs {
+    /// Instruction set to emulate, defaults to the one listed in the ROM database or CHIP-8
+    #[arg(long, value_enum)]
+    platform: Option<PlatformArg>,
+
+    /// Instructions executed per 60 Hz frame, timers tick in between. Defaults to the speed
+    /// listed in the ROM database
+    #[arg(long)]
+    ipf: Option<u32>,
+
+    #[command(flatten)]
+    quirks: QuirksArgs,
+
+    /// Number of steps to show before and after the divergence
+    #[arg(long, default_value_t = 5)]
+    context: usize,
+
+    /// ROM in a zip archive to run, by name or by its index in `list`
+    #[arg(long, value_name = "NAME|INDEX")]
+    entry: Option<String>,
+
+    /// ROM file or zip archive of ROMs to run
+    rom: String,
+
+    /// Reference log with one line per instruction, in any `--trace` format. Each line holds the
+    /// state before its instruction runs. JSON lines may also carry `memory` as a hex string
+    /// and `display` as a string of 0s and 1s to compare those too.
+    reference: String,
+}
+
+/// The state a reference log expects before an instruction runs
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Step {
+    pc: Address,
+    opcode: OpCode,
+    v: [u8; 16],
+    i: u16,
+    memory: Option<Vec<u8>>,
+    display: Option<Vec<bool>>,
+}
+
+/// The first thing that differs between the reference and our state
+#[derive(Debug, PartialEq)]
+enum Difference {
+    Pc {
+        expected: Address,
+        actual: Address,
+    },
+    Opcode {
+        expected: OpCode,
+        actual: OpCode,
+    },
+    Register {
+        x: usize,
+        expected: u8,
+        actual: u8,
+    },
+    I {
+        expected: u16,
+        actual: u16,
+    },
+    Memory {
+        addr: usize,
+        expected: u8,
+        actual: u8,
+    },
+    Pixel {
+        x: usize,
+        y: usize,
+        expected: bool,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on = |pixel: bool| if pixel { "on" } else { "off" };
+        match self {
+            Difference::Pc { expected, actual } => {
+                write!(f, "PC is 0x{:03X}, expected 0x{:03X}", actual, expected)
+            }
+            Difference::Opcode { expected, actual } => write!(
+                f,
+                "opcode at PC is {:04X}, expected {:04X}",
+                actual, expected
+            ),
+            Difference::Register {
+                x,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "V{:X} is 0x{:02X}, expected 0x{:02X}",
+                x, actual, expected
+            ),
+            Difference::I { expected, actual } => {
+                write!(f, "I is 0x{:03X}, expected 0x{:03X}", actual, expected)
+            }
+            Difference::Memory {
+                addr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "memory at 0x{:03X} is 0x{:02X}, expected 0x{:02X}",
+                addr, actual, expected
+            ),
+            Difference::Pixel { x, y, expected } => write!(
+                f,
+                "pixel ({}, {}) is {}, expected {}",
+                x,
+                y,
+                on(!expected),
+                on(*expected)
+            ),
+        }
+    }
+}
+
+/// An instruction we ran, for context
+struct Executed {
+    step: usize,
+    pc: Address,
+    opcode: OpCode,
+    mnemonic: String,
+}
+
+impl fmt::Display for Executed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8}  0x{:03X}  {:04X}  {}",
+            self.step, self.pc, self.opcode, self.mnemonic
+        )
+    }
+}
+
+pub fn run(args: TraceDiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let LoadedRom { rom, info, .. } = crate::rom::load(
+        &args.rom,
+        args.platform.map(Into::into),
+        args.entry.as_deref(),
+    )?;
+    let reference = std::fs::read_to_string(&args.reference)?;
+    let lines = reference
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    let steps = lines
+        .iter()
+        .enumerate()
+        .map(|(n, line)| parse_step(line).map_err(|err| format!("line {}: {}", n + 1, err)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut debugger = Debugger::new();
+    debugger.pause();
+    let mut system =
+        crate::rom::builder(&rom, info.as_ref(), args.platform, args.ipf, &args.quirks)
+            .rewind_frames(0)
+            .debugger(debugger)
+            .run();
+
+    let mut history = VecDeque::with_capacity(args.context + 1);
+    for (n, expected) in steps.iter().enumerate() {
+        if let Some(difference) = compare(&mut system, expected) {
+            println!(
+                "Diverged at step {} (frame {}): {}",
+                n,
+                system.frame(),
+                difference
+            );
+            if let Some(cause) = history.back() {
+                let Executed { pc, mnemonic, .. } = cause;
+                println!("Caused by 0x{:03X}: {}", pc, mnemonic);
+            }
+
+            println!();
+            println!("Ours:");
+            for executed in &history {
+                println!("  {}", executed);
+            }
+            println!("Reference:");
+            let start = n.saturating_sub(args.context);
+            let end = (n + args.context + 1).min(lines.len());
+            for (i, line) in lines.iter().enumerate().take(end).skip(start) {
+                let marker = if i == n { ">" } else { " " };
+                println!("{} {:>8}  {}", marker, i, line.trim());
+            }
+            return Err(format!("traces diverged at step {}", n).into());
+        }
+
+        let pc = system.cpu().registers.pc;
+        history.push_back(Executed {
+            step: n,
+            pc,
+            opcode: expected.opcode,
+            mnemonic: system
+                .cpu()
+                .instruction_at(pc)
+                .map_or_else(|| "???".to_string(), |instr| instr.to_string()),
+        });
+        if history.len() > args.context {
+            history.pop_front();
+        }
+
+        step(&mut system).map_err(|err| format!("step {} at 0x{:03X}: {}", n, pc, err))?;
+    }
+
+    println!("No divergence in {} steps", steps.len());
+    Ok(())
+}
+
+/// Runs a single instruction, letting frames pass while the CPU waits
+fn step(system: &mut System) -> Result<(), String> {
+    if let Some(debugger) = system.debugger_mut() {
+        debugger.step();
+    }
+    for _ in 0..MAX_WAIT_FRAMES {
+        system.run_frame();
+        if let Some(err) = system.fault() {
+            return Err(err.to_string());
+        }
+        if system.paused() {
+            return Ok(());
+        }
+        if system.exited() {
+            return Err("the program exited".to_string());
+        }
+    }
+    Err("the CPU is stuck, e.g. waiting for a key".to_string())
+}
+
+fn compare(system: &mut System, expected: &Step) -> Option<Difference> {
+    let cpu = system.cpu();
+    let registers = &cpu.registers;
+    if registers.pc != expected.pc {
+        return Some(Difference::Pc {
+            expected: expected.pc,
+            actual: registers.pc,
+        });
+    }
+
+    let opcode = match cpu.read_memory(registers.pc, 2).as_slice() {
+        [high, low] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+    if opcode != expected.opcode {
+        return Some(Difference::Opcode {
+            expected: expected.opcode,
+            actual: opcode,
+        });
+    }
+
+    let register = (0..16).find(|&x| registers.v[x] != expected.v[x]);
+    if let Some(x) = register {
+        return Some(Difference::Register {
+            x,
+            expected: expected.v[x],
+            actual: registers.v[x],
+        });
+    }
+    if registers.i != expected.i {
+        return Some(Difference::I {
+            expected: expected.i,
+            actual: registers.i,
+        });
+    }
+
+    if let Some(memory) = &expected.memory {
+        let actual = cpu.read_memory(0, memory.len());
+        let addr = (0..memory.len()).find(|&addr| actual.get(addr) != Some(&memory[addr]));
+        if let Some(addr) = addr {
+            return Some(Difference::Memory {
+                addr,
+                expected: memory[addr],
+                actual: actual.get(addr).copied().unwrap_or_default(),
+            });
+        }
+    }
+
+    if let Some(display) = &expected.display {
+        let (width, _) = system.resolution();
+        let pixels = system.pixels();
+        let index = (0..display.len())
+            .find(|&i| pixels.get(i).map(|p| *p == Pixel::On) != Some(display[i]));
+        if let Some(index) = index {
+            return Some(Difference::Pixel {
+                x: index % width,
+                y: index / width,
+                expected: display[index],
+            });
+        }
+    }
+
+    None
+}
+
+fn parse_step(line: &str) -> Result<Step, String> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        parse_json_step(line)
+    } else {
+        parse_text_step(line)
+    }
+}
+
+/// Parses a `--trace` text line, `PC | OPCODE | MNEMONIC | BEFORE | AFTER`
+fn parse_text_step(line: &str) -> Result<Step, String> {
+    let fields = line.split(" | ").collect::<Vec<_>>();
+    let [pc, opcode, _, before, ..] = fields.as_slice() else {
+        return Err("expected `PC | OPCODE | MNEMONIC | BEFORE | AFTER`".to_string());
+    };
+
+    let mut step = Step {
+        pc: parse_hex(pc)?,
+        opcode: parse_hex(opcode)?,
+        ..Step::default()
+    };
+    for field in before.split_whitespace() {
+        match field.split_once('=') {
+            Some(("V", registers)) => {
+                let bytes = parse_hex_bytes(registers)?;
+                step.v = bytes
+                    .try_into()
+                    .map_err(|_| "expected 16 registers".to_string())?;
+            }
+            Some(("I", i)) => step.i = parse_hex(i)?,
+            _ => {}
+        }
+    }
+    Ok(step)
+}
+
+/// Parses a `--trace` JSON line, or a flat object with the same fields as `before` next to
+/// `pc` and `opcode`
+fn parse_json_step(line: &str) -> Result<Step, String> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let state = value.get("before").unwrap_or(&value);
+    let number = |value: &serde_json::Value, name: &str| {
+        value
+            .get(name)
+            .and_then(serde_json::Value::as_u64)
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| format!("missing `{}`", name))
+    };
+
+    let v = state
+        .get("v")
+        .and_then(serde_json::Value::as_array)
+        .ok_or("missing `v`")?
+        .iter()
+        .map(|v| v.as_u64().and_then(|v| u8::try_from(v).ok()))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|v| v.try_into().ok())
+        .ok_or("expected 16 registers in `v`")?;
+    let memory = match value.get("memory").and_then(serde_json::Value::as_str) {
+        Some(memory) => Some(parse_hex_bytes(memory)?),
+        None => None,
+    };
+    let display = match value.get("display").and_then(serde_json::Value::as_str) {
+        Some(display) => Some(
+            display
+                .chars()
+                .map(|c| match c {
+                    '0' => Ok(false),
+                    '1' => Ok(true),
+                    _ => Err(format!("invalid pixel `{}` in `display`", c)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+
+    Ok(Step {
+        pc: number(&value, "pc")?,
+        opcode: number(&value, "opcode")?,
+        v,
+        i: number(state, "i")?,
+        memory,
+        display,
+    })
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex number `{}`", text))
+}
+
+fn parse_hex_bytes(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits in `{}`", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex bytes `{}`", text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chip8::platform::Platform;
+    use chip8::rom::RomImage;
+    use chip8::system::SystemBuilder;
+
+    use super::*;
+
+    // LD V1, 0x42; LD I, 0x300; ADD V1, 1; JP 0x204
+    const ROM: [u8; 8] = [0x61, 0x42, 0xA3, 0x00, 0x71, 0x01, 0x12, 0x04];
+
+    fn system() -> System {
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        let rom = RomImage::new(ROM, Platform::Chip8).unwrap();
+        SystemBuilder::new(&rom).debugger(debugger).run()
+    }
+
+    #[test]
+    fn test_parse_text_step() {
+        let step = parse_text_step(
+            "0202 | A300 | LD I, 0x300 \
+            | V=00420000000000000000000000000000 I=0000 SP=00 DT=00 ST=00 \
+            | V=00420000000000000000000000000000 I=0300 SP=00 DT=00 ST=00",
+        )
+        .unwrap();
+        assert_eq!(step.pc, 0x202);
+        assert_eq!(step.opcode, 0xA300);
+        assert_eq!(step.v[1], 0x42);
+        assert_eq!(step.i, 0);
+        assert!(parse_text_step("0202 | A300").is_err());
+    }
+
+    #[test]
+    fn test_parse_json_step() {
+        let step = parse_json_step(
+            r#"{"pc":514,"opcode":41728,"v":[0,66,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"i":0,"memory":"00e0","display":"01"}"#,
+        )
+        .unwrap();
+        assert_eq!(step.pc, 0x202);
+        assert_eq!(step.v[1], 0x42);
+        assert_eq!(step.memory, Some(vec![0x00, 0xE0]));
+        assert_eq!(step.display, Some(vec![false, true]));
+
+        assert!(parse_json_step(r#"{"pc":514,"opcode":41728,"v":[0],"i":0}"#).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut system = system();
+        let mut expected = Step {
+            pc: 0x200,
+            opcode: 0x6142,
+            ..Step::default()
+        };
+        assert_eq!(compare(&mut system, &expected), None);
+
+        step(&mut system).unwrap();
+        step(&mut system).unwrap();
+        expected = Step {
+            pc: 0x204,
+            opcode: 0x7101,
+            i: 0x300,
+            ..Step::default()
+        };
+        assert_eq!(
+            compare(&mut system, &expected),
+            Some(Difference::Register {
+                x: 1,
+                expected: 0,
+                actual: 0x42
+            })
+        );
+
+        expected.v[1] = 0x42;
+        expected.memory = Some(vec![0; 0x51]);
+        assert_eq!(
+            compare(&mut system, &expected),
+            Some(Difference::Memory {
+                addr: 0x50,
+                expected: 0,
+                actual: 0xF0
+            })
+        );
+
+        expected.memory = None;
+        expected.display = Some(vec![true]);
+        assert_eq!(
+            compare(&mut system, &expected),
+            Some(Difference::Pixel {
+                x: 0,
+                y: 0,
+                expected: true
+            })
+        );
+    }
+}
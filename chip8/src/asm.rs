@@ -0,0 +1,1051 @@
+//! A two-pass assembler for the classic CHIP-8 mnemonics printed by the disassembler.
+//!
+//! ```text
+//! ; Comments start with a semicolon
+//! SPEED = 4               ; constants, also `SPEED EQU 4`
+//! include "sprites.asm"   ; paths are relative to the including file
+//!
+//! start:  LD V0, SPEED * 2
+//!         LD I, sprite
+//!         DRW V0, V1, sprite_end - sprite
+//!         JP start
+//!
+//! sprite: DB 0b11110000, 0x90, $F0
+//! sprite_end:
+//!         DW 0x1234
+//! ```
+//!
+//! Expressions support `+ - * / % & | ^ << >> ~`, parentheses, decimal, `0x`/`$` hex, `0b` binary
+//! and `'c'` character literals.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::data::{Address, Register};
+use crate::instruction::Instruction;
+use crate::memory::PROGRAM_START;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A location in an assembly source file. Lines and columns start at 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub file: Option<PathBuf>,
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles `source` into a ROM image loaded at [`PROGRAM_START`].
+///
+/// `include` paths are resolved relative to the current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::default();
+    assembler.add_source(source, None, 0)?;
+    assembler.finish()
+}
+
+/// Assembles the file at `path` into a ROM image loaded at [`PROGRAM_START`].
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let source = std::fs::read_to_string(path).map_err(|err| AsmError {
+        file: Some(path.to_owned()),
+        span: Span {
+            line: 1,
+            column: 1,
+            len: 0,
+        },
+        message: err.to_string(),
+    })?;
+
+    let mut assembler = Assembler::default();
+    assembler.add_source(&source, Some(Rc::from(path)), 0)?;
+    assembler.finish()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64, Span),
+    Symbol(String, Span),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(Register),
+    I,
+    IndirectI,
+    Delay,
+    Sound,
+    Key,
+    Font,
+    HiFont,
+    Bcd,
+    Rpl,
+    Pitch,
+    Long(Expr),
+    Value(Expr),
+}
+
+#[derive(Clone, Debug)]
+enum DataValue {
+    Expr(Expr, Span),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+enum ItemKind {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<(Operand, Span)>,
+    },
+    Data {
+        width: usize,
+        values: Vec<DataValue>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Item {
+    kind: ItemKind,
+    addr: usize,
+    span: Span,
+    file: Option<Rc<Path>>,
+}
+
+#[derive(Clone, Debug)]
+enum Symbol {
+    Label(usize),
+    Constant(Expr, Option<Rc<Path>>),
+}
+
+#[derive(Default)]
+struct Assembler {
+    symbols: HashMap<String, Symbol>,
+    items: Vec<Item>,
+    addr: Option<usize>,
+}
+
+fn error(file: &Option<Rc<Path>>, span: Span, message: impl Into<String>) -> AsmError {
+    AsmError {
+        file: file.as_ref().map(|f| f.to_path_buf()),
+        span,
+        message: message.into(),
+    }
+}
+
+impl Assembler {
+    fn addr(&self) -> usize {
+        self.addr.unwrap_or(PROGRAM_START as usize)
+    }
+
+    fn add_source(
+        &mut self,
+        source: &str,
+        file: Option<Rc<Path>>,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        for (i, text) in source.lines().enumerate() {
+            let tokens = lex_line(text, i + 1).map_err(|(span, msg)| error(&file, span, msg))?;
+            self.add_line(&tokens, &file, depth)?;
+        }
+        Ok(())
+    }
+
+    fn add_line(
+        &mut self,
+        tokens: &[Token],
+        file: &Option<Rc<Path>>,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let mut tokens = tokens;
+
+        // Label definitions
+        if let [Token {
+            kind: TokenKind::Ident(name),
+            span,
+        }, Token {
+            kind: TokenKind::Punct(":"),
+            ..
+        }, rest @ ..] = tokens
+        {
+            self.define(name, Symbol::Label(self.addr()), file, *span)?;
+            tokens = rest;
+        }
+
+        let Some((first, rest)) = tokens.split_first() else {
+            return Ok(());
+        };
+        let TokenKind::Ident(word) = &first.kind else {
+            return Err(error(file, first.span, "expected a mnemonic or directive"));
+        };
+
+        // Constant definitions
+        if let Some(second) = rest.first() {
+            let is_constant = match &second.kind {
+                TokenKind::Punct("=") => true,
+                TokenKind::Ident(s) => s.eq_ignore_ascii_case("EQU"),
+                _ => false,
+            };
+            if is_constant {
+                let expr = parse_full_expr(&rest[1..], second.span, file)?;
+                return self.define(word, Symbol::Constant(expr, file.clone()), file, first.span);
+            }
+        }
+
+        match word.to_ascii_uppercase().as_str() {
+            "ORG" => {
+                let expr = parse_full_expr(rest, first.span, file)?;
+                let target = self.eval(&expr, file, &mut HashSet::new())?;
+                if target < self.addr() as i64 || target > 0xFFFF {
+                    return Err(error(
+                        file,
+                        first.span,
+                        format!("ORG 0x{:X} is behind the current address", target),
+                    ));
+                }
+                self.addr = Some(target as usize);
+            }
+            "INCLUDE" => {
+                let [Token {
+                    kind: TokenKind::Str(path),
+                    span,
+                }] = rest
+                else {
+                    return Err(error(file, first.span, "expected a quoted file path"));
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(error(file, *span, "includes are nested too deeply"));
+                }
+
+                let path = PathBuf::from(String::from_utf8_lossy(path).into_owned());
+                let path = match file.as_ref().and_then(|f| f.parent()) {
+                    Some(dir) if path.is_relative() => dir.join(path),
+                    _ => path,
+                };
+                let source = std::fs::read_to_string(&path).map_err(|err| {
+                    error(
+                        file,
+                        *span,
+                        format!("unable to include {}: {}", path.display(), err),
+                    )
+                })?;
+                self.add_source(&source, Some(Rc::from(path.as_path())), depth + 1)?;
+            }
+            "DB" | "DW" => {
+                let width = if word.eq_ignore_ascii_case("DB") {
+                    1
+                } else {
+                    2
+                };
+                let mut values = Vec::new();
+                let mut size = 0;
+                for group in split_operands(rest, first.span, file)? {
+                    match group {
+                        [Token {
+                            kind: TokenKind::Str(bytes),
+                            span,
+                        }] => {
+                            if width != 1 {
+                                return Err(error(file, *span, "strings are only allowed in DB"));
+                            }
+                            size += bytes.len();
+                            values.push(DataValue::Bytes(bytes.clone()));
+                        }
+                        _ => {
+                            let span = join_spans(group);
+                            size += width;
+                            values.push(DataValue::Expr(parse_full_expr(group, span, file)?, span));
+                        }
+                    }
+                }
+
+                self.push(ItemKind::Data { width, values }, size, first.span, file);
+            }
+            _ => {
+                let operands = split_operands(rest, first.span, file)?
+                    .into_iter()
+                    .map(|group| Ok((parse_operand(group, file)?, join_spans(group))))
+                    .collect::<Result<Vec<_>, AsmError>>()?;
+                // Only `LD I, LONG addr` is 4 bytes long
+                let size = if operands
+                    .iter()
+                    .any(|(op, _)| matches!(op, Operand::Long(_)))
+                {
+                    4
+                } else {
+                    2
+                };
+                self.push(
+                    ItemKind::Instruction {
+                        mnemonic: word.to_ascii_uppercase(),
+                        operands,
+                    },
+                    size,
+                    first.span,
+                    file,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, kind: ItemKind, size: usize, span: Span, file: &Option<Rc<Path>>) {
+        self.items.push(Item {
+            kind,
+            addr: self.addr(),
+            span,
+            file: file.clone(),
+        });
+        self.addr = Some(self.addr() + size);
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        symbol: Symbol,
+        file: &Option<Rc<Path>>,
+        span: Span,
+    ) -> Result<(), AsmError> {
+        if is_reserved(name) {
+            return Err(error(file, span, format!("'{}' is a reserved name", name)));
+        }
+        if self.symbols.insert(name.to_owned(), symbol).is_some() {
+            return Err(error(file, span, format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn eval(
+        &self,
+        expr: &Expr,
+        file: &Option<Rc<Path>>,
+        visiting: &mut HashSet<String>,
+    ) -> Result<i64, AsmError> {
+        let value = match expr {
+            Expr::Number(n, _) => *n,
+            Expr::Symbol(name, span) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => *addr as i64,
+                Some(Symbol::Constant(expr, const_file)) => {
+                    if !visiting.insert(name.clone()) {
+                        return Err(error(
+                            file,
+                            *span,
+                            format!("'{}' is defined in terms of itself", name),
+                        ));
+                    }
+                    let value = self.eval(expr, const_file, visiting)?;
+                    visiting.remove(name);
+                    value
+                }
+                None => return Err(error(file, *span, format!("undefined symbol '{}'", name))),
+            },
+            Expr::Unary(op, inner) => {
+                let inner = self.eval(inner, file, visiting)?;
+                match *op {
+                    "-" => inner.wrapping_neg(),
+                    "~" => !inner,
+                    _ => unreachable!("unknown unary operator {}", op),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, file, visiting)?;
+                let rhs_value = self.eval(rhs, file, visiting)?;
+                match *op {
+                    "+" => lhs.wrapping_add(rhs_value),
+                    "-" => lhs.wrapping_sub(rhs_value),
+                    "*" => lhs.wrapping_mul(rhs_value),
+                    "/" | "%" if rhs_value == 0 => {
+                        return Err(error(file, expr_span(rhs), "division by zero"))
+                    }
+                    "/" => lhs.wrapping_div(rhs_value),
+                    "%" => lhs.wrapping_rem(rhs_value),
+                    "&" => lhs & rhs_value,
+                    "|" => lhs | rhs_value,
+                    "^" => lhs ^ rhs_value,
+                    "<<" => lhs.wrapping_shl(rhs_value as u32),
+                    ">>" => lhs.wrapping_shr(rhs_value as u32),
+                    _ => unreachable!("unknown binary operator {}", op),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let start = PROGRAM_START as usize;
+        let mut rom = vec![0; self.addr().saturating_sub(start)];
+
+        for item in &self.items {
+            let offset = item.addr - start;
+            match &item.kind {
+                ItemKind::Instruction { mnemonic, operands } => {
+                    let bytes = self.instruction(mnemonic, operands, item)?.to_bytes();
+                    rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+                }
+                ItemKind::Data { width, values } => {
+                    let mut offset = offset;
+                    for value in values {
+                        match value {
+                            DataValue::Bytes(bytes) => {
+                                rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+                                offset += bytes.len();
+                            }
+                            DataValue::Expr(expr, span) => {
+                                let max = if *width == 1 { 0xFF } else { 0xFFFF };
+                                let value = self.eval(expr, &item.file, &mut HashSet::new())?;
+                                let value = check_range(value, max, *span, &item.file)?;
+                                let bytes = (value as u16).to_be_bytes();
+                                rom[offset..offset + width].copy_from_slice(&bytes[2 - width..]);
+                                offset += width;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(rom)
+    }
+
+    fn instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[(Operand, Span)],
+        item: &Item,
+    ) -> Result<Instruction, AsmError> {
+        use Operand::*;
+
+        let file = &item.file;
+        let value = |expr: &Expr, span: Span, max: i64| -> Result<i64, AsmError> {
+            let value = self.eval(expr, file, &mut HashSet::new())?;
+            check_range(value, max, span, file)
+        };
+        let addr = |expr: &Expr, span: Span| -> Result<Address, AsmError> {
+            value(expr, span, 0xFFF).map(|v| v as Address)
+        };
+        let byte = |expr: &Expr, span: Span| -> Result<u8, AsmError> {
+            value(expr, span, 0xFF).map(|v| v as u8)
+        };
+
+        let ops = operands.iter().map(|(op, _)| op).collect::<Vec<_>>();
+        let spans = operands.iter().map(|(_, span)| *span).collect::<Vec<_>>();
+
+        let instruction = match (mnemonic, ops.as_slice()) {
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SCD", [Value(e)]) => Instruction::Scd(value(e, spans[0], 0xF)? as u8),
+            ("SCU", [Value(e)]) => Instruction::Scu(value(e, spans[0], 0xF)? as u8),
+            ("SCR", []) => Instruction::Scr,
+            ("SCL", []) => Instruction::Scl,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::Low,
+            ("HIGH", []) => Instruction::High,
+            ("SYS", [Value(e)]) => Instruction::Sys(addr(e, spans[0])?),
+            ("JP", [Value(e)]) => Instruction::Jp(addr(e, spans[0])?),
+            ("JP", [Register(0), Value(e)]) => Instruction::JpV0(addr(e, spans[1])?),
+            ("CALL", [Value(e)]) => Instruction::Call(addr(e, spans[0])?),
+            ("SE", [Register(x), Register(y)]) => Instruction::SeReg(*x, *y),
+            ("SE", [Register(x), Value(e)]) => Instruction::Se(*x, byte(e, spans[1])?),
+            ("SAVE", [Register(x), Register(y)]) => Instruction::SaveRange(*x, *y),
+            ("LOAD", [Register(x), Register(y)]) => Instruction::LoadRange(*x, *y),
+            ("SNE", [Register(x), Register(y)]) => Instruction::SneReg(*x, *y),
+            ("SNE", [Register(x), Value(e)]) => Instruction::Sne(*x, byte(e, spans[1])?),
+            ("LD", [Register(x), Register(y)]) => Instruction::LdReg(*x, *y),
+            ("LD", [Register(x), Value(e)]) => Instruction::Ld(*x, byte(e, spans[1])?),
+            ("LD", [I, Value(e)]) => Instruction::LdI(addr(e, spans[1])?),
+            ("LD", [I, Long(e)]) => Instruction::LdILong(value(e, spans[1], 0xFFFF)? as Address),
+            ("LD", [Register(x), Delay]) => Instruction::LdFromDelay(*x),
+            ("LD", [Register(x), Key]) => Instruction::LdKey(*x),
+            ("LD", [Delay, Register(x)]) => Instruction::LdDelay(*x),
+            ("LD", [Sound, Register(x)]) => Instruction::LdSound(*x),
+            ("LD", [Font, Register(x)]) => Instruction::LdFont(*x),
+            ("LD", [HiFont, Register(x)]) => Instruction::LdHiFont(*x),
+            ("LD", [Bcd, Register(x)]) => Instruction::LdBcd(*x),
+            ("LD", [Pitch, Register(x)]) => Instruction::LdPitch(*x),
+            ("LD", [IndirectI, Register(x)]) => Instruction::Store(*x),
+            ("LD", [Register(x), IndirectI]) => Instruction::Load(*x),
+            ("LD", [Rpl, Register(x)]) => Instruction::StoreFlags(*x),
+            ("LD", [Register(x), Rpl]) => Instruction::LoadFlags(*x),
+            ("ADD", [Register(x), Register(y)]) => Instruction::AddReg(*x, *y),
+            ("ADD", [Register(x), Value(e)]) => Instruction::Add(*x, byte(e, spans[1])?),
+            ("ADD", [I, Register(x)]) => Instruction::AddI(*x),
+            ("OR", [Register(x), Register(y)]) => Instruction::Or(*x, *y),
+            ("AND", [Register(x), Register(y)]) => Instruction::And(*x, *y),
+            ("XOR", [Register(x), Register(y)]) => Instruction::Xor(*x, *y),
+            ("SUB", [Register(x), Register(y)]) => Instruction::Sub(*x, *y),
+            ("SUBN", [Register(x), Register(y)]) => Instruction::Subn(*x, *y),
+            // Without a Vy operand, shift Vx in place regardless of the shift quirk
+            ("SHR", [Register(x)]) => Instruction::Shr(*x, *x),
+            ("SHR", [Register(x), Register(y)]) => Instruction::Shr(*x, *y),
+            ("SHL", [Register(x)]) => Instruction::Shl(*x, *x),
+            ("SHL", [Register(x), Register(y)]) => Instruction::Shl(*x, *y),
+            ("RND", [Register(x), Value(e)]) => Instruction::Rnd(*x, byte(e, spans[1])?),
+            ("DRW", [Register(x), Register(y), Value(e)]) => Instruction::Drw {
+                x: *x,
+                y: *y,
+                n: value(e, spans[2], 0xF)? as u8,
+            },
+            ("SKP", [Register(x)]) => Instruction::Skp(*x),
+            ("SKNP", [Register(x)]) => Instruction::Sknp(*x),
+            ("PLANE", [Value(e)]) => Instruction::Plane(value(e, spans[0], 0xF)? as u8),
+            ("AUDIO", []) => Instruction::Audio,
+            _ if is_mnemonic(mnemonic) => {
+                return Err(error(
+                    file,
+                    item.span,
+                    format!("invalid operands for {}", mnemonic),
+                ))
+            }
+            _ => {
+                return Err(error(
+                    file,
+                    item.span,
+                    format!("unknown mnemonic '{}'", mnemonic),
+                ))
+            }
+        };
+
+        Ok(instruction)
+    }
+}
+
+fn is_mnemonic(word: &str) -> bool {
+    const MNEMONICS: [&str; 31] = [
+        "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB",
+        "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCR", "SCL", "EXIT", "LOW",
+        "HIGH", "SCU", "SAVE", "LOAD", "PLANE", "AUDIO",
+    ];
+    MNEMONICS.contains(&word)
+}
+
+fn is_reserved(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    parse_register(name).is_some()
+        || matches!(
+            upper.as_str(),
+            "I" | "DT"
+                | "ST"
+                | "K"
+                | "F"
+                | "HF"
+                | "B"
+                | "R"
+                | "PITCH"
+                | "LONG"
+                | "DB"
+                | "DW"
+                | "ORG"
+                | "INCLUDE"
+                | "EQU"
+        )
+        || is_mnemonic(&upper)
+}
+
+fn check_range(value: i64, max: i64, span: Span, file: &Option<Rc<Path>>) -> Result<i64, AsmError> {
+    // Negative values are accepted as two's complement as long as they fit
+    let min = -(max + 1) / 2;
+    if value < min || value > max {
+        return Err(error(
+            file,
+            span,
+            format!("value {} is out of range (0x0..=0x{:X})", value, max),
+        ));
+    }
+    Ok(value & max)
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V' | 'v'), Some(digit), None) => digit.to_digit(16).map(|d| d as Register),
+        _ => None,
+    }
+}
+
+fn join_spans(tokens: &[Token]) -> Span {
+    let first = tokens[0].span;
+    let last = tokens[tokens.len() - 1].span;
+    Span {
+        line: first.line,
+        column: first.column,
+        len: last.column + last.len - first.column,
+    }
+}
+
+fn expr_span(expr: &Expr) -> Span {
+    match expr {
+        Expr::Number(_, span) | Expr::Symbol(_, span) => *span,
+        Expr::Unary(_, inner) => expr_span(inner),
+        Expr::Binary(_, lhs, _) => expr_span(lhs),
+    }
+}
+
+fn split_operands<'a>(
+    tokens: &'a [Token],
+    span: Span,
+    file: &Option<Rc<Path>>,
+) -> Result<Vec<&'a [Token]>, AsmError> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut groups = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Punct("(") => depth += 1,
+            TokenKind::Punct(")") => depth -= 1,
+            TokenKind::Punct(",") if depth == 0 => {
+                if start == i {
+                    return Err(error(file, token.span, "expected an operand"));
+                }
+                groups.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start == tokens.len() {
+        let span = tokens.last().map_or(span, |t| t.span);
+        return Err(error(file, span, "expected an operand"));
+    }
+    groups.push(&tokens[start..]);
+
+    Ok(groups)
+}
+
+fn parse_operand(tokens: &[Token], file: &Option<Rc<Path>>) -> Result<Operand, AsmError> {
+    if let [Token {
+        kind: TokenKind::Ident(name),
+        ..
+    }] = tokens
+    {
+        if let Some(register) = parse_register(name) {
+            return Ok(Operand::Register(register));
+        }
+        match name.to_ascii_uppercase().as_str() {
+            "I" => return Ok(Operand::I),
+            "DT" => return Ok(Operand::Delay),
+            "ST" => return Ok(Operand::Sound),
+            "K" => return Ok(Operand::Key),
+            "F" => return Ok(Operand::Font),
+            "HF" => return Ok(Operand::HiFont),
+            "B" => return Ok(Operand::Bcd),
+            "R" => return Ok(Operand::Rpl),
+            "PITCH" => return Ok(Operand::Pitch),
+            _ => {}
+        }
+    }
+
+    if let [Token {
+        kind: TokenKind::Ident(name),
+        ..
+    }, rest @ ..] = tokens
+    {
+        if name.eq_ignore_ascii_case("LONG") && !rest.is_empty() {
+            return parse_full_expr(rest, join_spans(rest), file).map(Operand::Long);
+        }
+    }
+
+    if let [Token {
+        kind: TokenKind::Punct("["),
+        ..
+    }, Token {
+        kind: TokenKind::Ident(name),
+        ..
+    }, Token {
+        kind: TokenKind::Punct("]"),
+        ..
+    }] = tokens
+    {
+        if name.eq_ignore_ascii_case("I") {
+            return Ok(Operand::IndirectI);
+        }
+    }
+
+    parse_full_expr(tokens, join_spans(tokens), file).map(Operand::Value)
+}
+
+fn parse_full_expr(
+    tokens: &[Token],
+    span: Span,
+    file: &Option<Rc<Path>>,
+) -> Result<Expr, AsmError> {
+    if tokens.is_empty() {
+        return Err(error(file, span, "expected an expression"));
+    }
+
+    let mut pos = 0;
+    let expr = parse_expr(tokens, &mut pos, 0, file)?;
+    if let Some(token) = tokens.get(pos) {
+        return Err(error(file, token.span, "unexpected token in expression"));
+    }
+    Ok(expr)
+}
+
+fn binding_power(op: &str) -> Option<u8> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+fn parse_expr(
+    tokens: &[Token],
+    pos: &mut usize,
+    min_power: u8,
+    file: &Option<Rc<Path>>,
+) -> Result<Expr, AsmError> {
+    let Some(token) = tokens.get(*pos) else {
+        let span = tokens[tokens.len() - 1].span;
+        return Err(error(file, span, "unexpected end of expression"));
+    };
+    *pos += 1;
+
+    let mut lhs = match &token.kind {
+        TokenKind::Number(n) => Expr::Number(*n, token.span),
+        TokenKind::Ident(name) => Expr::Symbol(name.clone(), token.span),
+        TokenKind::Punct(op @ ("-" | "~")) => {
+            Expr::Unary(op, Box::new(parse_expr(tokens, pos, u8::MAX, file)?))
+        }
+        TokenKind::Punct("(") => {
+            let inner = parse_expr(tokens, pos, 0, file)?;
+            match tokens.get(*pos) {
+                Some(Token {
+                    kind: TokenKind::Punct(")"),
+                    ..
+                }) => *pos += 1,
+                _ => return Err(error(file, token.span, "unclosed parenthesis")),
+            }
+            inner
+        }
+        _ => return Err(error(file, token.span, "expected an expression")),
+    };
+
+    while let Some(Token {
+        kind: TokenKind::Punct(op),
+        ..
+    }) = tokens.get(*pos)
+    {
+        let Some(power) = binding_power(op) else {
+            break;
+        };
+        if power <= min_power {
+            break;
+        }
+        *pos += 1;
+        let rhs = parse_expr(tokens, pos, power, file)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+
+    Ok(lhs)
+}
+
+fn lex_line(text: &str, line: usize) -> Result<Vec<Token>, (Span, String)> {
+    const PUNCTUATION: [&str; 18] = [
+        "<<", ">>", ",", ":", "[", "]", "(", ")", "+", "-", "*", "/", "%", "&", "|", "^", "~", "=",
+    ];
+
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let span = |end: usize| Span {
+            line,
+            column: start + 1,
+            len: end - start,
+        };
+
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let kind = if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || matches!(chars[i], '_' | '.'))
+            {
+                i += 1;
+            }
+            TokenKind::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit()
+            || (c == '$' && chars.get(i + 1).is_some_and(|c| c.is_ascii_hexdigit()))
+        {
+            let (radix, skip) = match (c, chars.get(i + 1)) {
+                ('0', Some('x' | 'X')) => (16, 2),
+                ('0', Some('b' | 'B')) => (2, 2),
+                ('$', _) => (16, 1),
+                _ => (10, 0),
+            };
+            i += skip;
+            let digits_start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let digits = chars[digits_start..i]
+                .iter()
+                .filter(|c| **c != '_')
+                .collect::<String>();
+            let value = i64::from_str_radix(&digits, radix).map_err(|_| {
+                (
+                    span(i),
+                    format!(
+                        "invalid number '{}'",
+                        chars[start..i].iter().collect::<String>()
+                    ),
+                )
+            })?;
+            TokenKind::Number(value)
+        } else if c == '\'' {
+            match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some(value), Some('\'')) if value.is_ascii() => {
+                    i += 3;
+                    TokenKind::Number(*value as i64)
+                }
+                _ => return Err((span(i + 1), "invalid character literal".to_owned())),
+            }
+        } else if c == '"' {
+            i += 1;
+            let mut bytes = Vec::new();
+            loop {
+                match chars.get(i) {
+                    None => return Err((span(i), "unterminated string".to_owned())),
+                    Some('"') => break,
+                    Some('\\') => {
+                        let escaped = match chars.get(i + 1) {
+                            Some('n') => '\n',
+                            Some('0') => '\0',
+                            Some(c @ ('\\' | '"')) => *c,
+                            _ => return Err((span(i + 2), "invalid escape sequence".to_owned())),
+                        };
+                        bytes.push(escaped as u8);
+                        i += 2;
+                    }
+                    Some(c) => {
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            TokenKind::Str(bytes)
+        } else if let Some(punct) = PUNCTUATION.iter().find(|p| {
+            p.chars()
+                .enumerate()
+                .all(|(j, pc)| chars.get(i + j) == Some(&pc))
+        }) {
+            i += punct.len();
+            TokenKind::Punct(punct)
+        } else {
+            return Err((span(i + 1), format!("unexpected character '{}'", c)));
+        };
+
+        tokens.push(Token {
+            kind,
+            span: span(i),
+        });
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; draw a sprite forever
+            SPEED = 4
+            HEIGHT EQU sprite_end - sprite
+
+            start:  CLS
+                    LD V0, SPEED * 2
+                    LD I, sprite
+                    DRW V0, V1, HEIGHT
+                    ld vf, [i]
+                    JP start
+
+            sprite: DB 0b11110000, $90, 'A'
+            sprite_end:
+                    DW 0x1234, -1
+        ";
+
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                0x00, 0xE0, // CLS
+                0x60, 0x08, // LD V0, 8
+                0xA2, 0x0C, // LD I, 0x20C
+                0xD0, 0x13, // DRW V0, V1, 3
+                0xFF, 0x65, // LD VF, [I]
+                0x12, 0x00, // JP 0x200
+                0xF0, 0x90, 0x41, // DB
+                0x12, 0x34, 0xFF, 0xFF, // DW
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "DB 1 + 2 * 3, (1 + 2) * 3, 1 << 4 | 1, ~0 & 0xF0, 10 % 4, -(1 - 3)";
+        assert_eq!(assemble(source), Ok(vec![7, 9, 17, 0xF0, 2, 2]));
+    }
+
+    #[test]
+    fn test_org_and_strings() {
+        let source = "
+            JP main
+            ORG 0x208
+            main: DB \"HI\\n\"
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![0x12, 0x08, 0, 0, 0, 0, 0, 0, b'H', b'I', b'\n'])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = assemble("LD V0, 0x100").unwrap_err();
+        assert_eq!(
+            err.span,
+            Span {
+                line: 1,
+                column: 8,
+                len: 5
+            }
+        );
+        assert!(err.message.contains("out of range"));
+
+        let err = assemble("CLS\n  JP nowhere").unwrap_err();
+        assert_eq!(
+            err.span,
+            Span {
+                line: 2,
+                column: 6,
+                len: 7
+            }
+        );
+        assert_eq!(err.message, "undefined symbol 'nowhere'");
+
+        let err = assemble("  FOO V0").unwrap_err();
+        assert_eq!(err.span.column, 3);
+        assert_eq!(err.message, "unknown mnemonic 'FOO'");
+
+        let err = assemble("ADD DT, V0").unwrap_err();
+        assert_eq!(err.message, "invalid operands for ADD");
+
+        let err = assemble("a: CLS\na: CLS").unwrap_err();
+        assert_eq!(err.span.line, 2);
+
+        let err = assemble("B = 1").unwrap_err();
+        assert_eq!(err.message, "'B' is a reserved name");
+
+        let err = assemble("DB 1 / (2 - 2)").unwrap_err();
+        assert_eq!(err.message, "division by zero");
+
+        let err = assemble("X = Y\nY = X\nDB X").unwrap_err();
+        assert!(err.message.contains("itself"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), "include \"lib.asm\"\nJP routine").unwrap();
+        std::fs::write(dir.join("lib.asm"), "routine: RET").unwrap();
+
+        let rom = assemble_file(&dir.join("main.asm"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom, Ok(vec![0x00, 0xEE, 0x12, 0x00]));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for opcode in 0x0000..=0xFFFF {
+            if let Ok(instruction) = Instruction::decode(opcode) {
+                let source = instruction.to_string();
+                let rom = assemble(&source).unwrap();
+                assert_eq!(rom, opcode.to_be_bytes(), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_long() {
+        let rom = assemble("LD I, LONG data\nJP 0x200\nORG 0x1000\ndata: DB 1");
+        assert_eq!(
+            rom.as_ref().map(|rom| &rom[..6]),
+            Ok(&[0xF0, 0x00, 0x10, 0x00, 0x12, 0x00][..])
+        );
+        assert_eq!(rom.map(|rom| rom.len()), Ok(0xE01));
+    }
+
+    #[test]
+    fn test_round_trip_listing() {
+        let rom = [
+            0x22, 0x06, 0x12, 0x00, 0xF0, 0x0F, 0x6A, 0x02, 0xDA, 0xB5, 0x00, 0xEE, 0xAB,
+        ];
+        let source = crate::disasm::disassemble(&rom, crate::platform::Platform::Chip8)
+            .iter()
+            .map(|line| line.to_string().split("  ").last().unwrap().to_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        assert_eq!(assemble(&source), Ok(rom.to_vec()));
+    }
+}
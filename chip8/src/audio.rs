@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+
+use tracing::error;
+
+use crate::cpu::Cpu;
+use crate::platform::Platform;
+use crate::scheduler::FRAME_RATE;
+
+/// Sample rate used by the sinks in this crate
+pub const SAMPLE_RATE: u32 = 44_100;
+/// Pitch of the buzzer on platforms without XO-CHIP audio patterns, in Hz
+pub const BUZZER_FREQUENCY: f32 = 440.0;
+
+const AMPLITUDE: f32 = 0.25;
+const PATTERN_BITS: f32 = 128.0;
+
+/// What the speaker should play during one 60 Hz frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioFrame {
+    /// Whether the sound timer is running
+    pub active: bool,
+    /// The XO-CHIP 1-bit pattern, `None` plays a plain square wave buzzer
+    pub pattern: Option<[u8; 16]>,
+    /// The rate at which bits of the pattern are played, in Hz
+    pub playback_rate: f32,
+}
+
+impl AudioFrame {
+    pub fn of(cpu: &Cpu) -> AudioFrame {
+        let pattern = *cpu.audio_pattern();
+        let xo_chip = cpu.platform() >= Platform::XoChip && pattern.iter().any(|&b| b != 0);
+        AudioFrame {
+            active: cpu.sound_timer() > 0,
+            pattern: xo_chip.then_some(pattern),
+            playback_rate: cpu.playback_rate(),
+        }
+    }
+}
+
+/// Receives the state of the speaker once per frame, see
+/// [`crate::system::SystemBuilder::audio_sink`]
+pub trait AudioSink {
+    /// Called at the end of every frame, before the timers tick
+    fn frame(&mut self, frame: &AudioFrame);
+
+    /// Flushes any buffered output, called once the sink is no longer needed
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turns [`AudioFrame`]s into mono `f32` samples, keeping the waveform continuous across frames
+#[derive(Clone, Debug)]
+pub struct Synth {
+    sample_rate: u32,
+    /// Position in the current period, in cycles for the buzzer and in bits for patterns
+    phase: f32,
+    /// Fractional samples carried over when the sample rate isn't a multiple of the frame rate
+    remainder: u32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Synth {
+        Synth {
+            sample_rate,
+            phase: 0.0,
+            remainder: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Appends one frame worth of samples to `out`
+    pub fn render(&mut self, frame: &AudioFrame, out: &mut Vec<f32>) {
+        let total = self.sample_rate + self.remainder;
+        let count = total / FRAME_RATE;
+        self.remainder = total % FRAME_RATE;
+
+        if !frame.active {
+            self.phase = 0.0;
+            out.extend(std::iter::repeat_n(0.0, count as usize));
+            return;
+        }
+
+        let rate = self.sample_rate as f32;
+        for _ in 0..count {
+            let high = match &frame.pattern {
+                Some(pattern) => {
+                    let bit = self.phase as usize;
+                    self.phase = (self.phase + frame.playback_rate / rate) % PATTERN_BITS;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                None => {
+                    let high = self.phase < 0.5;
+                    self.phase = (self.phase + BUZZER_FREQUENCY / rate) % 1.0;
+                    high
+                }
+            };
+            out.push(if high { AMPLITUDE } else { -AMPLITUDE });
+        }
+    }
+}
+
+/// Writes the audio to a 16-bit mono WAV file, e.g. for headless runs
+pub struct WavSink<W: Write + Seek> {
+    writer: Option<hound::WavWriter<W>>,
+    synth: Synth,
+    samples: Vec<f32>,
+    /// The first write error, reported by [`AudioSink::finish`]
+    error: Option<hound::Error>,
+}
+
+impl WavSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        WavSink::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(writer: W) -> io::Result<Self> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = hound::WavWriter::new(writer, spec).map_err(to_io_error)?;
+        Ok(WavSink {
+            writer: Some(writer),
+            synth: Synth::new(SAMPLE_RATE),
+            samples: Vec::new(),
+            error: None,
+        })
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn frame(&mut self, frame: &AudioFrame) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        self.samples.clear();
+        self.synth.render(frame, &mut self.samples);
+        for &sample in &self.samples {
+            if let Err(err) = writer.write_sample((sample * i16::MAX as f32) as i16) {
+                error!("Could not write audio, recording stopped: {}", err);
+                self.error = Some(err);
+                self.writer = None;
+                return;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(to_io_error(err));
+        }
+        match self.writer.take() {
+            Some(writer) => writer.finalize().map_err(to_io_error),
+            None => Ok(()),
+        }
+    }
+}
+
+fn to_io_error(err: hound::Error) -> io::Error {
+    match err {
+        hound::Error::IoError(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Cursor, SeekFrom};
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::rom::RomImage;
+    use crate::system::SystemBuilder;
+
+    const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FRAME_RATE) as usize;
+
+    /// A WAV file that can still be read after the sink was handed to a system
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Shared {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+
+    fn read_samples(wav: &Shared) -> Vec<i16> {
+        let bytes = wav.0.borrow().get_ref().clone();
+        let mut reader = hound::WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+        assert_eq!(reader.spec().channels, 1);
+        reader.samples::<i16>().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_synth_buzzer() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        let mut samples = Vec::new();
+        let frame = AudioFrame {
+            active: true,
+            pattern: None,
+            playback_rate: 4000.0,
+        };
+        synth.render(&frame, &mut samples);
+        assert_eq!(samples.len(), SAMPLES_PER_FRAME);
+
+        // A 440 Hz square wave flips roughly every 50 samples
+        let flips = samples.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(flips, 14);
+
+        samples.clear();
+        synth.render(
+            &AudioFrame {
+                active: false,
+                ..frame
+            },
+            &mut samples,
+        );
+        assert!(samples.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_synth_pattern() {
+        let mut synth = Synth::new(8000);
+        let mut samples = Vec::new();
+        // Alternating bytes of ones and zeroes, played at half the sample rate
+        let mut pattern = [0x00; 16];
+        pattern[0] = 0xFF;
+        let frame = AudioFrame {
+            active: true,
+            pattern: Some(pattern),
+            playback_rate: 4000.0,
+        };
+        for _ in 0..3 {
+            synth.render(&frame, &mut samples);
+        }
+
+        assert!(samples[..16].iter().all(|&s| s > 0.0));
+        assert!(samples[16..256].iter().all(|&s| s < 0.0));
+        assert!(samples[256..272].iter().all(|&s| s > 0.0));
+    }
+
+    #[test]
+    fn test_synth_remainder() {
+        let mut synth = Synth::new(1000);
+        let mut samples = Vec::new();
+        let frame = AudioFrame {
+            active: false,
+            pattern: None,
+            playback_rate: 4000.0,
+        };
+        for _ in 0..FRAME_RATE {
+            synth.render(&frame, &mut samples);
+        }
+        assert_eq!(samples.len(), 1000);
+    }
+
+    #[test]
+    fn test_wav_sink() {
+        let wav = Shared::default();
+        // LD V0, 0x02; LD ST, V0; JP 0x204
+        let rom = [0x60, 0x02, 0xF0, 0x18, 0x12, 0x04];
+        let mut system = SystemBuilder::new(&RomImage::new(rom, Platform::Chip8).unwrap())
+            .audio_sink(WavSink::new(wav.clone()).unwrap())
+            .run();
+        for _ in 0..4 {
+            system.run_frame();
+        }
+        system.finish_audio().unwrap();
+
+        let samples = read_samples(&wav);
+        assert_eq!(samples.len(), 4 * SAMPLES_PER_FRAME);
+
+        // The buzzer sounds for the two frames the sound timer runs, then goes quiet
+        let (sound, silence) = samples.split_at(2 * SAMPLES_PER_FRAME);
+        assert!(sound.iter().all(|&s| s.abs() > 8000));
+        assert!(silence.iter().all(|&s| s == 0));
+    }
+}
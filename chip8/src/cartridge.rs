@@ -0,0 +1,255 @@
+//! Octo cartridges: GIF images with an Octo program and its settings hidden in them.
+//!
+//! The two low bits of every palette index, read four pixels to a byte with the most
+//! significant bits first and continuing through every frame, hold a big-endian 32 bit length
+//! followed by that many bytes of JSON: `{"program": "<Octo source>", "options": {...}}`.
+
+use serde::Deserialize;
+
+use crate::database::{parse_color, Colors, RomInfo};
+use crate::error::CartridgeError;
+use crate::gif;
+use crate::octo;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::rom::RomImage;
+
+/// Title of cartridges, which don't name their program
+const TITLE: &str = "Octo cartridge";
+
+/// The settings Octo saves with a program. Octo's own defaults fill in anything missing.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Options {
+    tickrate: u32,
+    fill_color: String,
+    fill_color2: String,
+    blend_color: String,
+    background_color: String,
+    buzz_color: String,
+    quiet_color: String,
+    shift_quirks: bool,
+    load_store_quirks: bool,
+    clip_quirks: bool,
+    v_blank_quirks: bool,
+    jump_quirks: bool,
+    logic_quirks: bool,
+    screen_rotation: u16,
+    max_size: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            tickrate: 20,
+            fill_color: "#FFCC00".into(),
+            fill_color2: "#FF6600".into(),
+            blend_color: "#662200".into(),
+            background_color: "#996600".into(),
+            buzz_color: "#FFAA00".into(),
+            quiet_color: "#000000".into(),
+            shift_quirks: false,
+            load_store_quirks: false,
+            clip_quirks: false,
+            v_blank_quirks: false,
+            jump_quirks: false,
+            logic_quirks: false,
+            screen_rotation: 0,
+            max_size: 3584,
+        }
+    }
+}
+
+impl Options {
+    fn rom_info(&self) -> RomInfo {
+        // Octo always runs XO-CHIP, smaller memory sizes are for staying compatible with
+        // other interpreters
+        let platform = match self.max_size {
+            3216 => Platform::Chip8,
+            3583 => Platform::SuperChip,
+            _ => Platform::XoChip,
+        };
+        let colors = [
+            &self.background_color,
+            &self.fill_color,
+            &self.fill_color2,
+            &self.blend_color,
+        ];
+        RomInfo {
+            title: TITLE.into(),
+            platform,
+            quirks: Quirks {
+                shift_uses_vy: !self.shift_quirks,
+                load_store_increments_i: !self.load_store_quirks,
+                jump_uses_vx: self.jump_quirks,
+                vf_reset: self.logic_quirks,
+                clip_sprites: self.clip_quirks,
+                display_wait: self.v_blank_quirks,
+            },
+            instructions_per_frame: Some(self.tickrate),
+            colors: Some(Colors {
+                pixels: colors.iter().filter_map(|c| parse_color(c)).collect(),
+                buzzer: parse_color(&self.buzz_color),
+                silence: parse_color(&self.quiet_color),
+            }),
+            keys: Default::default(),
+            screen_rotation: self.screen_rotation,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: Options,
+}
+
+/// A decoded cartridge, with its program compiled
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    /// The Octo source of the program
+    pub source: String,
+    pub rom: RomImage,
+    /// The settings the program was saved with, for
+    /// [`crate::system::SystemBuilder::rom_info`]
+    pub info: RomInfo,
+}
+
+impl Cartridge {
+    /// Whether `bytes` could be a cartridge, i.e. is a GIF image
+    pub fn is_gif(bytes: &[u8]) -> bool {
+        bytes.starts_with(b"GIF8")
+    }
+
+    /// Extracts the program of a cartridge and compiles it for the platform its options ask
+    /// for
+    pub fn decode(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let pixels = gif::decode(bytes).map_err(CartridgeError::InvalidGif)?;
+        let mut data = pixels
+            .chunks_exact(4)
+            .map(|bits| bits.iter().fold(0, |byte, pixel| byte << 2 | (pixel & 3)));
+
+        let length = data
+            .by_ref()
+            .take(4)
+            .fold(0usize, |length, byte| length << 8 | byte as usize);
+        let json = data.take(length).collect::<Vec<u8>>();
+        if json.len() < length || length == 0 {
+            return Err(CartridgeError::NoProgram("the image is too small".into()));
+        }
+        let payload: Payload = serde_json::from_slice(&json)
+            .map_err(|err| CartridgeError::NoProgram(err.to_string()))?;
+
+        let bytes = octo::compile(&payload.program).map_err(CartridgeError::Compile)?;
+        let info = payload.options.rom_info();
+        let rom = RomImage::new(bytes, info.platform).map_err(CartridgeError::Rom)?;
+        Ok(Cartridge {
+            source: payload.program,
+            rom,
+            info,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::SystemBuilder;
+
+    const BOUNCE: &[u8] = include_bytes!("../../res/cartridges/bounce.gif");
+    const BEEP: &[u8] = include_bytes!("../../res/cartridges/beep.gif");
+
+    #[test]
+    fn test_decode() {
+        let cartridge = Cartridge::decode(BOUNCE).unwrap();
+        assert!(cartridge.source.starts_with("# Bounces a ball"));
+        assert_eq!(
+            cartridge.rom.bytes(),
+            octo::compile(&cartridge.source).unwrap()
+        );
+        // The ball comes before main, so the ROM starts by jumping over it
+        assert_eq!(&cartridge.rom.bytes()[..2], [0x12, 0x06]);
+
+        let info = cartridge.info;
+        assert_eq!(info.platform, Platform::XoChip);
+        assert_eq!(info.instructions_per_frame, Some(7));
+        assert_eq!(
+            info.quirks,
+            Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: false,
+                jump_uses_vx: false,
+                vf_reset: false,
+                clip_sprites: true,
+                display_wait: true,
+            }
+        );
+        assert_eq!(
+            info.colors.unwrap().pixels,
+            [
+                [0x99, 0x66, 0x00],
+                [0xFF, 0xCC, 0x00],
+                [0xFF, 0x66, 0x00],
+                [0x66, 0x22, 0x00]
+            ]
+        );
+        assert_eq!(info.screen_rotation, 0);
+    }
+
+    #[test]
+    fn test_decode_frames() {
+        // Spread over several animation frames
+        let cartridge = Cartridge::decode(BEEP).unwrap();
+        assert_eq!(cartridge.info.screen_rotation, 90);
+        assert_eq!(cartridge.info.instructions_per_frame, Some(500));
+        assert_eq!(
+            cartridge.info.quirks,
+            Quirks {
+                shift_uses_vy: false,
+                load_store_increments_i: true,
+                jump_uses_vx: true,
+                vf_reset: true,
+                clip_sprites: false,
+                display_wait: false,
+            }
+        );
+
+        let mut system = SystemBuilder::new(&cartridge.rom)
+            .rom_info(&cartridge.info)
+            .run();
+        system.run_frame();
+        assert_eq!(system.fault(), None);
+        assert_eq!(system.cpu().playback_rate(), 4000.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            Cartridge::decode(b"GIF89a"),
+            Err(CartridgeError::InvalidGif(_))
+        ));
+
+        // A 3x2 image, too small to hold even the length
+        #[rustfmt::skip]
+        let tiny = [
+            b'G', b'I', b'F', b'8', b'9', b'a', 3, 0, 2, 0, 0x81, 0, 0,
+            0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 255, 0,
+            0x2C, 0, 0, 0, 0, 3, 0, 2, 0, 0,
+            2, 3, 0x44, 0x34, 0x56, 0,
+            0x3B,
+        ];
+        assert!(matches!(
+            Cartridge::decode(&tiny),
+            Err(CartridgeError::NoProgram(_))
+        ));
+
+        // The same image claiming to be 65535x65535 is refused before anything is allocated
+        let mut huge = tiny;
+        huge[30..34].copy_from_slice(&[0xFF; 4]);
+        assert!(matches!(
+            Cartridge::decode(&huge),
+            Err(CartridgeError::InvalidGif(_))
+        ));
+    }
+}
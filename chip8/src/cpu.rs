@@ -1,30 +1,59 @@
 use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg64Mcg;
+use serde::{Deserialize, Serialize};
 
-use crate::data::OpCode;
-use crate::display::{Display, Pixel, DISPLAY_SIZE};
+use crate::data::{OpCode, Register};
+use crate::display::{Display, Pixel};
+use crate::error::{CpuError, StateError};
+use crate::instruction::Instruction;
 use crate::keyboard::Keyboard;
-use crate::memory::PROGRAM_START;
+use crate::memory::{MemoryAccess, PROGRAM_START};
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::state::{self, RomHash};
 use crate::timer::Timer;
 use crate::{data::Address, memory::MemoryBus, registers::Registers};
 
-const STACK_SIZE: usize = 16;
+/// The deepest stack any platform has, see [`Platform::stack_depth`]
+pub const MAX_STACK_DEPTH: usize = 16;
 pub const FREQUENCY: u32 = 500; // 500 Hz
 const FONT_START: Address = 0x050; // Arbitrary, but it's convention to start at 0x50
+const HIRES_FONT_START: Address = 0x0A0; // Right after the regular font
+const FLAG_COUNT: usize = 16; // The HP 48 only has 8 RPL flags, allow all 16 registers anyway
+const AUDIO_PATTERN_SIZE: usize = 16;
+const DEFAULT_PITCH: u8 = 64; // 4000 Hz playback rate
 
-pub type Stack = [Address; STACK_SIZE];
+pub type Stack = [Address; MAX_STACK_DEPTH];
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Interrupt {
     #[default]
     None,
     KeyPress(u8),
+    VBlank,
+    Exit,
 }
 
+/// The result of a successful call to [`Cpu::tick`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepOutcome {
+    Executed,
+    WaitingForKey,
+    WaitingForVBlank,
+    /// The program ran `00FD`, the CPU won't execute anything else
+    Exited,
+}
+
+/// The complete machine state, everything in it is captured by [`Cpu::save_state`]
+#[derive(Serialize, Deserialize)]
 pub struct Cpu {
     pub registers: Registers,
+    pub quirks: Quirks,
+    platform: Platform,
     memory: MemoryBus,
     stack: Stack,
+    /// The number of entries of `stack` the platform can use
+    stack_depth: usize,
     keyboard: Keyboard,
     display: Display,
     pub drawing: bool,
@@ -32,14 +61,21 @@ pub struct Cpu {
     interrupt: Interrupt,
     delay: Timer,
     sound: Timer,
+    flags: [u8; FLAG_COUNT],
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    rom_hash: RomHash,
 }
 
 impl Default for Cpu {
     fn default() -> Cpu {
         Cpu {
             registers: Registers::default(),
+            quirks: Quirks::default(),
+            platform: Platform::default(),
             memory: MemoryBus::default(),
-            stack: [0; STACK_SIZE],
+            stack: [0; MAX_STACK_DEPTH],
+            stack_depth: Platform::default().stack_depth(),
             keyboard: Keyboard::default(),
             display: Display::default(),
             drawing: false,
@@ -47,6 +83,10 @@ impl Default for Cpu {
             interrupt: Interrupt::None,
             delay: Timer::new(),
             sound: Timer::new(),
+            flags: [0; FLAG_COUNT],
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+            rom_hash: state::rom_hash(&[]),
         }
     }
 }
@@ -55,28 +95,157 @@ impl Cpu {
     pub fn init(rom: &[u8]) -> Cpu {
         let mut cpu = Cpu::default();
 
-        // Load font into memory
+        // Load fonts into memory
         let font_rom = include_bytes!("../../res/font.bin");
         cpu.memory.write_bytes(FONT_START, font_rom);
+        let hires_font_rom = include_bytes!("../../res/font_hires.bin");
+        cpu.memory.write_bytes(HIRES_FONT_START, hires_font_rom);
 
         // Load ROM into memory
         cpu.memory.write_bytes(PROGRAM_START, rom);
         cpu.registers.pc = PROGRAM_START;
+        cpu.rom_hash = state::rom_hash(rom);
 
         cpu
     }
 
-    pub fn pixels(&mut self) -> &[Pixel; DISPLAY_SIZE] {
+    /// Serializes the entire machine into a versioned save state tagged with the ROM's hash
+    pub fn save_state(&self) -> Vec<u8> {
+        state::encode(self.rom_hash, self)
+    }
+
+    /// Restores a state from [`Cpu::save_state`], refusing states taken with a different ROM
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        *self = state::decode(bytes, self.rom_hash)?;
+        Ok(())
+    }
+
+    /// SHA-1 of the ROM the CPU was initialized with
+    pub fn rom_hash(&self) -> RomHash {
+        self.rom_hash
+    }
+
+    /// Returns whether the program stopped itself with `00FD`
+    pub fn exited(&self) -> bool {
+        self.interrupt == Interrupt::Exit
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    /// Switches the instruction set, resizing the addressable memory to match
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.memory.set_size(platform.memory_size());
+        self.stack_depth = platform.stack_depth();
+    }
+
+    pub fn pixels(&mut self) -> Vec<Pixel> {
         self.drawing = false;
-        &self.display.pixels
+        self.display.pixels()
+    }
+
+    /// The colour index of every pixel, one bit per XO-CHIP bitplane
+    pub fn colors(&mut self) -> Vec<u8> {
+        self.drawing = false;
+        self.display.colors()
+    }
+
+    /// The XO-CHIP 1-bit audio pattern played while the sound timer is active
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    /// The rate at which bits of the audio pattern are played, in Hz
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// The current width and height of the display in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.display.width(), self.display.height())
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay.get()
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound.get()
+    }
+
+    /// The return addresses of the subroutines currently being executed, outermost first
+    pub fn stack(&self) -> &[Address] {
+        &self.stack[..self.registers.sp]
+    }
+
+    /// The number of subroutine calls that can be nested before `CALL` faults
+    pub fn stack_depth(&self) -> usize {
+        self.stack_depth
+    }
+
+    /// Overrides the stack depth of the platform, up to [`MAX_STACK_DEPTH`]
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack_depth = depth.min(MAX_STACK_DEPTH);
+    }
+
+    /// Returns whether the CPU is blocked on `FX0A` until a key is pressed
+    pub fn waiting_for_key(&self) -> bool {
+        matches!(self.interrupt, Interrupt::KeyPress(_))
     }
 
-    pub fn tick(&mut self) {
+    /// Reads up to `len` bytes of addressable memory, without tracing the reads
+    pub fn read_memory(&self, addr: Address, len: usize) -> Vec<u8> {
+        (addr as usize..(addr as usize).saturating_add(len))
+            .take_while(|&target| self.memory.in_bounds(target, 1))
+            .map(|target| self.memory.peek(target as Address))
+            .collect()
+    }
+
+    /// Writes `data` at `addr`, returning `false` without writing anything if the range isn't
+    /// addressable
+    pub fn write_memory(&mut self, addr: Address, data: &[u8]) -> bool {
+        if !self.memory.in_bounds(addr as usize, data.len()) {
+            return false;
+        }
+        self.memory.write_bytes(addr, data);
+        true
+    }
+
+    /// Decodes the instruction at `addr`, including the operand of a long XO-CHIP instruction
+    pub fn instruction_at(&self, addr: Address) -> Option<Instruction> {
+        let bytes = self.read_memory(addr, 4);
+        let word = |i: usize| Some(u16::from_be_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]));
+
+        let opcode = word(0)?;
+        if opcode == Instruction::LONG_PREFIX && self.platform >= Platform::XoChip {
+            Instruction::decode_long(opcode, word(2)?).ok()
+        } else {
+            Instruction::decode_for(opcode, self.platform).ok()
+        }
+    }
+
+    /// Starts or stops recording the memory accesses made by instructions, see
+    /// [`Cpu::take_memory_accesses`]
+    pub fn set_memory_tracing(&mut self, enabled: bool) {
+        self.memory.set_tracing(enabled);
+    }
+
+    /// Returns and clears the memory accesses recorded since the last call
+    pub fn take_memory_accesses(&mut self) -> Vec<MemoryAccess> {
+        self.memory.take_accesses()
+    }
+
+    pub fn tick(&mut self) -> Result<StepOutcome, CpuError> {
         match self.interrupt {
-            Interrupt::KeyPress(_) => (),
+            Interrupt::KeyPress(_) => Ok(StepOutcome::WaitingForKey),
+            Interrupt::VBlank => Ok(StepOutcome::WaitingForVBlank),
+            Interrupt::Exit => Ok(StepOutcome::Exited),
             Interrupt::None => {
-                let instr = self.fetch();
-                self.execute(instr);
+                let instr = self.fetch()?;
+                self.execute(instr)?;
+                Ok(StepOutcome::Executed)
             }
         }
     }
@@ -84,6 +253,10 @@ impl Cpu {
     pub fn tick_timers(&mut self) {
         self.delay.tick();
         self.sound.tick();
+
+        if self.interrupt == Interrupt::VBlank {
+            self.interrupt = Interrupt::None;
+        }
     }
 
     pub fn key_down(&mut self, key: u8) {
@@ -99,222 +272,353 @@ impl Cpu {
         self.keyboard[key as usize] = false;
     }
 
-    fn fetch(&mut self) -> OpCode {
-        let left = self.memory.read(self.registers.pc);
-        let right = self.memory.read(self.registers.pc + 1);
+    /// The keys currently held as a bit mask, bit N is set while key N is down
+    pub fn keypad(&self) -> u16 {
+        self.keyboard
+            .iter()
+            .enumerate()
+            .filter(|(_, &down)| down)
+            .fold(0, |keys, (key, _)| keys | 1 << key)
+    }
+
+    /// Presses and releases keys through [`Cpu::key_down`] and [`Cpu::key_up`] until the keypad
+    /// matches `keys`, lowest key first
+    pub fn set_keypad(&mut self, keys: u16) {
+        for key in 0..self.keyboard.len() {
+            let down = keys & 1 << key != 0;
+            if down && !self.keyboard[key] {
+                self.key_down(key as u8);
+            } else if !down && self.keyboard[key] {
+                self.key_up(key as u8);
+            }
+        }
+    }
+
+    /// Restarts the random number generator behind `CXNN` from `seed`
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Pcg64Mcg::seed_from_u64(seed);
+    }
+
+    fn fetch(&mut self) -> Result<OpCode, CpuError> {
+        let pc = self.registers.pc;
+        if !self.memory.in_bounds(pc as usize, 2) {
+            return Err(CpuError::MemoryOutOfBounds {
+                addr: pc,
+                target: pc as usize + 1,
+            });
+        }
+
+        let left = self.memory.peek(pc);
+        let right = self.memory.peek(pc + 1);
         let instruction = (left as u16) << 8 | right as u16;
-        self.registers.pc += 2;
-        instruction
-    }
-
-    fn execute(&mut self, instr: OpCode) {
-        match (instr & 0xF000) >> 12 {
-            0x0 => match instr & 0x00FF {
-                0xE0 => self.display.clear(), // 00E0; CLS
-                0xEE => self.ret(),           // 00EE; RET
-                _ => self.sys_addr(instr),    // 0NNN; SYS addr
-            },
-            0x1 => self.jump_addr(instr & 0x0FFF), // 1NNN; JMP addr
-            0x2 => self.call_addr(instr & 0x0FFF), // 2NNN; CALL addr
-            0x3 => {
-                // 3XNN; SE Vx, byte
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                self.registers.pc += if self.registers.v[vx] == (instr & 0x00FF) as u8 {
-                    2
-                } else {
-                    0
+        self.registers.pc = pc.wrapping_add(2);
+        Ok(instruction)
+    }
+
+    /// The size of the instruction at the PC, which skips have to jump over as a whole
+    fn next_size(&self) -> Address {
+        let pc = self.registers.pc;
+        let long = self.platform >= Platform::XoChip
+            && self.memory.in_bounds(pc as usize, 2)
+            && [self.memory.peek(pc), self.memory.peek(pc + 1)]
+                == Instruction::LONG_PREFIX.to_be_bytes();
+        if long {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Checks that `len` bytes starting at `target` are addressable, returning the start address
+    fn check_bounds(&self, addr: Address, target: usize, len: usize) -> Result<Address, CpuError> {
+        if self.memory.in_bounds(target, len) {
+            Ok(target as Address)
+        } else {
+            Err(CpuError::MemoryOutOfBounds { addr, target })
+        }
+    }
+
+    fn execute(&mut self, instr: OpCode) -> Result<(), CpuError> {
+        // `execute` always runs right after `fetch`, so the instruction is just behind the PC
+        let addr = self.registers.pc.wrapping_sub(2);
+        let decoded = if instr == Instruction::LONG_PREFIX && self.platform >= Platform::XoChip {
+            let operand = self.fetch()?;
+            Instruction::decode_long(instr, operand)
+        } else {
+            Instruction::decode_for(instr, self.platform)
+        };
+        let instruction = decoded.map_err(|err| CpuError::InvalidOpcode {
+            addr,
+            opcode: err.opcode,
+        })?;
+        self.execute_instruction(addr, instruction)
+    }
+
+    fn execute_instruction(&mut self, addr: Address, instr: Instruction) -> Result<(), CpuError> {
+        let next_size = self.next_size();
+        let v = &mut self.registers.v;
+        let pc = &mut self.registers.pc;
+        let quirks = self.quirks;
+
+        match instr {
+            // 00E0; CLS
+            Instruction::Cls => self.display.clear(),
+            // 00EE; RET
+            Instruction::Ret => self.ret(addr)?,
+            Instruction::Scd(n) => {
+                // 00CN; SCD nibble
+                self.display.scroll_down(n as usize);
+                self.drawing = true;
+            }
+            Instruction::Scu(n) => {
+                // 00DN; SCU nibble
+                self.display.scroll_up(n as usize);
+                self.drawing = true;
+            }
+            Instruction::Scr => {
+                // 00FB; SCR
+                self.display.scroll_right();
+                self.drawing = true;
+            }
+            Instruction::Scl => {
+                // 00FC; SCL
+                self.display.scroll_left();
+                self.drawing = true;
+            }
+            Instruction::Exit => {
+                // 00FD; EXIT
+                self.interrupt = Interrupt::Exit;
+            }
+            Instruction::Low => {
+                // 00FE; LOW
+                self.display.set_hires(false);
+                self.drawing = true;
+            }
+            Instruction::High => {
+                // 00FF; HIGH
+                self.display.set_hires(true);
+                self.drawing = true;
+            }
+            // 0NNN; SYS addr
+            Instruction::Sys(nnn) => self.sys_addr(nnn),
+            // 1NNN; JMP addr
+            Instruction::Jp(nnn) => self.jump_addr(nnn),
+            // 2NNN; CALL addr
+            Instruction::Call(nnn) => self.call_addr(addr, nnn)?,
+            // 3XNN; SE Vx, byte
+            Instruction::Se(x, nn) => skip_if(pc, v[x as usize] == nn, next_size),
+            // 4XNN; SNE Vx, byte
+            Instruction::Sne(x, nn) => skip_if(pc, v[x as usize] != nn, next_size),
+            // 5XY0; SE Vx, Vy
+            Instruction::SeReg(x, y) => skip_if(pc, v[x as usize] == v[y as usize], next_size),
+            Instruction::SaveRange(x, y) => {
+                // 5XY2; SAVE Vx, Vy
+                let registers = register_range(x, y);
+                let start = self.check_bounds(addr, self.registers.i as usize, registers.len())?;
+                for (offset, r) in registers.into_iter().enumerate() {
+                    self.memory
+                        .write(start + offset as Address, self.registers.v[r]);
                 }
             }
-            0x4 => {
-                // 4XNN; SNE Vx, byte
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                self.registers.pc += if self.registers.v[vx] != (instr & 0x00FF) as u8 {
-                    2
-                } else {
-                    0
+            Instruction::LoadRange(x, y) => {
+                // 5XY3; LOAD Vx, Vy
+                let registers = register_range(x, y);
+                let start = self.check_bounds(addr, self.registers.i as usize, registers.len())?;
+                for (offset, r) in registers.into_iter().enumerate() {
+                    self.registers.v[r] = self.memory.read(start + offset as Address);
                 }
             }
-            0x5 => {
-                // 5XY0; SE Vx, Vy
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                let vy = ((instr as u16 & 0x00F0) >> 4) as usize;
-                self.registers.pc += if self.registers.v[vx] == self.registers.v[vy] {
-                    2
-                } else {
-                    0
+            // 6XNN; LD Vx, byte
+            Instruction::Ld(x, nn) => v[x as usize] = nn,
+            // 7XNN; ADD Vx, byte
+            Instruction::Add(x, nn) => v[x as usize] = v[x as usize].wrapping_add(nn),
+            // 8XY0; LD Vx, Vy
+            Instruction::LdReg(x, y) => v[x as usize] = v[y as usize],
+            Instruction::Or(x, y) => {
+                // 8XY1; OR Vx, Vy
+                v[x as usize] |= v[y as usize];
+                if quirks.vf_reset {
+                    v[0xF] = 0;
                 }
             }
-            0x6 => {
-                // 6XNN; LD Vx, byte
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                self.registers.v[vx] = (instr & 0x00FF) as u8;
-            }
-            0x7 => {
-                // 7XNN; ADD Vx, byte
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                self.registers.v[vx] = self.registers.v[vx].wrapping_add((instr & 0x00FF) as u8);
-            }
-            0x8 => {
-                let vy = ((instr as u16 & 0x00F0) >> 4) as usize;
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                match instr & 0x000F {
-                    0x0 => self.registers.v[vx] = self.registers.v[vy], // 8XY0; LD Vx, Vy
-                    0x1 => self.registers.v[vx] |= self.registers.v[vy], // 8XY1; OR Vx, Vy
-                    0x2 => self.registers.v[vx] &= self.registers.v[vy], // 8XY2; AND Vx, Vy
-                    0x3 => self.registers.v[vx] ^= self.registers.v[vy], // 8XY3; XOR Vx, Vy
-                    0x4 => {
-                        // 8XY4; ADD Vx, Vy
-                        let (result, overflow) =
-                            self.registers.v[vx].overflowing_add(self.registers.v[vy]);
-                        self.registers.v[vx] = result;
-                        self.registers.v[0xF] = if overflow { 1 } else { 0 };
-                    }
-                    0x5 => {
-                        // 8XY5; SUB Vx, Vy
-                        let (result, overflow) =
-                            self.registers.v[vx].overflowing_sub(self.registers.v[vy]);
-                        self.registers.v[vx] = result;
-                        self.registers.v[0xF] = if overflow { 0 } else { 1 };
-                    }
-                    0x6 => {
-                        // 8XY6; SHR Vx {, Vy}
-                        let carry = self.registers.v[vx] & 0b0000_0001;
-                        self.registers.v[vx] >>= 1;
-                        self.registers.v[0xF] = carry;
-                    }
-                    0x7 => {
-                        // 8XY7; SUBN Vx, Vy
-                        let (result, overflow) =
-                            self.registers.v[vy].overflowing_sub(self.registers.v[vx]);
-                        self.registers.v[vx] = result;
-                        self.registers.v[0xF] = if overflow { 0 } else { 1 };
-                    }
-                    0xE => {
-                        // 8XYE; SHL Vx {, Vy}
-                        let carry = (self.registers.v[vx] & 0b1000_0000) >> 7;
-                        self.registers.v[vx] <<= 1;
-                        self.registers.v[0xF] = carry;
-                    }
-                    _ => unreachable!("Instruction 0x{:04X} is not valid", instr),
+            Instruction::And(x, y) => {
+                // 8XY2; AND Vx, Vy
+                v[x as usize] &= v[y as usize];
+                if quirks.vf_reset {
+                    v[0xF] = 0;
                 }
             }
-            0x9 => {
-                // 9XY0; SNE Vx, Vy
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                let vy = ((instr as u16 & 0x00F0) >> 4) as usize;
-                self.registers.pc += if self.registers.v[vx] != self.registers.v[vy] {
-                    2
-                } else {
-                    0
+            Instruction::Xor(x, y) => {
+                // 8XY3; XOR Vx, Vy
+                v[x as usize] ^= v[y as usize];
+                if quirks.vf_reset {
+                    v[0xF] = 0;
                 }
             }
-            0xA => self.registers.i = instr & 0x0FFF, // ANNN; LD I, addr
-            0xB => self.jump_addr((instr & 0x0FFF) + self.registers.v[0] as u16), // BNNN; JP V0, addr
-            0xC => {
-                // CXNN; RND Vx, byte
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                self.registers.v[vx] = self.rng.gen::<u8>() & (instr & 0x00FF) as u8;
+            Instruction::AddReg(x, y) => {
+                // 8XY4; ADD Vx, Vy
+                let (result, overflow) = v[x as usize].overflowing_add(v[y as usize]);
+                v[x as usize] = result;
+                v[0xF] = if overflow { 1 } else { 0 };
+            }
+            Instruction::Sub(x, y) => {
+                // 8XY5; SUB Vx, Vy
+                let (result, overflow) = v[x as usize].overflowing_sub(v[y as usize]);
+                v[x as usize] = result;
+                v[0xF] = if overflow { 0 } else { 1 };
+            }
+            Instruction::Shr(x, y) => {
+                // 8XY6; SHR Vx {, Vy}
+                let value = v[if quirks.shift_uses_vy { y } else { x } as usize];
+                v[x as usize] = value >> 1;
+                v[0xF] = value & 0b0000_0001;
+            }
+            Instruction::Subn(x, y) => {
+                // 8XY7; SUBN Vx, Vy
+                let (result, overflow) = v[y as usize].overflowing_sub(v[x as usize]);
+                v[x as usize] = result;
+                v[0xF] = if overflow { 0 } else { 1 };
             }
-            0xD => {
+            Instruction::Shl(x, y) => {
+                // 8XYE; SHL Vx {, Vy}
+                let value = v[if quirks.shift_uses_vy { y } else { x } as usize];
+                v[x as usize] = value << 1;
+                v[0xF] = (value & 0b1000_0000) >> 7;
+            }
+            // 9XY0; SNE Vx, Vy
+            Instruction::SneReg(x, y) => skip_if(pc, v[x as usize] != v[y as usize], next_size),
+            // ANNN; LD I, addr
+            Instruction::LdI(nnn) => self.registers.i = nnn,
+            Instruction::JpV0(nnn) => {
+                // BNNN; JP V0, addr
+                let x = if quirks.jump_uses_vx { nnn >> 8 } else { 0 };
+                self.registers.pc = nnn + v[x as usize] as u16;
+            }
+            // CXNN; RND Vx, byte
+            Instruction::Rnd(x, nn) => v[x as usize] = self.rng.gen::<u8>() & nn,
+            Instruction::Drw { x, y, n } => {
                 // DXYN; DRW Vx, Vy, nibble
-                let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                let vy = ((instr as u16 & 0x00F0) >> 4) as usize;
-                let n = instr & 0x000F;
-
-                let sprite = self.memory.read_bytes(self.registers.i, n as usize);
-                let x = self.registers.v[vx] as usize;
-                let y = self.registers.v[vy] as usize;
-
-                let collision = self.display.draw(x, y, &sprite);
+                // SUPER-CHIP draws a 16x16 sprite for DXY0
+                let large = n == 0 && self.platform >= Platform::SuperChip;
+                let len = if large { 32 } else { n as usize };
+                // XO-CHIP reads one sprite after the other for each selected plane
+                let len = len * self.display.selected_planes();
+                let start = self.check_bounds(addr, self.registers.i as usize, len)?;
+                let sprite = self.memory.read_bytes(start, len);
+                let x = self.registers.v[x as usize] as usize;
+                let y = self.registers.v[y as usize] as usize;
+
+                let collision = if large {
+                    self.display.draw_large(x, y, &sprite, quirks.clip_sprites)
+                } else {
+                    self.display.draw(x, y, &sprite, quirks.clip_sprites)
+                };
                 self.drawing = true;
                 self.registers.v[0xF] = if collision { 1 } else { 0 };
-            }
-            0xE => match instr & 0x00FF {
-                0x9E => {
-                    // EX9E; SKP Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.registers.pc += if self.keyboard[self.registers.v[vx] as usize] {
-                        2
-                    } else {
-                        0
-                    }
-                }
-                0xA1 => {
-                    // EXA1; SKNP Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.registers.pc += if !self.keyboard[self.registers.v[vx] as usize] {
-                        2
-                    } else {
-                        0
-                    }
-                }
-                _ => unimplemented!("Instruction 0x{:04X} not implemented", instr),
-            },
-            0xF => match instr & 0x00FF {
-                0x07 => {
-                    // FX07; LD Vx, DT
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.registers.v[vx] = self.delay.get();
-                }
-                0x0A => {
-                    // FX0A; LD Vx, K
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.interrupt = Interrupt::KeyPress(vx as u8);
-                }
-                0x15 => {
-                    // FX15; LD DT, Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.delay.set(self.registers.v[vx]);
-                }
-                0x18 => {
-                    // FX18; LD ST, Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.sound.set(self.registers.v[vx]);
+
+                if quirks.display_wait {
+                    self.interrupt = Interrupt::VBlank;
                 }
-                0x1E => {
-                    // FX1E; ADD I, Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    self.registers.i += self.registers.v[vx] as u16;
+            }
+            Instruction::Skp(x) => {
+                // EX9E; SKP Vx
+                skip_if(pc, key_pressed(&self.keyboard, v[x as usize]), next_size);
+            }
+            Instruction::Sknp(x) => {
+                // EXA1; SKNP Vx
+                skip_if(pc, !key_pressed(&self.keyboard, v[x as usize]), next_size);
+            }
+            // F000 NNNN; LD I, LONG addr
+            Instruction::LdILong(nnnn) => self.registers.i = nnnn,
+            // FN01; PLANE nibble
+            Instruction::Plane(n) => self.display.select_planes(n),
+            Instruction::Audio => {
+                // F002; AUDIO
+                let start =
+                    self.check_bounds(addr, self.registers.i as usize, AUDIO_PATTERN_SIZE)?;
+                let pattern = self.memory.read_bytes(start, AUDIO_PATTERN_SIZE);
+                self.audio_pattern.copy_from_slice(&pattern);
+            }
+            // FX07; LD Vx, DT
+            Instruction::LdFromDelay(x) => v[x as usize] = self.delay.get(),
+            // FX0A; LD Vx, K
+            Instruction::LdKey(x) => self.interrupt = Interrupt::KeyPress(x),
+            // FX15; LD DT, Vx
+            Instruction::LdDelay(x) => self.delay.set(v[x as usize]),
+            // FX18; LD ST, Vx
+            Instruction::LdSound(x) => self.sound.set(v[x as usize]),
+            Instruction::AddI(x) => {
+                // FX1E; ADD I, Vx
+                self.registers.i = self.registers.i.wrapping_add(v[x as usize] as u16);
+            }
+            Instruction::LdFont(x) => {
+                // FX29; LD F, Vx
+                let digit = v[x as usize];
+                if digit > 0xF {
+                    return Err(CpuError::InvalidFontDigit { addr, digit });
                 }
-                0x29 => {
-                    // FX29; LD F, Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    let digit = self.registers.v[vx];
-                    debug_assert!(digit <= 0xF, "Invalid digit: 0x{:X}", digit);
-                    self.registers.i = (digit as u16 * 5) + FONT_START;
+                self.registers.i = (digit as u16 * 5) + FONT_START;
+            }
+            Instruction::LdHiFont(x) => {
+                // FX30; LD HF, Vx
+                let digit = v[x as usize];
+                if digit > 0xF {
+                    return Err(CpuError::InvalidFontDigit { addr, digit });
                 }
-                0x33 => {
-                    // FX33; LD B, Vx
-                    let vx = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    let value = self.registers.v[vx];
-                    self.memory.write(self.registers.i, value / 100);
-                    self.memory.write(self.registers.i + 1, (value / 10) % 10);
-                    self.memory.write(self.registers.i + 2, value % 10);
+                self.registers.i = (digit as u16 * 10) + HIRES_FONT_START;
+            }
+            Instruction::LdBcd(x) => {
+                // FX33; LD B, Vx
+                let value = v[x as usize];
+                let start = self.check_bounds(addr, self.registers.i as usize, 3)?;
+                self.memory.write(start, value / 100);
+                self.memory.write(start + 1, (value / 10) % 10);
+                self.memory.write(start + 2, value % 10);
+            }
+            // FX3A; LD PITCH, Vx
+            Instruction::LdPitch(x) => self.pitch = v[x as usize],
+            Instruction::Store(x) => {
+                // FX55; LD [I], Vx
+                let x = x as usize;
+                let start = self.check_bounds(addr, self.registers.i as usize, x + 1)?;
+                self.memory.write_bytes(start, &self.registers.v[..=x]);
+                if quirks.load_store_increments_i {
+                    self.registers.i = self.registers.i.wrapping_add(x as u16 + 1);
                 }
-                0x55 => {
-                    // FX55; LD [I], Vx
-                    let x = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    for i in 0..=x {
-                        self.memory
-                            .write(self.registers.i + i as u16, self.registers.v[i]);
-                    }
+            }
+            Instruction::Load(x) => {
+                // FX65; LD Vx, [I]
+                let x = x as usize;
+                let start = self.check_bounds(addr, self.registers.i as usize, x + 1)?;
+                for i in 0..=x {
+                    self.registers.v[i] = self.memory.read(start + i as u16);
                 }
-                0x65 => {
-                    // FX65; LD Vx, [I]
-                    let x = ((instr as u16 & 0x0F00) >> 8) as usize;
-                    for i in 0..=x {
-                        self.registers.v[i] = self.memory.read(self.registers.i + i as u16);
-                    }
+                if quirks.load_store_increments_i {
+                    self.registers.i = self.registers.i.wrapping_add(x as u16 + 1);
                 }
-                _ => unimplemented!("Instruction 0x{:04X} not implemented", instr),
-            },
-            _ => unimplemented!("Instruction 0x{:04X} not implemented", instr),
+            }
+            Instruction::StoreFlags(x) => {
+                // FX75; LD R, Vx
+                let x = x as usize;
+                self.flags[..=x].copy_from_slice(&v[..=x]);
+            }
+            Instruction::LoadFlags(x) => {
+                // FX85; LD Vx, R
+                let x = x as usize;
+                v[..=x].copy_from_slice(&self.flags[..=x]);
+            }
         }
+
+        Ok(())
     }
 
-    fn ret(&mut self) {
+    fn ret(&mut self, addr: Address) -> Result<(), CpuError> {
+        if self.registers.sp == 0 {
+            return Err(CpuError::StackUnderflow { addr });
+        }
+        self.registers.sp -= 1;
         self.registers.pc = self.stack[self.registers.sp];
-        self.registers.sp = self.registers.sp.saturating_sub(1);
+        Ok(())
     }
 
     fn sys_addr(&mut self, _addr: Address) {
@@ -326,18 +630,50 @@ impl Cpu {
         self.registers.pc = addr;
     }
 
-    fn call_addr(&mut self, addr: Address) {
-        self.registers.sp = self.registers.sp.saturating_add(1);
+    fn call_addr(&mut self, addr: Address, target: Address) -> Result<(), CpuError> {
+        // `sp` is the number of return addresses on the stack, the next one goes at `stack[sp]`
+        if self.registers.sp >= self.stack_depth {
+            return Err(CpuError::StackOverflow {
+                addr,
+                depth: self.stack_depth,
+            });
+        }
         self.stack[self.registers.sp] = self.registers.pc;
-        self.registers.pc = addr & 0x0FFF;
+        self.registers.sp += 1;
+        self.registers.pc = target & 0x0FFF;
+        Ok(())
+    }
+}
+
+fn skip_if(pc: &mut Address, condition: bool, len: Address) {
+    if condition {
+        *pc = pc.wrapping_add(len);
     }
 }
 
+/// The registers from Vx to Vy, in reverse order if X is greater than Y
+fn register_range(x: Register, y: Register) -> Vec<usize> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
+
+fn key_pressed(keyboard: &Keyboard, key: u8) -> bool {
+    // The keypad only decodes the low nibble of Vx
+    keyboard[(key & 0x0F) as usize]
+}
+
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod tests {
     use super::*;
-    use crate::display::DISPLAY_WIDTH;
+    use crate::display::{
+        DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_SIZE,
+        HIRES_DISPLAY_WIDTH,
+    };
 
     #[test]
     fn test_tick_timers() {
@@ -357,26 +693,65 @@ mod tests {
         cpu.memory.write(0x200, 0x12);
         cpu.memory.write(0x201, 0x34);
 
-        assert_eq!(cpu.fetch(), 0x1234); // check that the instruction is read correctly
+        assert_eq!(cpu.fetch().unwrap(), 0x1234); // check that the instruction is read correctly
         assert_eq!(cpu.registers.pc, 0x202); // check that the program counter is incremented
     }
 
+    #[test]
+    fn test_instruction_at() {
+        let mut cpu = Cpu::init(&[0x00, 0xE0, 0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(cpu.instruction_at(0x200), Some(Instruction::Cls));
+        assert_eq!(cpu.instruction_at(0x202), None);
+        assert_eq!(cpu.instruction_at(0xFFF), None);
+
+        cpu.set_platform(Platform::XoChip);
+        assert_eq!(
+            cpu.instruction_at(0x202),
+            Some(Instruction::LdILong(0x1234))
+        );
+    }
+
+    #[test]
+    fn test_memory_tracing() {
+        // LD I, 0x300; LD V1, [I]
+        let mut cpu = Cpu::init(&[0xA3, 0x00, 0xF1, 0x65]);
+        cpu.set_memory_tracing(true);
+
+        cpu.tick().unwrap();
+        assert!(cpu.take_memory_accesses().is_empty());
+        cpu.tick().unwrap();
+        assert_eq!(
+            cpu.take_memory_accesses()
+                .iter()
+                .map(|access| access.addr)
+                .collect::<Vec<_>>(),
+            [0x300, 0x301]
+        );
+    }
+
     #[test]
     fn test_RET() {
         const RET: OpCode = 0x00EE;
 
         let mut cpu = Cpu::default();
-        cpu.registers.sp = 1;
+        cpu.registers.sp = 2;
         cpu.stack[0] = 0x1234;
         cpu.stack[1] = 0x5678;
 
-        cpu.execute(RET);
+        cpu.execute(RET).unwrap();
         assert_eq!(cpu.registers.pc, 0x5678);
+        assert_eq!(cpu.registers.sp, 1);
+        cpu.execute(RET).unwrap();
+        assert_eq!(cpu.registers.pc, 0x1234);
         assert_eq!(cpu.registers.sp, 0);
 
-        cpu.execute(RET);
-        assert_eq!(cpu.registers.pc, 0x1234);
-        assert_eq!(cpu.registers.sp, 0); // saturating_sub should prevent underflow
+        cpu.registers.pc = 0x202;
+        assert_eq!(
+            cpu.execute(RET),
+            Err(CpuError::StackUnderflow { addr: 0x200 })
+        );
+        assert_eq!(cpu.registers.pc, 0x202);
+        assert_eq!(cpu.registers.sp, 0);
     }
 
     #[test]
@@ -385,7 +760,7 @@ mod tests {
 
         let mut cpu = Cpu::default();
         assert_eq!(cpu.registers.pc, 0);
-        cpu.execute(JMP);
+        cpu.execute(JMP).unwrap();
         assert_eq!(cpu.registers.pc, 0x234);
     }
 
@@ -397,10 +772,59 @@ mod tests {
         cpu.registers.pc = 0x200;
         cpu.registers.sp = 0;
 
-        cpu.execute(CALL);
+        cpu.execute(CALL).unwrap();
 
         assert_eq!(cpu.registers.pc, 0x345);
-        assert_eq!(cpu.stack[cpu.registers.sp], 0x200);
+        assert_eq!(cpu.registers.sp, 1);
+        assert_eq!(cpu.stack(), [0x200]);
+    }
+
+    #[test]
+    fn test_CALL_addr_overflow() {
+        const CALL: OpCode = 0x2345;
+
+        let mut cpu = Cpu::default();
+        cpu.registers.pc = 0x202;
+        cpu.registers.sp = 12;
+
+        assert_eq!(
+            cpu.execute(CALL),
+            Err(CpuError::StackOverflow {
+                addr: 0x200,
+                depth: 12
+            })
+        );
+        assert_eq!(cpu.registers.pc, 0x202);
+        assert_eq!(cpu.registers.sp, 12);
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        // A subroutine that calls itself: CALL 0x200
+        let mut cpu = Cpu::init(&[0x22, 0x00]);
+        for depth in 0..12 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Executed), "call {}", depth);
+        }
+        assert_eq!(cpu.stack(), [0x202; 12]);
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::StackOverflow {
+                addr: 0x200,
+                depth: 12
+            })
+        );
+
+        let mut cpu = Cpu::init(&[0x22, 0x00]);
+        cpu.set_platform(Platform::SuperChip);
+        assert_eq!(cpu.stack_depth(), 16);
+        for _ in 0..16 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.stack().len(), 16);
+        assert!(cpu.tick().is_err());
+
+        cpu.set_stack_depth(64);
+        assert_eq!(cpu.stack_depth(), MAX_STACK_DEPTH);
     }
 
     #[test]
@@ -409,10 +833,10 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0x3012);
+        cpu.execute(0x3012).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
 
-        cpu.execute(0x3013);
+        cpu.execute(0x3013).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -422,10 +846,10 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0x4013);
+        cpu.execute(0x4013).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
 
-        cpu.execute(0x4012);
+        cpu.execute(0x4012).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -437,10 +861,10 @@ mod tests {
         cpu.registers.v[2] = 0x13;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0x5010);
+        cpu.execute(0x5010).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
 
-        cpu.execute(0x5020);
+        cpu.execute(0x5020).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -449,7 +873,7 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0x00;
 
-        cpu.execute(0x6012);
+        cpu.execute(0x6012).unwrap();
         assert_eq!(cpu.registers.v[0], 0x12);
     }
 
@@ -458,12 +882,12 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0x12;
 
-        cpu.execute(0x7012);
+        cpu.execute(0x7012).unwrap();
         assert_eq!(cpu.registers.v[0], 0x24);
 
         cpu.registers.v[0] = 0xFF;
 
-        cpu.execute(0x7001);
+        cpu.execute(0x7001).unwrap();
         assert_eq!(cpu.registers.v[0], 0x00);
     }
 
@@ -473,19 +897,19 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.registers.v[1] = 0x34;
 
-        cpu.execute(0x8010);
+        cpu.execute(0x8010).unwrap();
         assert_eq!(cpu.registers.v[0], 0x34);
 
         cpu.registers.v[0] = 0x12;
         cpu.registers.v[0xF] = 0x34;
 
-        cpu.execute(0x80F0);
+        cpu.execute(0x80F0).unwrap();
         assert_eq!(cpu.registers.v[0], 0x34);
 
         cpu.registers.v[0xF] = 0x12;
         cpu.registers.v[0] = 0x34;
 
-        cpu.execute(0x8F00);
+        cpu.execute(0x8F00).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0x34);
     }
 
@@ -495,19 +919,19 @@ mod tests {
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[1] = 0b1010;
 
-        cpu.execute(0x8011);
+        cpu.execute(0x8011).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1110);
 
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[0xF] = 0b1010;
 
-        cpu.execute(0x80F1);
+        cpu.execute(0x80F1).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1110);
 
         cpu.registers.v[0xF] = 0b1100;
         cpu.registers.v[0] = 0b1010;
 
-        cpu.execute(0x8F01);
+        cpu.execute(0x8F01).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0b1110);
     }
 
@@ -517,19 +941,19 @@ mod tests {
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[1] = 0b1010;
 
-        cpu.execute(0x8012);
+        cpu.execute(0x8012).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1000);
 
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[0xF] = 0b1010;
 
-        cpu.execute(0x80F2);
+        cpu.execute(0x80F2).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1000);
 
         cpu.registers.v[0xF] = 0b1100;
         cpu.registers.v[0] = 0b1010;
 
-        cpu.execute(0x8F02);
+        cpu.execute(0x8F02).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0b1000);
     }
 
@@ -539,19 +963,19 @@ mod tests {
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[1] = 0b1010;
 
-        cpu.execute(0x8013);
+        cpu.execute(0x8013).unwrap();
         assert_eq!(cpu.registers.v[0], 0b0110);
 
         cpu.registers.v[0] = 0b1100;
         cpu.registers.v[0xF] = 0b1010;
 
-        cpu.execute(0x80F3);
+        cpu.execute(0x80F3).unwrap();
         assert_eq!(cpu.registers.v[0], 0b0110);
 
         cpu.registers.v[0xF] = 0b1100;
         cpu.registers.v[0] = 0b1010;
 
-        cpu.execute(0x8F03);
+        cpu.execute(0x8F03).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0b0110);
     }
 
@@ -561,14 +985,14 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.registers.v[1] = 0x34;
 
-        cpu.execute(0x8014);
+        cpu.execute(0x8014).unwrap();
         assert_eq!(cpu.registers.v[0], 0x46);
         assert_eq!(cpu.registers.v[0xF], 0);
 
         cpu.registers.v[0] = 0xFF;
         cpu.registers.v[1] = 0x01;
 
-        cpu.execute(0x8014);
+        cpu.execute(0x8014).unwrap();
         assert_eq!(cpu.registers.v[0], 0x00);
         assert_eq!(cpu.registers.v[0xF], 1);
 
@@ -576,7 +1000,7 @@ mod tests {
         cpu.registers.v[0xF] = 0xFF;
         cpu.registers.v[0] = 0xAA;
 
-        cpu.execute(0x8F04);
+        cpu.execute(0x8F04).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0x1);
     }
 
@@ -586,14 +1010,14 @@ mod tests {
         cpu.registers.v[0] = 0x34;
         cpu.registers.v[1] = 0x12;
 
-        cpu.execute(0x8015);
+        cpu.execute(0x8015).unwrap();
         assert_eq!(cpu.registers.v[0], 0x22);
         assert_eq!(cpu.registers.v[0xF], 1);
 
         cpu.registers.v[0] = 0x12;
         cpu.registers.v[1] = 0x34;
 
-        cpu.execute(0x8015);
+        cpu.execute(0x8015).unwrap();
         assert_eq!(cpu.registers.v[0], 0xDE);
         assert_eq!(cpu.registers.v[0xF], 0);
 
@@ -601,7 +1025,7 @@ mod tests {
         cpu.registers.v[0xF] = 0x34;
         cpu.registers.v[0] = 0x12;
 
-        cpu.execute(0x8F05);
+        cpu.execute(0x8F05).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0x1);
     }
 
@@ -610,13 +1034,13 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0b0000_1100;
 
-        cpu.execute(0x8006);
+        cpu.execute(0x8006).unwrap();
         assert_eq!(cpu.registers.v[0], 0b0000_0110);
         assert_eq!(cpu.registers.v[0xF], 0);
 
         cpu.registers.v[0] = 0b0000_1101;
 
-        cpu.execute(0x8006);
+        cpu.execute(0x8006).unwrap();
         assert_eq!(cpu.registers.v[0], 0b0000_0110);
         assert_eq!(cpu.registers.v[0xF], 1);
     }
@@ -627,14 +1051,14 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.registers.v[1] = 0x34;
 
-        cpu.execute(0x8017);
+        cpu.execute(0x8017).unwrap();
         assert_eq!(cpu.registers.v[0], 0x22);
         assert_eq!(cpu.registers.v[0xF], 1);
 
         cpu.registers.v[0] = 0x34;
         cpu.registers.v[1] = 0x12;
 
-        cpu.execute(0x8017);
+        cpu.execute(0x8017).unwrap();
         assert_eq!(cpu.registers.v[0], 0xDE);
         assert_eq!(cpu.registers.v[0xF], 0);
 
@@ -642,7 +1066,7 @@ mod tests {
         cpu.registers.v[0xF] = 0x12;
         cpu.registers.v[0] = 0x34;
 
-        cpu.execute(0x8F07);
+        cpu.execute(0x8F07).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0x1);
     }
 
@@ -651,13 +1075,13 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0b1100_0000;
 
-        cpu.execute(0x800E);
+        cpu.execute(0x800E).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1000_0000);
         assert_eq!(cpu.registers.v[0xF], 1);
 
         cpu.registers.v[0] = 0b0100_0000;
 
-        cpu.execute(0x800E);
+        cpu.execute(0x800E).unwrap();
         assert_eq!(cpu.registers.v[0], 0b1000_0000);
         assert_eq!(cpu.registers.v[0xF], 0);
     }
@@ -670,10 +1094,10 @@ mod tests {
         cpu.registers.v[2] = 0x13;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0x9010);
+        cpu.execute(0x9010).unwrap();
         assert_eq!(cpu.registers.pc, 0x200);
 
-        cpu.execute(0x9020);
+        cpu.execute(0x9020).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -682,7 +1106,7 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.i = 0x1234;
 
-        cpu.execute(0xA432);
+        cpu.execute(0xA432).unwrap();
         assert_eq!(cpu.registers.i, 0x0432);
     }
 
@@ -691,15 +1115,31 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0x12;
 
-        cpu.execute(0xB234);
+        cpu.execute(0xB234).unwrap();
         assert_eq!(cpu.registers.pc, 0x0246);
     }
 
     #[test]
-    fn test_RND_Vx_byte() {
+    fn test_keypad() {
         let mut cpu = Cpu::default();
-        cpu.rng = Pcg64Mcg::seed_from_u64(0);
-        cpu.execute(0xC012);
+        cpu.key_down(0x1);
+        cpu.key_down(0xF);
+        assert_eq!(cpu.keypad(), 0x8002);
+
+        cpu.execute(0xF30A).unwrap();
+        cpu.set_keypad(0x0014);
+        assert_eq!(cpu.keypad(), 0x0014);
+        // The lowest newly pressed key satisfies a pending key wait
+        assert_eq!(cpu.registers.v[3], 0x2);
+    }
+
+    #[test]
+    fn test_RND_Vx_byte() {
+        let mut cpu = Cpu {
+            rng: Pcg64Mcg::seed_from_u64(0),
+            ..Default::default()
+        };
+        cpu.execute(0xC012).unwrap();
         assert_eq!(cpu.registers.v[0], 0x02);
     }
 
@@ -712,7 +1152,7 @@ mod tests {
         cpu.memory.write(0x200, 0b11110000);
         cpu.memory.write(0x201, 0b00001111);
 
-        cpu.execute(0xD012);
+        cpu.execute(0xD012).unwrap();
         assert_eq!(cpu.registers.v[0xF], 0);
         assert!(cpu.drawing);
 
@@ -727,7 +1167,7 @@ mod tests {
             (7, 1),
         ];
         for &(x, y) in on_pixels.iter() {
-            assert_eq!(cpu.display.pixels[y * DISPLAY_WIDTH + x], Pixel::On);
+            assert_eq!(cpu.display.planes[0][y * DISPLAY_WIDTH + x], Pixel::On);
         }
     }
 
@@ -738,10 +1178,10 @@ mod tests {
         cpu.registers.v[0] = 0xB;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0xE09E);
+        cpu.execute(0xE09E).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
 
-        cpu.execute(0xE19E);
+        cpu.execute(0xE19E).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -752,10 +1192,10 @@ mod tests {
         cpu.registers.v[0] = 0xB;
         cpu.registers.pc = 0x200;
 
-        cpu.execute(0xE0A1);
+        cpu.execute(0xE0A1).unwrap();
         assert_eq!(cpu.registers.pc, 0x200);
 
-        cpu.execute(0xE1A1);
+        cpu.execute(0xE1A1).unwrap();
         assert_eq!(cpu.registers.pc, 0x202);
     }
 
@@ -765,7 +1205,7 @@ mod tests {
         cpu.delay.set(0x12);
         cpu.registers.v[0] = 0x00;
 
-        cpu.execute(0xF007);
+        cpu.execute(0xF007).unwrap();
         assert_eq!(cpu.registers.v[0], 0x12);
     }
 
@@ -774,7 +1214,7 @@ mod tests {
         let mut cpu = Cpu::default();
         cpu.registers.v[0] = 0xFF;
 
-        cpu.execute(0xF00A);
+        cpu.execute(0xF00A).unwrap();
         assert_eq!(cpu.interrupt, Interrupt::KeyPress(0));
 
         cpu.key_down(0xB);
@@ -788,7 +1228,7 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.delay.set(0x00);
 
-        cpu.execute(0xF015);
+        cpu.execute(0xF015).unwrap();
         assert_eq!(cpu.delay.get(), 0x12);
     }
 
@@ -798,7 +1238,7 @@ mod tests {
         cpu.registers.v[0] = 0x12;
         cpu.sound.set(0x00);
 
-        cpu.execute(0xF018);
+        cpu.execute(0xF018).unwrap();
         assert_eq!(cpu.sound.get(), 0x12);
     }
 
@@ -808,7 +1248,7 @@ mod tests {
         cpu.registers.i = 0x1234;
         cpu.registers.v[0] = 0x12;
 
-        cpu.execute(0xF01E);
+        cpu.execute(0xF01E).unwrap();
         assert_eq!(cpu.registers.i, 0x1246);
     }
 
@@ -819,18 +1259,33 @@ mod tests {
             cpu.registers.v[0] = i as u8;
             cpu.registers.i = 0x000;
 
-            cpu.execute(0xF029);
+            cpu.execute(0xF029).unwrap();
             assert_eq!(cpu.registers.i, (i * 5) + FONT_START);
         }
     }
 
+    #[test]
+    fn test_LD_F_Vx_invalid_digit() {
+        let mut cpu = Cpu::default();
+        cpu.registers.pc = 0x202;
+        cpu.registers.v[0] = 0x10;
+
+        assert_eq!(
+            cpu.execute(0xF029),
+            Err(CpuError::InvalidFontDigit {
+                addr: 0x200,
+                digit: 0x10
+            })
+        );
+    }
+
     #[test]
     fn test_LD_B_Vx() {
         let mut cpu = Cpu::default();
         cpu.registers.i = 0x200;
         cpu.registers.v[0] = 123;
 
-        cpu.execute(0xF033);
+        cpu.execute(0xF033).unwrap();
         assert_eq!(cpu.memory.read(0x200), 1);
         assert_eq!(cpu.memory.read(0x201), 2);
         assert_eq!(cpu.memory.read(0x202), 3);
@@ -844,7 +1299,7 @@ mod tests {
         }
 
         cpu.registers.i = 0x200;
-        cpu.execute(0xF755);
+        cpu.execute(0xF755).unwrap();
 
         for i in 0x0..=0xF_u8 {
             if i <= 0x7 {
@@ -854,7 +1309,7 @@ mod tests {
             }
         }
 
-        cpu.execute(0xFF55);
+        cpu.execute(0xFF55).unwrap();
 
         for i in 0x0..=0xF_u8 {
             assert_eq!(cpu.memory.read(0x200 + i as Address), i);
@@ -869,7 +1324,7 @@ mod tests {
         }
 
         cpu.registers.i = 0x200;
-        cpu.execute(0xF765);
+        cpu.execute(0xF765).unwrap();
 
         for i in 0x0..=0xF_u8 {
             if i <= 0x7 {
@@ -879,10 +1334,383 @@ mod tests {
             }
         }
 
-        cpu.execute(0xFF65);
+        cpu.execute(0xFF65).unwrap();
 
         for i in 0x0..=0xF_u8 {
             assert_eq!(cpu.registers.v[i as usize], i);
         }
     }
+
+    #[test]
+    fn test_LD_I_Vx_out_of_bounds() {
+        let mut cpu = Cpu::default();
+        cpu.registers.pc = 0x202;
+        cpu.registers.i = 0xFFE;
+
+        assert_eq!(
+            cpu.execute(0xF255),
+            Err(CpuError::MemoryOutOfBounds {
+                addr: 0x200,
+                target: 0xFFE
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_opcodes() {
+        // SUPER-CHIP instructions aren't available on the default CHIP-8 platform
+        for opcode in [0x5001, 0x8008, 0x900F, 0xE000, 0xF099, 0xF030] {
+            let mut cpu = Cpu::default();
+            cpu.registers.pc = 0x202;
+
+            assert_eq!(
+                cpu.execute(opcode),
+                Err(CpuError::InvalidOpcode {
+                    addr: 0x200,
+                    opcode
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_later_platform_sys() {
+        // SUPER-CHIP's 00CN-00FF are machine code calls on CHIP-8, which are ignored
+        for opcode in [0x00C1, 0x00D1, 0x00FB, 0x00FD, 0x00FF] {
+            let mut cpu = Cpu::default();
+            cpu.registers.pc = 0x202;
+            assert_eq!(cpu.execute(opcode), Ok(()), "{:04X}", opcode);
+            assert!(!cpu.exited());
+        }
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut cpu = Cpu::init(&[0xF0, 0x0A, 0xFF, 0xFF]);
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.tick(), Ok(StepOutcome::WaitingForKey));
+
+        cpu.key_down(0x1);
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::InvalidOpcode {
+                addr: 0x202,
+                opcode: 0xFFFF
+            })
+        );
+    }
+
+    #[test]
+    fn test_tick_out_of_bounds() {
+        let mut cpu = Cpu::default();
+        cpu.registers.pc = 0xFFF;
+
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::MemoryOutOfBounds {
+                addr: 0xFFF,
+                target: 0x1000
+            })
+        );
+    }
+
+    #[test]
+    fn test_quirk_shift_uses_vy() {
+        let mut cpu = Cpu::default();
+        cpu.quirks.shift_uses_vy = true;
+        cpu.registers.v[0] = 0b0000_0000;
+        cpu.registers.v[1] = 0b1000_0001;
+
+        cpu.execute(0x8016).unwrap();
+        assert_eq!(cpu.registers.v[0], 0b0100_0000);
+        assert_eq!(cpu.registers.v[0xF], 1);
+
+        cpu.registers.v[0] = 0b0000_0000;
+        cpu.execute(0x801E).unwrap();
+        assert_eq!(cpu.registers.v[0], 0b0000_0010);
+        assert_eq!(cpu.registers.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_quirk_load_store_increments_i() {
+        let mut cpu = Cpu::default();
+        cpu.quirks.load_store_increments_i = true;
+        cpu.registers.i = 0x300;
+
+        cpu.execute(0xF255).unwrap();
+        assert_eq!(cpu.registers.i, 0x303);
+
+        cpu.execute(0xF065).unwrap();
+        assert_eq!(cpu.registers.i, 0x304);
+    }
+
+    #[test]
+    fn test_quirk_jump_uses_vx() {
+        let mut cpu = Cpu::default();
+        cpu.quirks.jump_uses_vx = true;
+        cpu.registers.v[0] = 0x10;
+        cpu.registers.v[2] = 0x01;
+
+        cpu.execute(0xB234).unwrap();
+        assert_eq!(cpu.registers.pc, 0x0235);
+    }
+
+    #[test]
+    fn test_quirk_vf_reset() {
+        for opcode in [0x8011, 0x8012, 0x8013] {
+            let mut cpu = Cpu::default();
+            cpu.quirks.vf_reset = true;
+            cpu.registers.v[0xF] = 0x12;
+
+            cpu.execute(opcode).unwrap();
+            assert_eq!(cpu.registers.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn test_quirk_display_wait() {
+        let mut cpu = Cpu::init(&[0xD0, 0x01, 0x60, 0x12]);
+        cpu.quirks.display_wait = true;
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.tick(), Ok(StepOutcome::WaitingForVBlank));
+
+        cpu.tick_timers();
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.registers.v[0], 0x12);
+    }
+
+    fn schip() -> Cpu {
+        let mut cpu = Cpu::init(&[]);
+        cpu.set_platform(Platform::SuperChip);
+        cpu
+    }
+
+    #[test]
+    fn test_HIGH_LOW() {
+        let mut cpu = schip();
+
+        cpu.execute(0x00FF).unwrap();
+        assert_eq!(
+            cpu.resolution(),
+            (HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT)
+        );
+        assert_eq!(cpu.pixels().len(), HIRES_DISPLAY_SIZE);
+
+        cpu.execute(0x00FE).unwrap();
+        assert_eq!(cpu.resolution(), (DISPLAY_WIDTH, DISPLAY_HEIGHT));
+    }
+
+    #[test]
+    fn test_SCD_SCR_SCL() {
+        let mut cpu = schip();
+        cpu.display.draw(0, 0, &[0x80], false);
+
+        cpu.execute(0x00C2).unwrap();
+        assert_eq!(cpu.display.planes[0][2 * DISPLAY_WIDTH], Pixel::On);
+        assert!(cpu.drawing);
+
+        cpu.execute(0x00FB).unwrap();
+        assert_eq!(cpu.display.planes[0][2 * DISPLAY_WIDTH + 4], Pixel::On);
+
+        cpu.execute(0x00FC).unwrap();
+        assert_eq!(cpu.display.planes[0][2 * DISPLAY_WIDTH], Pixel::On);
+    }
+
+    #[test]
+    fn test_EXIT() {
+        let mut cpu = Cpu::init(&[0x00, 0xFD]);
+        cpu.set_platform(Platform::SuperChip);
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Exited));
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn test_DRW_Vx_Vy_0() {
+        let mut cpu = schip();
+        cpu.registers.i = 0x300;
+        cpu.memory.write_bytes(0x300, &[0xFF; 32]);
+        cpu.execute(0x00FF).unwrap();
+
+        cpu.execute(0xD010).unwrap();
+        let on_pixels = cpu.display.planes[0]
+            .iter()
+            .filter(|p| **p == Pixel::On)
+            .count();
+        assert_eq!(on_pixels, 16 * 16);
+        assert_eq!(cpu.registers.v[0xF], 0);
+
+        // Plain CHIP-8 draws nothing for DXY0
+        let mut cpu = Cpu::default();
+        cpu.execute(0xD010).unwrap();
+        assert!(cpu.display.planes[0].iter().all(|p| *p == Pixel::Off));
+    }
+
+    #[test]
+    fn test_LD_HF_Vx() {
+        let mut cpu = schip();
+        cpu.registers.v[0] = 0x2;
+
+        cpu.execute(0xF030).unwrap();
+        assert_eq!(cpu.registers.i, HIRES_FONT_START + 20);
+        assert_eq!(cpu.memory.read(cpu.registers.i), 0x3E);
+    }
+
+    #[test]
+    fn test_LD_R_Vx() {
+        let mut cpu = schip();
+        cpu.registers.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        cpu.execute(0xF275).unwrap();
+        cpu.registers.v = [0; 16];
+        cpu.execute(0xF385).unwrap();
+        assert_eq!(cpu.registers.v[..4], [1, 2, 3, 0]);
+    }
+
+    fn xo_chip() -> Cpu {
+        let mut cpu = Cpu::init(&[]);
+        cpu.set_platform(Platform::XoChip);
+        cpu
+    }
+
+    #[test]
+    fn test_LD_I_long() {
+        let mut cpu = Cpu::init(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0]);
+        cpu.set_platform(Platform::XoChip);
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.registers.i, 0x1234);
+        assert_eq!(cpu.registers.pc, 0x204);
+
+        // Not available on plain CHIP-8
+        let mut cpu = Cpu::init(&[0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(
+            cpu.tick(),
+            Err(CpuError::InvalidOpcode {
+                addr: 0x200,
+                opcode: 0xF000
+            })
+        );
+    }
+
+    #[test]
+    fn test_skip_long() {
+        let mut cpu = Cpu::init(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01]);
+        cpu.set_platform(Platform::XoChip);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let mut cpu = xo_chip();
+        cpu.registers.i = 0xFFF0;
+        cpu.registers.v[0] = 0xAB;
+
+        cpu.execute(0xF055).unwrap();
+        assert_eq!(cpu.memory.read(0xFFF0), 0xAB);
+
+        cpu.set_platform(Platform::Chip8);
+        assert_eq!(
+            cpu.execute(0xF055),
+            Err(CpuError::MemoryOutOfBounds {
+                addr: 0x1FE,
+                target: 0xFFF0
+            })
+        );
+    }
+
+    #[test]
+    fn test_SAVE_LOAD_range() {
+        let mut cpu = xo_chip();
+        cpu.registers.i = 0x300;
+        cpu.registers.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        cpu.execute(0x5132).unwrap();
+        assert_eq!(cpu.memory.read_bytes(0x300, 3), vec![2, 3, 4]);
+        assert_eq!(cpu.registers.i, 0x300);
+
+        // Reversed ranges are transferred in reverse order
+        cpu.execute(0x5312).unwrap();
+        assert_eq!(cpu.memory.read_bytes(0x300, 3), vec![4, 3, 2]);
+
+        cpu.execute(0x5AC3).unwrap();
+        assert_eq!(cpu.registers.v[0xA..=0xC], [4, 3, 2]);
+    }
+
+    #[test]
+    fn test_PLANE_DRW() {
+        let mut cpu = xo_chip();
+        cpu.registers.i = 0x300;
+        cpu.memory.write_bytes(0x300, &[0x80, 0x40]);
+
+        cpu.execute(0xF301).unwrap();
+        cpu.execute(0xD011).unwrap();
+        assert_eq!(cpu.colors()[..2], [0b01, 0b10]);
+
+        // CLS only clears the selected planes
+        cpu.execute(0xF201).unwrap();
+        cpu.execute(0x00E0).unwrap();
+        assert_eq!(cpu.colors()[..2], [0b01, 0b00]);
+    }
+
+    #[test]
+    fn test_AUDIO_PITCH() {
+        let mut cpu = xo_chip();
+        cpu.registers.i = 0x300;
+        cpu.memory.write_bytes(0x300, &[0xAA; 16]);
+        assert_eq!(cpu.playback_rate(), 4000.0);
+
+        cpu.execute(0xF002).unwrap();
+        assert_eq!(cpu.audio_pattern(), &[0xAA; 16]);
+
+        cpu.registers.v[1] = 112;
+        cpu.execute(0xF13A).unwrap();
+        assert_eq!(cpu.playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let rom = [0x60, 0x12, 0xC1, 0xFF, 0xA3, 0x00, 0xD0, 0x05, 0x12, 0x00];
+        let mut cpu = Cpu::init(&rom);
+        cpu.set_platform(Platform::SuperChip);
+        cpu.tick().unwrap();
+        cpu.key_down(0x3);
+        cpu.delay.set(0x20);
+
+        let state = cpu.save_state();
+        let registers = cpu.registers.v;
+        for _ in 0..8 {
+            cpu.tick().unwrap();
+        }
+
+        let mut restored = Cpu::init(&rom);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.registers.v, registers);
+        assert_eq!(restored.registers.pc, 0x202);
+        assert_eq!(restored.platform(), Platform::SuperChip);
+        assert!(restored.keyboard[0x3]);
+        assert_eq!(restored.delay.get(), 0x20);
+
+        // The RNG continues from the same point
+        cpu.load_state(&state).unwrap();
+        for _ in 0..8 {
+            cpu.tick().unwrap();
+            restored.tick().unwrap();
+        }
+        assert_eq!(cpu.registers.v, restored.registers.v);
+        assert_eq!(cpu.display.planes, restored.display.planes);
+    }
+
+    #[test]
+    fn test_load_state_rom_mismatch() {
+        let state = Cpu::init(&[0x00, 0xE0]).save_state();
+
+        let mut cpu = Cpu::init(&[0x00, 0xEE]);
+        assert_eq!(cpu.load_state(&state), Err(StateError::RomMismatch));
+    }
 }
@@ -1,2 +1,3 @@
 pub type Address = u16;
 pub type OpCode = u16;
+pub type Register = u8;
@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::state::{self, RomHash};
+
+/// A snapshot of the community CHIP-8 database, refreshed with `just update-database`
+const BUNDLED: &str = include_str!("../../res/database/programs.json");
+
+/// Platforms of the database that can run here, in the order they are preferred.
+///
+/// The quirks are the ones the database lists for each platform in `platforms.json`.
+const PLATFORMS: [(&str, Platform, DbQuirks); 7] = [
+    (
+        "originalChip8",
+        Platform::Chip8,
+        DbQuirks::all(false, false, false, false, false, true, true),
+    ),
+    (
+        "hybridVIP",
+        Platform::Chip8,
+        DbQuirks::all(false, false, false, false, false, true, true),
+    ),
+    (
+        "modernChip8",
+        Platform::Chip8,
+        DbQuirks::all(false, false, false, false, false, false, false),
+    ),
+    (
+        "chip48",
+        Platform::Chip8,
+        DbQuirks::all(true, true, false, false, true, false, false),
+    ),
+    (
+        "superchip1",
+        Platform::SuperChip,
+        DbQuirks::all(true, true, false, false, true, false, false),
+    ),
+    (
+        "superchip",
+        Platform::SuperChip,
+        DbQuirks::all(true, false, true, false, true, false, false),
+    ),
+    (
+        "xochip",
+        Platform::XoChip,
+        DbQuirks::all(false, false, false, true, false, false, false),
+    ),
+];
+
+/// Quirks as the database names them, most of them the inverse of the ones in [`Quirks`]
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DbQuirks {
+    /// Shifts change Vx in place
+    shift: Option<bool>,
+    /// `FX55`/`FX65` add X to I instead of X + 1, treated like incrementing I
+    memory_increment_by_x: Option<bool>,
+    /// `FX55`/`FX65` leave I alone
+    memory_leave_i_unchanged: Option<bool>,
+    /// Sprites wrap around the edges of the screen
+    wrap: Option<bool>,
+    /// `BXNN` jumps to XNN + VX
+    jump: Option<bool>,
+    /// Drawing waits for the vertical blank
+    vblank: Option<bool>,
+    /// The logic instructions reset VF
+    logic: Option<bool>,
+}
+
+impl DbQuirks {
+    const fn all(
+        shift: bool,
+        memory_increment_by_x: bool,
+        memory_leave_i_unchanged: bool,
+        wrap: bool,
+        jump: bool,
+        vblank: bool,
+        logic: bool,
+    ) -> DbQuirks {
+        DbQuirks {
+            shift: Some(shift),
+            memory_increment_by_x: Some(memory_increment_by_x),
+            memory_leave_i_unchanged: Some(memory_leave_i_unchanged),
+            wrap: Some(wrap),
+            jump: Some(jump),
+            vblank: Some(vblank),
+            logic: Some(logic),
+        }
+    }
+
+    /// Overwrites the quirks this sets, leaving the others alone
+    fn apply(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shift_uses_vy = !shift;
+        }
+        if let Some(unchanged) = self.memory_leave_i_unchanged {
+            quirks.load_store_increments_i = !unchanged;
+        }
+        if self.memory_increment_by_x == Some(true) {
+            quirks.load_store_increments_i = true;
+        }
+        if let Some(wrap) = self.wrap {
+            quirks.clip_sprites = !wrap;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jump_uses_vx = jump;
+        }
+        if let Some(vblank) = self.vblank {
+            quirks.display_wait = vblank;
+        }
+        if let Some(logic) = self.logic {
+            quirks.vf_reset = logic;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DbProgram {
+    title: String,
+    roms: HashMap<String, DbRom>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct DbRom {
+    platforms: Vec<String>,
+    quirky_platforms: HashMap<String, DbQuirks>,
+    tickrate: Option<u32>,
+    colors: Option<DbColors>,
+    keys: BTreeMap<String, u8>,
+    screen_rotation: u16,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct DbColors {
+    pixels: Vec<String>,
+    buzzer: Option<String>,
+    silence: Option<String>,
+}
+
+/// The colours a ROM was designed for, as RGB
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Colors {
+    /// One colour per combination of planes, starting with the background
+    pub pixels: Vec<[u8; 3]>,
+    /// Background while the buzzer sounds
+    pub buzzer: Option<[u8; 3]>,
+    /// Background while the buzzer is quiet
+    pub silence: Option<[u8; 3]>,
+}
+
+/// What the database knows about a ROM, converted to the settings of this emulator
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    /// The speed the ROM is meant to run at, `None` if the default works
+    pub instructions_per_frame: Option<u32>,
+    pub colors: Option<Colors>,
+    /// What the CHIP-8 keys do in the game, e.g. `"up"` or `"a"` to the key pressed for it
+    pub keys: BTreeMap<String, u8>,
+    /// Clockwise rotation of the screen in degrees, for programs made for a phone held upright
+    pub screen_rotation: u16,
+}
+
+/// ROM metadata in the format of the community CHIP-8 database, looked up by SHA-1
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    programs: Vec<DbProgram>,
+    /// Lowercase SHA-1 to the index of the program
+    hashes: HashMap<String, usize>,
+}
+
+impl Database {
+    /// Parses the contents of the database's `programs.json`
+    pub fn parse(json: &str) -> serde_json::Result<Database> {
+        let programs: Vec<DbProgram> = serde_json::from_str(json)?;
+        let hashes = programs
+            .iter()
+            .enumerate()
+            .flat_map(|(index, program)| {
+                program
+                    .roms
+                    .keys()
+                    .map(move |hash| (hash.to_ascii_lowercase(), index))
+            })
+            .collect();
+        Ok(Database { programs, hashes })
+    }
+
+    /// The database compiled into this crate
+    pub fn bundled() -> &'static Database {
+        static DATABASE: OnceLock<Database> = OnceLock::new();
+        DATABASE.get_or_init(|| Database::parse(BUNDLED).expect("Bundled ROM database is invalid"))
+    }
+
+    /// The number of ROMs in the database
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Looks up a ROM by its SHA-1, `None` if it is unknown or only runs on platforms that
+    /// aren't supported
+    pub fn lookup(&self, sha1: &RomHash) -> Option<RomInfo> {
+        let hex = state::rom_hash_hex(sha1);
+        let program = &self.programs[*self.hashes.get(&hex)?];
+        let rom = program
+            .roms
+            .iter()
+            .find_map(|(hash, rom)| hash.eq_ignore_ascii_case(&hex).then_some(rom))?;
+
+        let (id, platform, base) = rom.platforms.iter().find_map(|id| {
+            PLATFORMS
+                .iter()
+                .find(|(name, _, _)| name == id)
+                .map(|&(_, platform, quirks)| (id, platform, quirks))
+        })?;
+        let mut quirks = Quirks::default();
+        base.apply(&mut quirks);
+        if let Some(overrides) = rom.quirky_platforms.get(id) {
+            overrides.apply(&mut quirks);
+        }
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            platform,
+            quirks,
+            instructions_per_frame: rom.tickrate,
+            colors: rom.colors.as_ref().map(|colors| Colors {
+                pixels: colors
+                    .pixels
+                    .iter()
+                    .filter_map(|c| parse_color(c))
+                    .collect(),
+                buzzer: colors.buzzer.as_deref().and_then(parse_color),
+                silence: colors.silence.as_deref().and_then(parse_color),
+            }),
+            keys: rom.keys.clone(),
+            screen_rotation: rom.screen_rotation,
+        })
+    }
+}
+
+/// Parses a `#rrggbb` colour
+pub(crate) fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn hash() -> RomHash {
+        let mut bytes = [0; 20];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&HASH[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_bundled() {
+        // Parsing the bundled copy panics if it is broken
+        Database::bundled();
+    }
+
+    #[test]
+    #[ignore = "res/database/programs.json is an empty placeholder, run `just update-database`"]
+    fn test_bundled_snapshot() {
+        assert!(!Database::bundled().is_empty());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let json = r##"[
+            {
+                "title": "Test Game",
+                "roms": {
+                    "0123456789ABCDEF0123456789ABCDEF01234567": {
+                        "file": "test.ch8",
+                        "platforms": ["megachip8", "superchip", "xochip"],
+                        "quirkyPlatforms": { "superchip": { "vblank": true } },
+                        "tickrate": 30,
+                        "colors": { "pixels": ["#000000", "#ff8000", "bad"], "buzzer": "#FFAA00" },
+                        "keys": { "up": 5, "a": 6 }
+                    }
+                }
+            }
+        ]"##;
+        let database = Database::parse(json).unwrap();
+        assert_eq!(database.len(), 1);
+
+        let info = database.lookup(&hash()).unwrap();
+        assert_eq!(info.title, "Test Game");
+        assert_eq!(info.platform, Platform::SuperChip);
+        assert_eq!(
+            info.quirks,
+            Quirks {
+                display_wait: true,
+                ..Quirks::SCHIP
+            }
+        );
+        assert_eq!(info.instructions_per_frame, Some(30));
+        assert_eq!(
+            info.colors,
+            Some(Colors {
+                pixels: vec![[0x00, 0x00, 0x00], [0xFF, 0x80, 0x00]],
+                buzzer: Some([0xFF, 0xAA, 0x00]),
+                silence: None,
+            })
+        );
+        assert_eq!(
+            info.keys,
+            BTreeMap::from([("a".into(), 6), ("up".into(), 5)])
+        );
+
+        assert_eq!(database.lookup(&state::rom_hash(b"unknown")), None);
+    }
+
+    #[test]
+    fn test_platform_quirks() {
+        let quirks = |id: &str| {
+            let (_, _, base) = PLATFORMS.iter().find(|(name, _, _)| *name == id).unwrap();
+            let mut quirks = Quirks::default();
+            base.apply(&mut quirks);
+            quirks
+        };
+        assert_eq!(quirks("originalChip8"), Quirks::VIP);
+        assert_eq!(quirks("superchip"), Quirks::SCHIP);
+        assert_eq!(quirks("xochip"), Quirks::XO_CHIP);
+    }
+}
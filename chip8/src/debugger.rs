@@ -0,0 +1,478 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cpu::{Cpu, StepOutcome};
+use crate::data::{Address, Register};
+use crate::error::CpuError;
+use crate::instruction::Instruction;
+pub use crate::memory::{Access, MemoryAccess};
+
+/// A value a [`Condition`] can test
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    V(Register),
+    I,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Limits a breakpoint to the times a register holds a certain value, e.g. `V3 == 0x10`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn matches(&self, cpu: &Cpu) -> bool {
+        let actual = match self.operand {
+            Operand::V(x) => cpu.registers.v[x as usize] as u16,
+            Operand::I => cpu.registers.i,
+        };
+        match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operand {
+            Operand::V(x) => write!(f, "V{:X}", x)?,
+            Operand::I => write!(f, "I")?,
+        }
+        let comparison = match self.comparison {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, " {} 0x{:X}", comparison, self.value)
+    }
+}
+
+/// The kind of memory access that triggers a watchpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Watch {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (Watch::ReadWrite, _) | (Watch::Read, Access::Read) | (Watch::Write, Access::Write)
+        )
+    }
+}
+
+/// Why the debugger paused the CPU. The PC always points at the next instruction to run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Break {
+    /// [`Debugger::pause`] was called
+    Pause,
+    /// A step, step over or step out finished
+    Step,
+    Breakpoint(Address),
+    /// The instruction at `addr` accessed a watched byte
+    Watchpoint {
+        addr: Address,
+        access: MemoryAccess,
+    },
+    /// The instruction at the address drew a sprite
+    Draw(Address),
+    /// The instruction at the address started waiting for a key press
+    KeyWait(Address),
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Break::Pause => write!(f, "Paused"),
+            Break::Step => write!(f, "Stepped"),
+            Break::Breakpoint(addr) => write!(f, "Breakpoint at 0x{:03X}", addr),
+            Break::Watchpoint { addr, access } => {
+                let verb = match access.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                write!(
+                    f,
+                    "Watchpoint: {} of 0x{:03X} at 0x{:03X}",
+                    verb, access.addr, addr
+                )
+            }
+            Break::Draw(addr) => write!(f, "Draw at 0x{:03X}", addr),
+            Break::KeyWait(addr) => write!(f, "Waiting for a key at 0x{:03X}", addr),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Running,
+    Paused,
+    Step,
+    /// Step over or out was requested, the target is picked before the next instruction runs
+    StepOverNext,
+    StepOutNext,
+    /// Runs until the CPU is back at `ret` with the same stack depth, i.e. the call returned
+    StepOver {
+        ret: Address,
+        sp: usize,
+    },
+    /// Runs until the stack is shallower than `sp`
+    StepOut {
+        sp: usize,
+    },
+}
+
+/// Breakpoints, watchpoints and stepping on top of [`Cpu::tick`].
+///
+/// The debugger never stops the CPU by itself, it only decides when it should be paused.
+/// Whoever drives the CPU checks [`Debugger::is_paused`] before every [`Debugger::tick`], which
+/// is what [`crate::scheduler::Scheduler`] does once a debugger is attached.
+#[derive(Debug)]
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: BTreeMap<Address, Option<Condition>>,
+    watchpoints: BTreeMap<Address, Watch>,
+    break_on_draw: bool,
+    break_on_key_wait: bool,
+    last_break: Option<Break>,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger {
+            mode: Mode::Running,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            break_on_draw: false,
+            break_on_key_wait: false,
+            last_break: None,
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.stop(Break::Pause);
+    }
+
+    pub fn resume(&mut self) {
+        self.mode = Mode::Running;
+    }
+
+    /// Runs a single instruction
+    pub fn step(&mut self) {
+        self.mode = Mode::Step;
+    }
+
+    /// Runs a single instruction, or a whole subroutine if the next instruction is a `CALL`
+    pub fn step_over(&mut self) {
+        self.mode = Mode::StepOverNext;
+    }
+
+    /// Runs until the current subroutine returns. Outside of a subroutine this is the same as
+    /// [`Debugger::resume`].
+    pub fn step_out(&mut self) {
+        self.mode = Mode::StepOutNext;
+    }
+
+    /// Returns why the CPU was last paused, once
+    pub fn take_break(&mut self) -> Option<Break> {
+        self.last_break.take()
+    }
+
+    /// Pauses before the instruction at `addr` runs, if `condition` holds at that point
+    pub fn set_breakpoint(&mut self, addr: Address, condition: Option<Condition>) {
+        self.breakpoints.insert(addr, condition);
+    }
+
+    /// Returns whether there was a breakpoint at `addr`
+    pub fn remove_breakpoint(&mut self, addr: Address) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (Address, Option<Condition>)> + '_ {
+        self.breakpoints.iter().map(|(addr, cond)| (*addr, *cond))
+    }
+
+    /// Pauses after an instruction reads or writes the byte at `addr`
+    pub fn set_watchpoint(&mut self, addr: Address, watch: Watch) {
+        self.watchpoints.insert(addr, watch);
+    }
+
+    /// Returns whether there was a watchpoint at `addr`
+    pub fn remove_watchpoint(&mut self, addr: Address) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (Address, Watch)> + '_ {
+        self.watchpoints.iter().map(|(addr, watch)| (*addr, *watch))
+    }
+
+    /// Pauses after every `DRW`
+    pub fn set_break_on_draw(&mut self, enabled: bool) {
+        self.break_on_draw = enabled;
+    }
+
+    pub fn break_on_draw(&self) -> bool {
+        self.break_on_draw
+    }
+
+    /// Pauses after every `LD Vx, K`, before the CPU starts waiting for the key
+    pub fn set_break_on_key_wait(&mut self, enabled: bool) {
+        self.break_on_key_wait = enabled;
+    }
+
+    pub fn break_on_key_wait(&self) -> bool {
+        self.break_on_key_wait
+    }
+
+    /// Runs one instruction on `cpu`, pausing afterwards if any breakpoint was hit.
+    ///
+    /// This doesn't check whether the debugger is paused.
+    pub fn tick(&mut self, cpu: &mut Cpu) -> Result<StepOutcome, CpuError> {
+        let addr = cpu.registers.pc;
+        let instruction = cpu.instruction_at(addr);
+        let sp = cpu.registers.sp;
+        self.mode = match (self.mode, instruction) {
+            (Mode::StepOverNext, Some(Instruction::Call(_))) => Mode::StepOver {
+                ret: addr.wrapping_add(2),
+                sp,
+            },
+            (Mode::StepOverNext, _) => Mode::Step,
+            (Mode::StepOutNext, _) => Mode::StepOut { sp },
+            (mode, _) => mode,
+        };
+
+        let watching = !self.watchpoints.is_empty();
+        cpu.set_memory_tracing(watching);
+        let outcome = cpu.tick();
+        let accesses = cpu.take_memory_accesses();
+        cpu.set_memory_tracing(false);
+
+        let outcome = outcome?;
+        if outcome == StepOutcome::Executed {
+            if let Some(reason) = self.check(cpu, addr, instruction, &accesses) {
+                self.stop(reason);
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn check(
+        &self,
+        cpu: &Cpu,
+        addr: Address,
+        instruction: Option<Instruction>,
+        accesses: &[MemoryAccess],
+    ) -> Option<Break> {
+        let watched = accesses.iter().find(|access| {
+            self.watchpoints
+                .get(&access.addr)
+                .is_some_and(|watch| watch.matches(access.access))
+        });
+        if let Some(access) = watched {
+            return Some(Break::Watchpoint {
+                addr,
+                access: *access,
+            });
+        }
+
+        match instruction {
+            Some(Instruction::Drw { .. }) if self.break_on_draw => return Some(Break::Draw(addr)),
+            Some(Instruction::LdKey(_)) if self.break_on_key_wait => {
+                return Some(Break::KeyWait(addr))
+            }
+            _ => {}
+        }
+
+        let pc = cpu.registers.pc;
+        if let Some(condition) = self.breakpoints.get(&pc) {
+            if condition.is_none_or(|condition| condition.matches(cpu)) {
+                return Some(Break::Breakpoint(pc));
+            }
+        }
+
+        let sp = cpu.registers.sp;
+        let stepped = match self.mode {
+            Mode::Running | Mode::Paused => false,
+            Mode::Step | Mode::StepOverNext | Mode::StepOutNext => true,
+            Mode::StepOver { ret, sp: depth } => pc == ret && sp == depth,
+            Mode::StepOut { sp: depth } => sp < depth,
+        };
+        stepped.then_some(Break::Step)
+    }
+
+    fn stop(&mut self, reason: Break) {
+        self.mode = Mode::Paused;
+        self.last_break = Some(reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: CALL 0x208
+    // 202: LD I, 0x300
+    // 204: LD [I], V1
+    // 206: JP 0x206
+    // 208: ADD V1, 1
+    // 20A: RET
+    const ROM: [u8; 12] = [
+        0x22, 0x08, 0xA3, 0x00, 0xF1, 0x55, 0x12, 0x06, 0x71, 0x01, 0x00, 0xEE,
+    ];
+
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu) -> Option<Break> {
+        for _ in 0..100 {
+            debugger.tick(cpu).unwrap();
+            if debugger.is_paused() {
+                return debugger.take_break();
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_step() {
+        let mut cpu = Cpu::init(&ROM);
+        let mut debugger = Debugger::new();
+
+        debugger.step();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Step));
+        assert_eq!(cpu.registers.pc, 0x208);
+
+        debugger.step_out();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Step));
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut cpu = Cpu::init(&ROM);
+        let mut debugger = Debugger::new();
+
+        debugger.step_over();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Step));
+        assert_eq!(cpu.registers.pc, 0x202);
+        assert_eq!(cpu.registers.v[1], 1);
+
+        debugger.step_over();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Step));
+        assert_eq!(cpu.registers.pc, 0x204);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = Cpu::init(&ROM);
+        let mut debugger = Debugger::new();
+        debugger.set_breakpoint(0x20A, None);
+
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Breakpoint(0x20A)));
+        assert_eq!(cpu.registers.pc, 0x20A);
+
+        // Resuming runs the instruction under the breakpoint
+        debugger.resume();
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+
+        assert!(debugger.remove_breakpoint(0x20A));
+        assert!(!debugger.remove_breakpoint(0x20A));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut cpu = Cpu::init(&ROM);
+        let mut debugger = Debugger::new();
+        let condition = |value| Condition {
+            operand: Operand::V(1),
+            comparison: Comparison::Eq,
+            value,
+        };
+
+        debugger.set_breakpoint(0x20A, Some(condition(2)));
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+
+        let mut cpu = Cpu::init(&ROM);
+        debugger.set_breakpoint(0x20A, Some(condition(1)));
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Breakpoint(0x20A)));
+        assert_eq!(condition(1).to_string(), "V1 == 0x1");
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut cpu = Cpu::init(&ROM);
+        let mut debugger = Debugger::new();
+        debugger.set_watchpoint(0x301, Watch::Write);
+        debugger.set_watchpoint(0x300, Watch::Read);
+
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            Some(Break::Watchpoint {
+                addr: 0x204,
+                access: MemoryAccess {
+                    addr: 0x301,
+                    access: Access::Write
+                }
+            })
+        );
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn test_break_on_draw_and_key_wait() {
+        // DRW V0, V0, 1; LD V2, K
+        let mut cpu = Cpu::init(&[0xD0, 0x01, 0xF2, 0x0A]);
+        let mut debugger = Debugger::new();
+        debugger.set_break_on_draw(true);
+        debugger.set_break_on_key_wait(true);
+
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::Draw(0x200)));
+        debugger.resume();
+        assert_eq!(run(&mut debugger, &mut cpu), Some(Break::KeyWait(0x202)));
+        assert!(cpu.waiting_for_key());
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut debugger = Debugger::new();
+        assert!(!debugger.is_paused());
+
+        debugger.pause();
+        assert!(debugger.is_paused());
+        assert_eq!(debugger.take_break(), Some(Break::Pause));
+        assert_eq!(debugger.take_break(), None);
+
+        debugger.resume();
+        assert!(!debugger.is_paused());
+    }
+}
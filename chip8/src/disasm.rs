@@ -0,0 +1,320 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::data::{Address, OpCode};
+use crate::instruction::Instruction;
+use crate::memory::PROGRAM_START;
+use crate::platform::Platform;
+
+/// A single line of a disassembly listing
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Instruction {
+        addr: Address,
+        opcode: OpCode,
+        instruction: Instruction,
+    },
+    Data {
+        addr: Address,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Line {
+    pub fn addr(&self) -> Address {
+        match self {
+            Line::Instruction { addr, .. } | Line::Data { addr, .. } => *addr,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Instruction {
+                addr,
+                opcode,
+                instruction,
+            } => write!(f, "0x{:03X}  {:04X}  {}", addr, opcode, instruction),
+            Line::Data { addr, bytes } => {
+                let raw = bytes
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>();
+                let values = bytes
+                    .iter()
+                    .map(|b| format!("0x{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "0x{:03X}  {:<4}  DB {}", addr, raw, values)
+            }
+        }
+    }
+}
+
+/// Disassembles a ROM loaded at [`PROGRAM_START`] by decoding every word in order.
+///
+/// Words that don't decode to an instruction are emitted as data. `F000 NNNN` is only read as
+/// one instruction on [`Platform::XoChip`], like the CPU does.
+pub fn disassemble(rom: &[u8], platform: Platform) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(rom.len() / 2 + 1);
+
+    let mut offset = 0;
+    while offset < rom.len() {
+        let len = match decode_at(rom, offset, platform) {
+            Some(instruction) => instruction.size() as usize,
+            None => 2.min(rom.len() - offset),
+        };
+        let addr = PROGRAM_START + offset as Address;
+        lines.push(decode_line(addr, &rom[offset..offset + len], platform));
+        offset += len;
+    }
+
+    lines
+}
+
+/// Disassembles a ROM loaded at [`PROGRAM_START`] by following control flow from the entry point.
+///
+/// Only words reachable through jumps, calls and skips are decoded as instructions, everything
+/// else is emitted as data. Targets of `JP V0, addr` can't be known statically and aren't followed.
+pub fn disassemble_recursive(rom: &[u8], platform: Platform) -> Vec<Line> {
+    let end = PROGRAM_START as usize + rom.len();
+    let instruction_at = |addr: usize| -> Option<Instruction> {
+        if addr < PROGRAM_START as usize {
+            return None;
+        }
+        decode_at(rom, addr - PROGRAM_START as usize, platform)
+    };
+
+    let mut code = BTreeSet::new();
+    let mut pending = vec![PROGRAM_START as usize];
+    while let Some(addr) = pending.pop() {
+        if code.contains(&addr) {
+            continue;
+        }
+        let Some(instruction) = instruction_at(addr) else {
+            continue;
+        };
+        code.insert(addr);
+
+        let next = addr + instruction.size() as usize;
+        // Skips jump over `F000 NNNN` as a whole
+        let after_next = next + instruction_at(next).map_or(2, |i| i.size() as usize);
+        match instruction {
+            Instruction::Jp(target) => pending.push(target as usize),
+            Instruction::Call(target) => pending.extend([next, target as usize]),
+            Instruction::Ret | Instruction::JpV0(_) | Instruction::Exit => {}
+            Instruction::Se(..)
+            | Instruction::Sne(..)
+            | Instruction::SeReg(..)
+            | Instruction::SneReg(..)
+            | Instruction::Skp(_)
+            | Instruction::Sknp(_) => pending.extend([next, after_next]),
+            _ => pending.push(next),
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut addr = PROGRAM_START as usize;
+    while addr < end {
+        if code.contains(&addr) {
+            let len = instruction_at(addr).map_or(2, |i| i.size() as usize);
+            lines.push(decode_line(
+                addr as Address,
+                &rom_slice(rom, addr, len),
+                platform,
+            ));
+            addr += len;
+        } else {
+            // Split data into words to keep the listing aligned, stopping short of any instruction
+            let len = if addr + 2 <= end && !code.contains(&(addr + 1)) {
+                2
+            } else {
+                1
+            };
+            lines.push(Line::Data {
+                addr: addr as Address,
+                bytes: rom_slice(rom, addr, len),
+            });
+            addr += len;
+        }
+    }
+
+    lines
+}
+
+fn rom_slice(rom: &[u8], addr: usize, len: usize) -> Vec<u8> {
+    let offset = addr - PROGRAM_START as usize;
+    rom[offset..offset + len].to_vec()
+}
+
+/// Decodes the instruction at `offset` in the ROM, including the operand of `F000 NNNN` on
+/// XO-CHIP
+fn decode_at(rom: &[u8], offset: usize, platform: Platform) -> Option<Instruction> {
+    let word = |offset: usize| -> Option<OpCode> {
+        let bytes = rom.get(offset..offset + 2)?;
+        Some((bytes[0] as OpCode) << 8 | bytes[1] as OpCode)
+    };
+
+    let opcode = word(offset)?;
+    match word(offset + 2) {
+        Some(next) if platform >= Platform::XoChip => Instruction::decode_long(opcode, next).ok(),
+        _ => Instruction::decode_for(opcode, platform).ok(),
+    }
+}
+
+fn decode_line(addr: Address, bytes: &[u8], platform: Platform) -> Line {
+    match decode_at(bytes, 0, platform) {
+        Some(instruction) if instruction.size() as usize == bytes.len() => Line::Instruction {
+            addr,
+            opcode: (bytes[0] as OpCode) << 8 | bytes[1] as OpCode,
+            instruction,
+        },
+        _ => Line::Data {
+            addr,
+            bytes: bytes.to_vec(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let rom = [0x00, 0xE0, 0xA2, 0x2A, 0xFF, 0xFF, 0xAB];
+        let lines = disassemble(&rom, Platform::Chip8);
+
+        assert_eq!(
+            lines,
+            vec![
+                Line::Instruction {
+                    addr: 0x200,
+                    opcode: 0x00E0,
+                    instruction: Instruction::Cls
+                },
+                Line::Instruction {
+                    addr: 0x202,
+                    opcode: 0xA22A,
+                    instruction: Instruction::LdI(0x22A)
+                },
+                Line::Data {
+                    addr: 0x204,
+                    bytes: vec![0xFF, 0xFF]
+                },
+                Line::Data {
+                    addr: 0x206,
+                    bytes: vec![0xAB]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_recursive() {
+        let rom = [
+            0x22, 0x08, // 0x200: CALL 0x208
+            0x12, 0x0A, // 0x202: JP 0x20A
+            0xF0, 0x0F, // 0x204: data
+            0x00, 0xE0, // 0x206: data that happens to decode
+            0x00, 0xEE, // 0x208: RET
+            0x30, 0x01, // 0x20A: SE V0, 0x01
+            0x12, 0x0A, // 0x20C: JP 0x20A
+            0x12, 0x0A, // 0x20E: JP 0x20A
+        ];
+        let lines = disassemble_recursive(&rom, Platform::Chip8);
+
+        let code = lines
+            .iter()
+            .filter(|line| matches!(line, Line::Instruction { .. }))
+            .map(|line| line.addr())
+            .collect::<Vec<_>>();
+        assert_eq!(code, vec![0x200, 0x202, 0x208, 0x20A, 0x20C, 0x20E]);
+
+        let data = lines
+            .iter()
+            .filter(|line| matches!(line, Line::Data { .. }))
+            .map(|line| line.addr())
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![0x204, 0x206]);
+    }
+
+    #[test]
+    fn test_disassemble_long() {
+        let rom = [
+            0x30, 0x01, // 0x200: SE V0, 0x01
+            0xF0, 0x00, 0x12, 0x34, // 0x202: LD I, LONG 0x1234
+            0x00, 0xEE, // 0x206: RET
+        ];
+
+        let lines = disassemble(&rom, Platform::XoChip);
+        assert_eq!(
+            lines[1],
+            Line::Instruction {
+                addr: 0x202,
+                opcode: 0xF000,
+                instruction: Instruction::LdILong(0x1234)
+            }
+        );
+        assert_eq!(lines[2].addr(), 0x206);
+
+        let lines = disassemble_recursive(&rom, Platform::XoChip);
+        let code = lines.iter().map(|line| line.addr()).collect::<Vec<_>>();
+        assert_eq!(code, vec![0x200, 0x202, 0x206]);
+        assert!(lines
+            .iter()
+            .all(|line| matches!(line, Line::Instruction { .. })));
+
+        // Other platforms don't have the long form, the operand is the next word
+        let lines = disassemble(&rom, Platform::SuperChip);
+        let addrs = lines.iter().map(|line| line.addr()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![0x200, 0x202, 0x204, 0x206]);
+        assert_eq!(
+            lines[1],
+            Line::Data {
+                addr: 0x202,
+                bytes: vec![0xF0, 0x00]
+            }
+        );
+        assert!(matches!(lines[2], Line::Instruction { addr: 0x204, .. }));
+    }
+
+    #[test]
+    fn test_disassemble_platform() {
+        // HIGH; SCU 2; LD [I], V0-V1 (SUPER-CHIP flags); LD I, V3 (XO-CHIP plane)
+        let rom = [0x00, 0xFF, 0x00, 0xD2, 0xF1, 0x75, 0xF3, 0x01];
+        let mnemonics = |platform| {
+            disassemble(&rom, platform)
+                .iter()
+                .map(|line| line.to_string()[13..].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            mnemonics(Platform::Chip8),
+            ["SYS 0x0FF", "SYS 0x0D2", "DB 0xF1, 0x75", "DB 0xF3, 0x01"]
+        );
+        assert_eq!(
+            mnemonics(Platform::SuperChip)[..3],
+            ["HIGH", "SYS 0x0D2", "LD R, V1"]
+        );
+        assert_eq!(mnemonics(Platform::XoChip)[1], "SCU 2");
+    }
+
+    #[test]
+    fn test_line_display() {
+        let line = Line::Instruction {
+            addr: 0x200,
+            opcode: 0xF365,
+            instruction: Instruction::Load(3),
+        };
+        assert_eq!(line.to_string(), "0x200  F365  LD V3, [I]");
+
+        let line = Line::Data {
+            addr: 0x204,
+            bytes: vec![0xAB],
+        };
+        assert_eq!(line.to_string(), "0x204  AB    DB 0xAB");
+    }
+}
@@ -1,51 +1,252 @@
+use serde::{Deserialize, Serialize};
+
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_SIZE: usize = DISPLAY_HEIGHT * DISPLAY_WIDTH;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_SIZE: usize = HIRES_DISPLAY_HEIGHT * HIRES_DISPLAY_WIDTH;
+
+/// XO-CHIP has two bitplanes, giving 4 colours
+pub const PLANE_COUNT: usize = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Pixel {
     On,
     Off,
 }
 
+/// The screen, either in the 64x32 low resolution mode or the SCHIP 128x64 high resolution mode.
+///
+/// Drawing, clearing and scrolling only affect the selected bitplanes. Only the first plane is
+/// selected until an XO-CHIP program picks others with `FN01`.
+#[derive(Serialize, Deserialize)]
 pub struct Display {
-    pub pixels: [Pixel; DISPLAY_SIZE],
+    pub planes: [Vec<Pixel>; PLANE_COUNT],
+    hires: bool,
+    selected: u8,
 }
 
 impl Default for Display {
     fn default() -> Display {
         Display {
-            pixels: [Pixel::Off; DISPLAY_SIZE],
+            planes: std::array::from_fn(|_| vec![Pixel::Off; DISPLAY_SIZE]),
+            hires: false,
+            selected: 0b01,
         }
     }
 }
 
 impl Display {
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_WIDTH
+        } else {
+            DISPLAY_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
+        }
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between low and high resolution, clearing every plane
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        let size = self.width() * self.height();
+        for plane in self.planes.iter_mut() {
+            *plane = vec![Pixel::Off; size];
+        }
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling, as a bitmask
+    pub fn select_planes(&mut self, mask: u8) {
+        self.selected = mask & 0b11;
+    }
+
+    /// The number of planes currently selected
+    pub fn selected_planes(&self) -> usize {
+        self.selected.count_ones() as usize
+    }
+
+    /// The screen as seen with a single colour, a pixel is on when it's set in any plane
+    pub fn pixels(&self) -> Vec<Pixel> {
+        (0..self.planes[0].len())
+            .map(|i| {
+                if self.planes.iter().any(|plane| plane[i] == Pixel::On) {
+                    Pixel::On
+                } else {
+                    Pixel::Off
+                }
+            })
+            .collect()
+    }
+
+    /// The colour index of every pixel, with bit N set when the pixel is on in plane N
+    pub fn colors(&self) -> Vec<u8> {
+        (0..self.planes[0].len())
+            .map(|i| {
+                self.planes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, plane)| plane[i] == Pixel::On)
+                    .fold(0, |color, (n, _)| color | 1 << n)
+            })
+            .collect()
+    }
+
     pub fn clear(&mut self) {
-        self.pixels = [Pixel::Off; DISPLAY_SIZE];
+        for plane in self.selected_mut() {
+            plane.fill(Pixel::Off);
+        }
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+    /// XORs `sprite` onto the screen, returning whether any pixel was turned off.
+    ///
+    /// `sprite` holds one sprite of the same height for each selected plane, in plane order.
+    /// The starting position always wraps around the screen, `clip` controls whether the parts
+    /// of the sprite that go past the edges are dropped or wrapped to the other side.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, 1, clip)
+    }
+
+    /// Like [`Display::draw`], but for the 16x16 sprites of `DXY0`, stored as 2 bytes per row
+    pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, 2, clip)
+    }
+
+    /// Scrolls the screen down by `n` rows
+    pub fn scroll_down(&mut self, n: usize) {
+        let width = self.width();
+        let n = n.min(self.height());
+        for plane in self.selected_mut() {
+            plane.rotate_right(n * width);
+            plane[..n * width].fill(Pixel::Off);
+        }
+    }
+
+    /// Scrolls the screen up by `n` rows
+    pub fn scroll_up(&mut self, n: usize) {
+        let width = self.width();
+        let n = n.min(self.height());
+        for plane in self.selected_mut() {
+            plane.rotate_left(n * width);
+            let len = plane.len();
+            plane[len - n * width..].fill(Pixel::Off);
+        }
+    }
+
+    /// Scrolls the screen right by 4 pixels
+    pub fn scroll_right(&mut self) {
+        let width = self.width();
+        for plane in self.selected_mut() {
+            for row in plane.chunks_mut(width) {
+                row.rotate_right(4);
+                row[..4].fill(Pixel::Off);
+            }
+        }
+    }
+
+    /// Scrolls the screen left by 4 pixels
+    pub fn scroll_left(&mut self) {
+        let width = self.width();
+        for plane in self.selected_mut() {
+            for row in plane.chunks_mut(width) {
+                row.rotate_left(4);
+                row[width - 4..].fill(Pixel::Off);
+            }
+        }
+    }
+
+    fn selected_mut(&mut self) -> impl Iterator<Item = &mut Vec<Pixel>> {
+        let selected = self.selected;
+        self.planes
+            .iter_mut()
+            .enumerate()
+            .filter(move |(n, _)| selected & (1 << n) != 0)
+            .map(|(_, plane)| plane)
+    }
+
+    fn draw_planes(
+        &mut self,
+        x: usize,
+        y: usize,
+        mut sprite: &[u8],
+        bytes_per_row: usize,
+        clip: bool,
+    ) -> bool {
+        let count = self.selected_planes();
+        if count == 0 || sprite.is_empty() {
+            return false;
+        }
+
+        let (width, height) = (self.width(), self.height());
+        let len = sprite.len() / count;
         let mut collision = false;
-        for (j, &byte) in sprite.iter().enumerate() {
-            for i in 0..8 {
-                let x = (x + i) % DISPLAY_WIDTH;
-                let y = (y + j) % DISPLAY_HEIGHT;
-                let pixel = (byte >> (7 - i)) & 1;
-                let index = y * DISPLAY_WIDTH + x;
-                let old_pixel = self.pixels[index];
-                let new_pixel = xor_pixel(old_pixel, pixel);
-
-                self.pixels[index] = new_pixel;
-                if old_pixel == Pixel::On && new_pixel == Pixel::Off {
-                    collision = true;
-                }
+        for plane in 0..PLANE_COUNT {
+            if self.selected & (1 << plane) == 0 {
+                continue;
             }
+            let (data, rest) = sprite.split_at(len.min(sprite.len()));
+            let rows = data.chunks(bytes_per_row).map(|row| {
+                row.iter().fold(0u16, |acc, &byte| acc << 8 | byte as u16)
+                    << (8 * (bytes_per_row - row.len()))
+            });
+            let pixels = &mut self.planes[plane];
+            collision |= blit(
+                pixels,
+                (width, height),
+                (x, y),
+                8 * bytes_per_row,
+                rows,
+                clip,
+            );
+            sprite = rest;
         }
         collision
     }
 }
 
+fn blit(
+    pixels: &mut [Pixel],
+    (width, height): (usize, usize),
+    (x, y): (usize, usize),
+    sprite_width: usize,
+    rows: impl Iterator<Item = u16>,
+    clip: bool,
+) -> bool {
+    let (x, y) = (x % width, y % height);
+    let mut collision = false;
+    for (j, row) in rows.enumerate() {
+        for i in 0..sprite_width {
+            if clip && (x + i >= width || y + j >= height) {
+                continue;
+            }
+            let x = (x + i) % width;
+            let y = (y + j) % height;
+            let pixel = ((row >> (sprite_width - 1 - i)) & 1) as u8;
+            let index = y * width + x;
+            let old_pixel = pixels[index];
+            let new_pixel = xor_pixel(old_pixel, pixel);
+
+            pixels[index] = new_pixel;
+            if old_pixel == Pixel::On && new_pixel == Pixel::Off {
+                collision = true;
+            }
+        }
+    }
+    collision
+}
+
 fn xor_pixel(old: Pixel, new: u8) -> Pixel {
     debug_assert!(new < 2);
     match (old, new) {
@@ -63,10 +264,18 @@ mod tests {
 
     #[test]
     fn test_clear() {
-        let mut display = Display::default();
-        display.pixels = [Pixel::On; DISPLAY_SIZE];
+        let mut display = Display {
+            planes: [vec![Pixel::On; DISPLAY_SIZE], vec![Pixel::On; DISPLAY_SIZE]],
+            hires: false,
+            selected: 0b01,
+        };
+        display.clear();
+        assert_eq!(display.planes[0], [Pixel::Off; DISPLAY_SIZE]);
+        assert_eq!(display.planes[1], [Pixel::On; DISPLAY_SIZE]);
+
+        display.select_planes(0b11);
         display.clear();
-        assert_eq!(display.pixels, [Pixel::Off; DISPLAY_SIZE]);
+        assert_eq!(display.planes[1], [Pixel::Off; DISPLAY_SIZE]);
     }
 
     #[test]
@@ -87,7 +296,7 @@ mod tests {
     fn test_draw() {
         let mut display = Display::default();
         let sprite = [0b11110000, 0b00001111];
-        let collision = display.draw(0, 0, &sprite);
+        let collision = display.draw(0, 0, &sprite, false);
         let on_pixels = [
             (0, 0),
             (1, 0),
@@ -99,11 +308,11 @@ mod tests {
             (7, 1),
         ];
         for &(x, y) in on_pixels.iter() {
-            assert_eq!(display.pixels[y * DISPLAY_WIDTH + x], Pixel::On);
+            assert_eq!(display.planes[0][y * DISPLAY_WIDTH + x], Pixel::On);
         }
         assert!(!collision);
 
-        let collision = display.draw(0, 0, &sprite);
+        let collision = display.draw(0, 0, &sprite, false);
         let on_pixels = [
             (0, 0),
             (1, 0),
@@ -115,8 +324,137 @@ mod tests {
             (7, 1),
         ];
         for &(x, y) in on_pixels.iter() {
-            assert_eq!(display.pixels[y * DISPLAY_WIDTH + x], Pixel::Off);
+            assert_eq!(display.planes[0][y * DISPLAY_WIDTH + x], Pixel::Off);
         }
         assert!(collision);
     }
+
+    #[test]
+    fn test_draw_wrap() {
+        let mut display = Display::default();
+        let sprite = [0b11000000, 0b11000000];
+        display.draw(DISPLAY_WIDTH - 1, DISPLAY_HEIGHT - 1, &sprite, false);
+
+        let on_pixels = [
+            (DISPLAY_WIDTH - 1, DISPLAY_HEIGHT - 1),
+            (0, DISPLAY_HEIGHT - 1),
+            (DISPLAY_WIDTH - 1, 0),
+            (0, 0),
+        ];
+        for &(x, y) in on_pixels.iter() {
+            assert_eq!(display.planes[0][y * DISPLAY_WIDTH + x], Pixel::On);
+        }
+    }
+
+    #[test]
+    fn test_draw_clip() {
+        let mut display = Display::default();
+        let sprite = [0b11000000, 0b11000000];
+        display.draw(DISPLAY_WIDTH - 1, DISPLAY_HEIGHT - 1, &sprite, true);
+
+        let on_pixels = display.planes[0]
+            .iter()
+            .filter(|p| **p == Pixel::On)
+            .count();
+        assert_eq!(on_pixels, 1);
+        assert_eq!(display.planes[0][DISPLAY_SIZE - 1], Pixel::On);
+
+        // The starting position still wraps
+        let mut display = Display::default();
+        display.draw(DISPLAY_WIDTH, DISPLAY_HEIGHT, &sprite, true);
+        assert_eq!(display.planes[0][0], Pixel::On);
+        assert_eq!(display.planes[0][DISPLAY_WIDTH + 1], Pixel::On);
+    }
+
+    #[test]
+    fn test_set_hires() {
+        let mut display = Display::default();
+        display.draw(0, 0, &[0xFF], false);
+
+        display.set_hires(true);
+        assert_eq!(display.width(), HIRES_DISPLAY_WIDTH);
+        assert_eq!(display.height(), HIRES_DISPLAY_HEIGHT);
+        assert_eq!(display.planes[0], vec![Pixel::Off; HIRES_DISPLAY_SIZE]);
+
+        // Drawing wraps at the high resolution edges
+        display.draw(HIRES_DISPLAY_WIDTH - 1, 0, &[0b11000000], false);
+        assert_eq!(display.planes[0][HIRES_DISPLAY_WIDTH - 1], Pixel::On);
+        assert_eq!(display.planes[0][0], Pixel::On);
+
+        display.set_hires(false);
+        assert_eq!(display.planes[0], vec![Pixel::Off; DISPLAY_SIZE]);
+    }
+
+    #[test]
+    fn test_draw_large() {
+        let mut display = Display::default();
+        let mut sprite = [0; 32];
+        sprite[0] = 0x80;
+        sprite[31] = 0x01;
+        display.draw_large(0, 0, &sprite, false);
+
+        let on_pixels = display.planes[0]
+            .iter()
+            .filter(|p| **p == Pixel::On)
+            .count();
+        assert_eq!(on_pixels, 2);
+        assert_eq!(display.planes[0][0], Pixel::On);
+        assert_eq!(display.planes[0][15 * DISPLAY_WIDTH + 15], Pixel::On);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut display = Display::default();
+        display.draw(0, 0, &[0x80], false);
+
+        display.scroll_down(3);
+        assert_eq!(display.planes[0][3 * DISPLAY_WIDTH], Pixel::On);
+
+        display.scroll_right();
+        assert_eq!(display.planes[0][3 * DISPLAY_WIDTH + 4], Pixel::On);
+
+        display.scroll_left();
+        display.scroll_left();
+        let on_pixels = display.planes[0]
+            .iter()
+            .filter(|p| **p == Pixel::On)
+            .count();
+        assert_eq!(on_pixels, 0);
+    }
+
+    #[test]
+    fn test_draw_planes() {
+        let mut display = Display::default();
+        display.select_planes(0b11);
+
+        // One row for the first plane, then one for the second
+        display.draw(0, 0, &[0b1100_0000, 0b1010_0000], false);
+        assert_eq!(display.colors()[..3], [0b11, 0b01, 0b10]);
+        assert_eq!(
+            display.pixels()[..4],
+            [Pixel::On, Pixel::On, Pixel::On, Pixel::Off]
+        );
+
+        // Only the second plane
+        display.select_planes(0b10);
+        let collision = display.draw(0, 0, &[0b1000_0000], false);
+        assert!(collision);
+        assert_eq!(display.colors()[..3], [0b01, 0b01, 0b10]);
+
+        // No planes selected
+        display.select_planes(0b00);
+        assert!(!display.draw(0, 0, &[0xFF], false));
+        assert_eq!(display.colors()[..3], [0b01, 0b01, 0b10]);
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        let mut display = Display::default();
+        display.draw(0, 3, &[0x80], false);
+
+        display.scroll_up(3);
+        assert_eq!(display.planes[0][0], Pixel::On);
+        display.scroll_up(1);
+        assert!(display.planes[0].iter().all(|p| *p == Pixel::Off));
+    }
 }
@@ -0,0 +1,194 @@
+use std::fmt;
+
+use crate::asm::AsmError;
+use crate::data::{Address, OpCode};
+use crate::platform::Platform;
+
+/// A fault raised by the CPU while executing an instruction.
+///
+/// `addr` is always the address of the instruction that caused the fault.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuError {
+    InvalidOpcode {
+        addr: Address,
+        opcode: OpCode,
+    },
+    /// A `CALL` nested deeper than the stack of the platform allows
+    StackOverflow {
+        addr: Address,
+        depth: usize,
+    },
+    /// A `RET` outside of any subroutine
+    StackUnderflow {
+        addr: Address,
+    },
+    MemoryOutOfBounds {
+        addr: Address,
+        target: usize,
+    },
+    InvalidFontDigit {
+        addr: Address,
+        digit: u8,
+    },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::InvalidOpcode { addr, opcode } => {
+                write!(f, "invalid opcode 0x{:04X} at 0x{:03X}", opcode, addr)
+            }
+            CpuError::StackOverflow { addr, depth } => write!(
+                f,
+                "stack overflow at 0x{:03X}, calls can only nest {} deep",
+                addr, depth
+            ),
+            CpuError::StackUnderflow { addr } => write!(f, "stack underflow at 0x{:03X}", addr),
+            CpuError::MemoryOutOfBounds { addr, target } => write!(
+                f,
+                "out of bounds memory access to 0x{:X} at 0x{:03X}",
+                target, addr
+            ),
+            CpuError::InvalidFontDigit { addr, digit } => {
+                write!(f, "invalid font digit 0x{:02X} at 0x{:03X}", digit, addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// Returned when a save state can't be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateError {
+    /// The data doesn't start with a save state header
+    InvalidFormat,
+    /// The state was written by an incompatible version of the emulator
+    UnsupportedVersion { version: u16 },
+    /// The state was taken while running a different ROM
+    RomMismatch,
+    /// The header is valid but the state itself can't be decoded
+    Corrupt(String),
+    /// Loading a state would break the movie being recorded or replayed
+    MovieActive,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidFormat => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { version } => write!(
+                f,
+                "save state version {} is not supported (expected {})",
+                version,
+                crate::state::STATE_VERSION
+            ),
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::Corrupt(err) => write!(f, "save state is corrupt: {}", err),
+            StateError::MovieActive => write!(
+                f,
+                "save states can't be loaded while a movie is recording or replaying"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Returned when a movie can't be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum MovieError {
+    /// The data doesn't start with a movie header
+    InvalidFormat,
+    /// The movie was written by an incompatible version of the emulator
+    UnsupportedVersion { version: u16 },
+    /// The movie was recorded with a different ROM
+    RomMismatch,
+    /// The header is valid but the movie itself can't be decoded
+    Corrupt(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::InvalidFormat => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion { version } => write!(
+                f,
+                "movie version {} is not supported (expected {})",
+                version,
+                crate::movie::MOVIE_VERSION
+            ),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::Corrupt(err) => write!(f, "movie is corrupt: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// Returned when a file can't be used as a ROM
+#[derive(Clone, Debug, PartialEq)]
+pub enum RomError {
+    Empty,
+    /// The ROM doesn't fit in memory after the interpreter area
+    TooLarge {
+        size: usize,
+        max: usize,
+        platform: Platform,
+    },
+    /// The data starts with the signature of another file format
+    NotChip8 {
+        format: &'static str,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge {
+                size,
+                max,
+                platform,
+            } => write!(
+                f,
+                "ROM is {} bytes but at most {} bytes fit in {:?} memory",
+                size, max, platform
+            ),
+            RomError::NotChip8 { format } => {
+                write!(f, "file looks like {}, not a CHIP-8 ROM", format)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// Returned when an Octo cartridge can't be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum CartridgeError {
+    /// The file isn't a GIF image or is damaged
+    InvalidGif(String),
+    /// The image doesn't carry an Octo program
+    NoProgram(String),
+    /// The program doesn't compile
+    Compile(AsmError),
+    Rom(RomError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::InvalidGif(err) => write!(f, "invalid GIF: {}", err),
+            CartridgeError::NoProgram(err) => {
+                write!(f, "not an Octo cartridge: {}", err)
+            }
+            CartridgeError::Compile(err) => {
+                write!(f, "could not compile the cartridge program: {}", err)
+            }
+            CartridgeError::Rom(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
@@ -0,0 +1,497 @@
+//! A GDB remote serial protocol server for debugging ROMs with standard tooling.
+//!
+//! The stub works on a [`System`] with a [`Debugger`] attached and is polled by whoever runs the
+//! system, so the program keeps running at its usual pace between commands. Registers are
+//! numbered as follows, multi-byte values are little endian:
+//!
+//! | Number | Register           | Size    |
+//! |--------|--------------------|---------|
+//! | 0-15   | V0-VF              | 1 byte  |
+//! | 16     | I                  | 2 bytes |
+//! | 17     | PC                 | 2 bytes |
+//! | 18     | SP                 | 1 byte  |
+//! | 19-34  | Stack, read only   | 2 bytes |
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::cpu::MAX_STACK_DEPTH;
+use crate::data::Address;
+use crate::debugger::{Break, Debugger, Watch};
+use crate::error::CpuError;
+use crate::system::System;
+
+const STACK_REGISTERS: usize = MAX_STACK_DEPTH;
+const REGISTER_COUNT: usize = 19 + STACK_REGISTERS;
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// A connection to a GDB client
+pub struct GdbStub {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    /// Whether the client is waiting for a stop reply after `c` or `s`
+    running: bool,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            buffer: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Handles the packets received since the last call and reports the CPU stopping.
+    ///
+    /// Returns `false` once the client detached or killed the program.
+    pub fn poll(&mut self, system: &mut System) -> io::Result<bool> {
+        if system.debugger().is_none() {
+            return Err(io::Error::other("The system has no debugger attached"));
+        }
+
+        let mut chunk = [0; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            let Some(packet) = packet else {
+                // Ctrl-C in the client
+                debugger(system).pause();
+                continue;
+            };
+            match self.handle(system, &packet) {
+                Some(Reply::Packet(reply)) => self.send(&reply)?,
+                Some(Reply::Close) => {
+                    self.send("OK")?;
+                    return Ok(false);
+                }
+                None => self.running = true,
+            }
+        }
+
+        if self.running && (system.paused() || system.fault().is_some() || system.exited()) {
+            self.running = false;
+            let reply = stop_reply(system);
+            self.send(&reply)?;
+        }
+        Ok(true)
+    }
+
+    /// Takes the next packet or interrupt out of the buffer, acknowledging packets as they
+    /// arrive. Interrupts are returned as `Some(None)`.
+    fn next_packet(&mut self) -> io::Result<Option<Option<String>>> {
+        loop {
+            let Some(&first) = self.buffer.first() else {
+                return Ok(None);
+            };
+            match first {
+                INTERRUPT => {
+                    self.buffer.remove(0);
+                    return Ok(Some(None));
+                }
+                b'$' => {}
+                // Acknowledgements and noise
+                _ => {
+                    self.buffer.remove(0);
+                    continue;
+                }
+            }
+
+            let Some(end) = self.buffer.iter().position(|&b| b == b'#') else {
+                return Ok(None);
+            };
+            if self.buffer.len() < end + 3 {
+                return Ok(None);
+            }
+
+            let packet = self.buffer[1..end].to_vec();
+            let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            self.buffer.drain(..end + 3);
+
+            if checksum != Some(checksum_of(&packet)) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(Some(String::from_utf8_lossy(&packet).into_owned())));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        // The socket is non-blocking, but replies are small enough to never fill the buffer
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(packet.as_bytes());
+        self.stream.set_nonblocking(true)?;
+        result
+    }
+
+    fn handle(&mut self, system: &mut System, packet: &str) -> Option<Reply> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => stop_reply(system),
+            "g" => (0..REGISTER_COUNT)
+                .map(|n| hex(&read_register(system, n)))
+                .collect(),
+            "G" => write_registers(system, args),
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTER_COUNT => hex(&read_register(system, n)),
+                _ => error(),
+            },
+            "P" => write_register(system, args),
+            "m" => read_memory(system, args),
+            "M" => write_memory(system, args),
+            "Z" | "z" => breakpoint(system, command == "Z", args),
+            "c" => {
+                debugger(system).resume();
+                return None;
+            }
+            "s" => {
+                debugger(system).step();
+                return None;
+            }
+            "H" => ok(),
+            "k" => return Some(Reply::Close),
+            "D" => {
+                // Let the program carry on without the client
+                debugger(system).resume();
+                return Some(Reply::Close);
+            }
+            "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "q" if args == "fThreadInfo" => "m1".to_string(),
+            "q" if args == "sThreadInfo" => "l".to_string(),
+            // Anything else is unsupported, which GDB expects to be an empty reply
+            _ => String::new(),
+        };
+        Some(Reply::Packet(reply))
+    }
+}
+
+enum Reply {
+    Packet(String),
+    /// Acknowledge and end the session
+    Close,
+}
+
+fn debugger(system: &mut System) -> &mut Debugger {
+    system
+        .debugger_mut()
+        .expect("Debugger is checked when polling")
+}
+
+fn stop_reply(system: &mut System) -> String {
+    if system.exited() {
+        return "W00".to_string();
+    }
+
+    let signal = match system.fault() {
+        Some(CpuError::InvalidOpcode { .. }) => SIGILL,
+        Some(_) => SIGSEGV,
+        None => match debugger(system).take_break() {
+            Some(Break::Pause) => SIGINT,
+            _ => SIGTRAP,
+        },
+    };
+    format!("S{:02x}", signal)
+}
+
+fn read_register(system: &System, n: usize) -> Vec<u8> {
+    let registers = &system.cpu().registers;
+    match n {
+        0..=15 => vec![registers.v[n]],
+        16 => registers.i.to_le_bytes().to_vec(),
+        17 => registers.pc.to_le_bytes().to_vec(),
+        18 => vec![registers.sp as u8],
+        _ => {
+            let stack = system.cpu().stack();
+            let addr = stack.get(n - 19).copied().unwrap_or(0);
+            addr.to_le_bytes().to_vec()
+        }
+    }
+}
+
+/// Writes a register from its little endian bytes, the stack can't be written
+fn set_register(system: &mut System, n: usize, bytes: &[u8]) -> bool {
+    let stack_depth = system.cpu().stack_depth();
+    let registers = &mut system.cpu_mut().registers;
+    match (n, bytes) {
+        (0..=15, [value]) => registers.v[n] = *value,
+        (16, [low, high]) => registers.i = u16::from_le_bytes([*low, *high]),
+        (17, [low, high]) => registers.pc = u16::from_le_bytes([*low, *high]),
+        (18, [value]) if (*value as usize) <= stack_depth => registers.sp = *value as usize,
+        (19.., _) => {}
+        _ => return false,
+    }
+    true
+}
+
+fn write_register(system: &mut System, args: &str) -> String {
+    let parsed = args.split_once('=').and_then(|(n, value)| {
+        let n = usize::from_str_radix(n, 16).ok()?;
+        Some((n, unhex(value)?))
+    });
+    match parsed {
+        Some((n, bytes)) if n < REGISTER_COUNT && set_register(system, n, &bytes) => ok(),
+        _ => error(),
+    }
+}
+
+fn write_registers(system: &mut System, args: &str) -> String {
+    let Some(bytes) = unhex(args) else {
+        return error();
+    };
+
+    let mut offset = 0;
+    for n in 0..REGISTER_COUNT {
+        let size = read_register(system, n).len();
+        let Some(value) = bytes.get(offset..offset + size) else {
+            break;
+        };
+        if !set_register(system, n, value) {
+            return error();
+        }
+        offset += size;
+    }
+    ok()
+}
+
+/// Parses `addr,len`
+fn parse_range(args: &str) -> Option<(Address, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        Address::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn read_memory(system: &System, args: &str) -> String {
+    match parse_range(args) {
+        Some((addr, len)) => match system.cpu().read_memory(addr, len) {
+            bytes if bytes.is_empty() && len > 0 => error(),
+            bytes => hex(&bytes),
+        },
+        None => error(),
+    }
+}
+
+fn write_memory(system: &mut System, args: &str) -> String {
+    let parsed = args.split_once(':').and_then(|(range, data)| {
+        let (addr, len) = parse_range(range)?;
+        let data = unhex(data)?;
+        (data.len() == len).then_some((addr, data))
+    });
+    match parsed {
+        Some((addr, data)) if system.cpu_mut().write_memory(addr, &data) => ok(),
+        _ => error(),
+    }
+}
+
+/// Handles `Z`/`z` packets: `0` and `1` are breakpoints, `2`, `3` and `4` are write, read and
+/// access watchpoints on a range of bytes
+fn breakpoint(system: &mut System, insert: bool, args: &str) -> String {
+    let mut parts = args.splitn(3, ',');
+    let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+        return error();
+    };
+    let (Ok(addr), Ok(len)) = (
+        Address::from_str_radix(addr, 16),
+        Address::from_str_radix(len, 16),
+    ) else {
+        return error();
+    };
+
+    let debugger = debugger(system);
+    let watch = match kind {
+        "0" | "1" => {
+            if insert {
+                debugger.set_breakpoint(addr, None);
+            } else {
+                debugger.remove_breakpoint(addr);
+            }
+            return ok();
+        }
+        "2" => Watch::Write,
+        "3" => Watch::Read,
+        "4" => Watch::ReadWrite,
+        _ => return String::new(),
+    };
+    for offset in 0..len.max(1) {
+        let addr = addr.wrapping_add(offset);
+        if insert {
+            debugger.set_watchpoint(addr, watch);
+        } else {
+            debugger.remove_watchpoint(addr);
+        }
+    }
+    ok()
+}
+
+fn ok() -> String {
+    "OK".to_string()
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::platform::Platform;
+    use crate::rom::RomImage;
+    use crate::system::SystemBuilder;
+
+    // 200: LD V1, 0x42
+    // 202: CALL 0x208
+    // 204: JP 0x204
+    // 208: LD I, 0x300
+    // 20A: RET
+    const ROM: [u8; 12] = [
+        0x61, 0x42, 0x22, 0x08, 0x12, 0x04, 0x00, 0x00, 0xA3, 0x00, 0x00, 0xEE,
+    ];
+
+    struct Session {
+        client: TcpStream,
+        stub: GdbStub,
+        system: System,
+    }
+
+    impl Session {
+        fn new() -> Session {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let (stream, _) = listener.accept().unwrap();
+
+            let mut debugger = Debugger::new();
+            debugger.pause();
+            let system = SystemBuilder::new(&RomImage::new(ROM, Platform::Chip8).unwrap())
+                .debugger(debugger)
+                .run();
+
+            Session {
+                client,
+                stub: GdbStub::new(stream).unwrap(),
+                system,
+            }
+        }
+
+        /// Sends a packet and runs the system until the stub replies
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.client.write_all(packet.as_bytes()).unwrap();
+
+            let mut received = Vec::new();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                self.stub.poll(&mut self.system).unwrap();
+                self.system.run_frame();
+
+                let mut chunk = [0; 1024];
+                if let Ok(len) = self.client.read(&mut chunk) {
+                    received.extend_from_slice(&chunk[..len]);
+                }
+                let text = String::from_utf8_lossy(&received).into_owned();
+                if let Some(reply) = text.strip_prefix("+$") {
+                    if let Some((data, checksum)) = reply.split_once('#') {
+                        if checksum.len() == 2 {
+                            assert_eq!(
+                                u8::from_str_radix(checksum, 16).unwrap(),
+                                checksum_of(data.as_bytes())
+                            );
+                            return data.to_string();
+                        }
+                    }
+                }
+            }
+            panic!("No reply to {}", data);
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut session = Session::new();
+        assert_eq!(session.send("qSupported:swbreak+"), "PacketSize=1000");
+        assert_eq!(session.send("?"), "S02");
+
+        let registers = session.send("g");
+        assert_eq!(registers.len(), (16 + 2 + 2 + 1 + 2 * STACK_REGISTERS) * 2);
+        assert_eq!(&registers[32..40], "00000002");
+        assert_eq!(session.send("p11"), "0002");
+
+        assert_eq!(session.send("P3=7f"), "OK");
+        assert_eq!(session.system.cpu().registers.v[3], 0x7F);
+        assert_eq!(session.send("P23=zz"), "E01");
+
+        assert_eq!(session.send("m200,4"), "61422208");
+        assert_eq!(session.send("M300,2:abcd"), "OK");
+        assert_eq!(session.system.cpu().read_memory(0x300, 2), [0xAB, 0xCD]);
+        assert_eq!(session.send("m1000,1"), "E01");
+        // Lengths past the end of memory are cut short instead of overflowing
+        let rest = session.send("m200,ffffffffffffffff");
+        assert_eq!(rest.len(), (0x1000 - 0x200) * 2);
+        assert!(rest.starts_with("61422208"));
+        assert_eq!(session.send("vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        let mut session = Session::new();
+
+        assert_eq!(session.send("s"), "S05");
+        assert_eq!(session.system.cpu().registers.pc, 0x202);
+        assert_eq!(session.system.cpu().registers.v[1], 0x42);
+
+        assert_eq!(session.send("Z0,20a,2"), "OK");
+        assert_eq!(session.send("c"), "S05");
+        assert_eq!(session.system.cpu().registers.pc, 0x20A);
+        assert_eq!(session.send("p13"), "0402");
+
+        assert_eq!(session.send("z0,20a,2"), "OK");
+        assert_eq!(session.send("Z2,300,1"), "OK");
+        assert_eq!(session.send("M208,2:f1"), "E01");
+        assert_eq!(session.send("D"), "OK");
+        assert!(!session.system.paused());
+    }
+}
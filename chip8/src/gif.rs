@@ -0,0 +1,233 @@
+//! Just enough of a GIF decoder to read the data hidden in Octo cartridges, see
+//! [`crate::cartridge`]. Colours are ignored, only the palette index of every pixel is kept.
+
+const HEADERS: [&[u8]; 2] = [b"GIF87a", b"GIF89a"];
+const MAX_CODE_SIZE: u32 = 12;
+/// Pixels across all frames, far more than any cartridge needs: a cartridge holding a full
+/// 64 KiB XO-CHIP program's source takes well under 4 million
+const MAX_PIXELS: usize = 1 << 24;
+
+/// Reads the palette indices of every frame of a GIF, one frame after the other in raster
+/// order
+pub(crate) fn decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if !HEADERS.contains(&reader.take(6)?) {
+        return Err("missing GIF header".into());
+    }
+
+    // Logical screen descriptor
+    reader.take(4)?;
+    let flags = reader.byte()?;
+    reader.take(2)?;
+    if flags & 0x80 != 0 {
+        reader.take(color_table_size(flags))?;
+    }
+
+    let mut pixels = Vec::new();
+    loop {
+        match reader.byte()? {
+            // Extension, e.g. animation timing or comments
+            0x21 => {
+                reader.byte()?;
+                reader.sub_blocks()?;
+            }
+            // Image
+            0x2C => {
+                reader.take(4)?;
+                let width = reader.u16()? as usize;
+                let height = reader.u16()? as usize;
+                let flags = reader.byte()?;
+                if flags & 0x80 != 0 {
+                    reader.take(color_table_size(flags))?;
+                }
+                let min_code_size = reader.byte()? as u32;
+                if !(2..MAX_CODE_SIZE).contains(&min_code_size) {
+                    return Err(format!("invalid LZW code size {}", min_code_size));
+                }
+
+                // Checked before anything is allocated, the sizes come straight from the file
+                let size = width * height;
+                if size > MAX_PIXELS - pixels.len() {
+                    return Err(format!("{}x{} frame is too large", width, height));
+                }
+
+                let mut frame = decompress(&reader.sub_blocks()?, min_code_size, size)?;
+                frame.resize(size, 0);
+                if flags & 0x40 != 0 {
+                    frame = deinterlace(&frame, width, height);
+                }
+                pixels.extend(frame);
+            }
+            // Trailer
+            0x3B => return Ok(pixels),
+            block => return Err(format!("unknown GIF block 0x{:02X}", block)),
+        }
+    }
+}
+
+fn color_table_size(flags: u8) -> usize {
+    3 << ((flags & 0x07) + 1)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("unexpected end of GIF")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Joins a chain of length prefixed blocks, ended by an empty one
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        loop {
+            let len = self.byte()? as usize;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(self.take(len)?);
+        }
+    }
+}
+
+/// Decodes GIF flavoured LZW: codes are packed least significant bit first and grow up to 12
+/// bits as the table fills. Output past `limit` bytes is dropped.
+fn decompress(data: &[u8], min_code_size: u32, limit: usize) -> Result<Vec<u8>, String> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    // Every entry is the previous entry it extends plus one new byte
+    let mut prefixes: Vec<Option<u16>> = (0..clear + 2).map(|_| None).collect();
+    let mut suffixes: Vec<u8> = (0..clear + 2).map(|code| code as u8).collect();
+    let mut code_size = min_code_size + 1;
+    let mut previous: Option<u16> = None;
+
+    let mut out = Vec::new();
+    let mut entry = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for &byte in data {
+        bits |= (byte as u32) << bit_count;
+        bit_count += 8;
+
+        while bit_count >= code_size {
+            let code = (bits & ((1 << code_size) - 1)) as u16;
+            bits >>= code_size;
+            bit_count -= code_size;
+
+            if code == clear {
+                prefixes.truncate(clear as usize + 2);
+                suffixes.truncate(clear as usize + 2);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end {
+                return Ok(out);
+            }
+
+            let next = prefixes.len() as u16;
+            entry.clear();
+            let mut current = match (code, previous) {
+                (code, _) if code < next => Some(code),
+                // The one code that can be used before it's in the table: the previous entry
+                // plus its own first byte
+                (code, Some(previous)) if code == next => Some(previous),
+                _ => return Err(format!("invalid LZW code {}", code)),
+            };
+            while let Some(code) = current {
+                entry.push(suffixes[code as usize]);
+                current = prefixes[code as usize];
+            }
+            entry.reverse();
+            if code == next {
+                entry.push(entry[0]);
+            }
+            out.extend_from_slice(&entry);
+            if out.len() >= limit {
+                out.truncate(limit);
+                return Ok(out);
+            }
+
+            if let Some(previous) = previous {
+                if prefixes.len() < 1 << MAX_CODE_SIZE {
+                    prefixes.push(Some(previous));
+                    suffixes.push(entry[0]);
+                    if prefixes.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                        code_size += 1;
+                    }
+                }
+            }
+            previous = Some(code);
+        }
+    }
+    Ok(out)
+}
+
+/// Puts the rows of an interlaced image, stored in four passes, back in order
+fn deinterlace(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let rows = [(0, 8), (4, 8), (2, 4), (1, 2)]
+        .into_iter()
+        .flat_map(|(start, step)| (start..height).step_by(step));
+    let mut out = vec![0; pixels.len()];
+    for (row, source) in rows.zip(pixels.chunks(width)) {
+        out[row * width..(row + 1) * width].copy_from_slice(source);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // A 3x2 image with a 4 colour palette: 0 1 2 / 3 0 1
+        #[rustfmt::skip]
+        let gif = [
+            b'G', b'I', b'F', b'8', b'9', b'a', 3, 0, 2, 0, 0x81, 0, 0,
+            0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 255, 0,
+            0x2C, 0, 0, 0, 0, 3, 0, 2, 0, 0,
+            // Clear, 0, 1, 2, 3, then 0 1 (code 6) and end with codes grown to 4 bits
+            2, 3, 0x44, 0x34, 0x56, 0,
+            0x3B,
+        ];
+        assert_eq!(decode(&gif), Ok(vec![0, 1, 2, 3, 0, 1]));
+        assert_eq!(decode(b"GIF89a"), Err("unexpected end of GIF".into()));
+        assert_eq!(decode(b"\x89PNG\r\n"), Err("missing GIF header".into()));
+
+        // A tiny file claiming a 65535x65535 frame
+        let mut huge = gif;
+        huge[30..34].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(decode(&huge), Err("65535x65535 frame is too large".into()));
+
+        // Data beyond the declared size is ignored: the same codes in a 2x2 frame
+        let mut small = gif;
+        small[30..34].copy_from_slice(&[2, 0, 2, 0]);
+        assert_eq!(decode(&small), Ok(vec![0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_deinterlace() {
+        let rows = (0..10).collect::<Vec<u8>>();
+        // Rows 0 and 8, then 4, then 2 and 6, then the odd ones
+        let interlaced = [0, 8, 4, 2, 6, 1, 3, 5, 7, 9];
+        assert_eq!(deinterlace(&interlaced, 1, 10), rows);
+    }
+}
@@ -0,0 +1,538 @@
+use std::fmt;
+
+use crate::data::{Address, OpCode, Register};
+use crate::platform::Platform;
+
+/// A decoded CHIP-8 instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0; CLS
+    Cls,
+    /// 00EE; RET
+    Ret,
+    /// 00CN; SCD nibble
+    Scd(u8),
+    /// 00DN; SCU nibble
+    Scu(u8),
+    /// 00FB; SCR
+    Scr,
+    /// 00FC; SCL
+    Scl,
+    /// 00FD; EXIT
+    Exit,
+    /// 00FE; LOW
+    Low,
+    /// 00FF; HIGH
+    High,
+    /// 0NNN; SYS addr
+    Sys(Address),
+    /// 1NNN; JP addr
+    Jp(Address),
+    /// 2NNN; CALL addr
+    Call(Address),
+    /// 3XNN; SE Vx, byte
+    Se(Register, u8),
+    /// 4XNN; SNE Vx, byte
+    Sne(Register, u8),
+    /// 5XY0; SE Vx, Vy
+    SeReg(Register, Register),
+    /// 5XY2; SAVE Vx, Vy
+    SaveRange(Register, Register),
+    /// 5XY3; LOAD Vx, Vy
+    LoadRange(Register, Register),
+    /// 6XNN; LD Vx, byte
+    Ld(Register, u8),
+    /// 7XNN; ADD Vx, byte
+    Add(Register, u8),
+    /// 8XY0; LD Vx, Vy
+    LdReg(Register, Register),
+    /// 8XY1; OR Vx, Vy
+    Or(Register, Register),
+    /// 8XY2; AND Vx, Vy
+    And(Register, Register),
+    /// 8XY3; XOR Vx, Vy
+    Xor(Register, Register),
+    /// 8XY4; ADD Vx, Vy
+    AddReg(Register, Register),
+    /// 8XY5; SUB Vx, Vy
+    Sub(Register, Register),
+    /// 8XY6; SHR Vx {, Vy}
+    Shr(Register, Register),
+    /// 8XY7; SUBN Vx, Vy
+    Subn(Register, Register),
+    /// 8XYE; SHL Vx {, Vy}
+    Shl(Register, Register),
+    /// 9XY0; SNE Vx, Vy
+    SneReg(Register, Register),
+    /// ANNN; LD I, addr
+    LdI(Address),
+    /// BNNN; JP V0, addr
+    JpV0(Address),
+    /// CXNN; RND Vx, byte
+    Rnd(Register, u8),
+    /// DXYN; DRW Vx, Vy, nibble. `DXY0` draws a 16x16 sprite on SUPER-CHIP
+    Drw { x: Register, y: Register, n: u8 },
+    /// EX9E; SKP Vx
+    Skp(Register),
+    /// EXA1; SKNP Vx
+    Sknp(Register),
+    /// F000 NNNN; LD I, LONG addr
+    LdILong(Address),
+    /// FN01; PLANE nibble
+    Plane(u8),
+    /// F002; AUDIO
+    Audio,
+    /// FX07; LD Vx, DT
+    LdFromDelay(Register),
+    /// FX0A; LD Vx, K
+    LdKey(Register),
+    /// FX15; LD DT, Vx
+    LdDelay(Register),
+    /// FX18; LD ST, Vx
+    LdSound(Register),
+    /// FX1E; ADD I, Vx
+    AddI(Register),
+    /// FX29; LD F, Vx
+    LdFont(Register),
+    /// FX30; LD HF, Vx
+    LdHiFont(Register),
+    /// FX33; LD B, Vx
+    LdBcd(Register),
+    /// FX3A; LD PITCH, Vx
+    LdPitch(Register),
+    /// FX55; LD [I], Vx
+    Store(Register),
+    /// FX65; LD Vx, [I]
+    Load(Register),
+    /// FX75; LD R, Vx
+    StoreFlags(Register),
+    /// FX85; LD Vx, R
+    LoadFlags(Register),
+}
+
+/// Returned by [`Instruction::decode`] for words that aren't valid instructions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: OpCode,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid opcode 0x{:04X}", self.opcode)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Instruction {
+    /// The first word of `F000 NNNN`, the only instruction that is 4 bytes long
+    pub const LONG_PREFIX: OpCode = 0xF000;
+
+    /// Decodes a single word.
+    ///
+    /// [`Instruction::LONG_PREFIX`] isn't a complete instruction and is rejected, use
+    /// [`Instruction::decode_long`] when the following word is available.
+    pub fn decode(opcode: OpCode) -> Result<Instruction, DecodeError> {
+        let x = ((opcode & 0x0F00) >> 8) as Register;
+        let y = ((opcode & 0x00F0) >> 4) as Register;
+        let n = (opcode & 0x000F) as u8;
+        let nn = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+
+        let instruction = match (opcode & 0xF000) >> 12 {
+            0x0 => match opcode {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                0x00C0..=0x00CF => Instruction::Scd(n),
+                0x00D0..=0x00DF => Instruction::Scu(n),
+                0x00FB => Instruction::Scr,
+                0x00FC => Instruction::Scl,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::Low,
+                0x00FF => Instruction::High,
+                _ => Instruction::Sys(nnn),
+            },
+            0x1 => Instruction::Jp(nnn),
+            0x2 => Instruction::Call(nnn),
+            0x3 => Instruction::Se(x, nn),
+            0x4 => Instruction::Sne(x, nn),
+            0x5 => match n {
+                0x0 => Instruction::SeReg(x, y),
+                0x2 => Instruction::SaveRange(x, y),
+                0x3 => Instruction::LoadRange(x, y),
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x6 => Instruction::Ld(x, nn),
+            0x7 => Instruction::Add(x, nn),
+            0x8 => match n {
+                0x0 => Instruction::LdReg(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::AddReg(x, y),
+                0x5 => Instruction::Sub(x, y),
+                0x6 => Instruction::Shr(x, y),
+                0x7 => Instruction::Subn(x, y),
+                0xE => Instruction::Shl(x, y),
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x9 if n == 0x0 => Instruction::SneReg(x, y),
+            0xA => Instruction::LdI(nnn),
+            0xB => Instruction::JpV0(nnn),
+            0xC => Instruction::Rnd(x, nn),
+            0xD => Instruction::Drw { x, y, n },
+            0xE => match nn {
+                0x9E => Instruction::Skp(x),
+                0xA1 => Instruction::Sknp(x),
+                _ => return Err(DecodeError { opcode }),
+            },
+            0xF => match nn {
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0x0 => Instruction::Audio,
+                0x07 => Instruction::LdFromDelay(x),
+                0x0A => Instruction::LdKey(x),
+                0x15 => Instruction::LdDelay(x),
+                0x18 => Instruction::LdSound(x),
+                0x1E => Instruction::AddI(x),
+                0x29 => Instruction::LdFont(x),
+                0x30 => Instruction::LdHiFont(x),
+                0x33 => Instruction::LdBcd(x),
+                0x3A => Instruction::LdPitch(x),
+                0x55 => Instruction::Store(x),
+                0x65 => Instruction::Load(x),
+                0x75 => Instruction::StoreFlags(x),
+                0x85 => Instruction::LoadFlags(x),
+                _ => return Err(DecodeError { opcode }),
+            },
+            _ => return Err(DecodeError { opcode }),
+        };
+
+        Ok(instruction)
+    }
+
+    /// Decodes a single word the way `platform` sees it. Instructions from later platforms are
+    /// invalid, except for those in the `0NNN` range, which earlier platforms take for machine
+    /// code routines.
+    pub fn decode_for(opcode: OpCode, platform: Platform) -> Result<Instruction, DecodeError> {
+        match Instruction::decode(opcode)? {
+            instruction if instruction.platform() <= platform => Ok(instruction),
+            _ if opcode & 0xF000 == 0 => Ok(Instruction::Sys(opcode)),
+            _ => Err(DecodeError { opcode }),
+        }
+    }
+
+    /// Decodes `opcode`, taking `next` as the operand if `opcode` is [`Instruction::LONG_PREFIX`]
+    pub fn decode_long(opcode: OpCode, next: OpCode) -> Result<Instruction, DecodeError> {
+        if opcode == Instruction::LONG_PREFIX {
+            Ok(Instruction::LdILong(next))
+        } else {
+            Instruction::decode(opcode)
+        }
+    }
+
+    /// The length of the instruction in bytes
+    pub fn size(&self) -> Address {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Encodes the instruction as big-endian bytes, 4 of them for `F000 NNNN`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            Instruction::LdILong(addr) => {
+                let mut bytes = Instruction::LONG_PREFIX.to_be_bytes().to_vec();
+                bytes.extend(addr.to_be_bytes());
+                bytes
+            }
+            _ => self.encode().to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Encodes the first word of the instruction, see [`Instruction::to_bytes`] for `F000 NNNN`
+    pub fn encode(&self) -> OpCode {
+        fn xnn(prefix: OpCode, x: Register, nn: u8) -> OpCode {
+            prefix << 12 | (x as OpCode & 0xF) << 8 | nn as OpCode
+        }
+        fn xyn(prefix: OpCode, x: Register, y: Register, n: u8) -> OpCode {
+            xnn(prefix, x, (y & 0xF) << 4 | (n & 0xF))
+        }
+        fn nnn(prefix: OpCode, addr: Address) -> OpCode {
+            prefix << 12 | (addr & 0x0FFF)
+        }
+
+        match *self {
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Scd(n) => 0x00C0 | (n as OpCode & 0xF),
+            Instruction::Scu(n) => 0x00D0 | (n as OpCode & 0xF),
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Sys(addr) => nnn(0x0, addr),
+            Instruction::Jp(addr) => nnn(0x1, addr),
+            Instruction::Call(addr) => nnn(0x2, addr),
+            Instruction::Se(x, nn) => xnn(0x3, x, nn),
+            Instruction::Sne(x, nn) => xnn(0x4, x, nn),
+            Instruction::SeReg(x, y) => xyn(0x5, x, y, 0x0),
+            Instruction::SaveRange(x, y) => xyn(0x5, x, y, 0x2),
+            Instruction::LoadRange(x, y) => xyn(0x5, x, y, 0x3),
+            Instruction::Ld(x, nn) => xnn(0x6, x, nn),
+            Instruction::Add(x, nn) => xnn(0x7, x, nn),
+            Instruction::LdReg(x, y) => xyn(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xyn(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xyn(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xyn(0x8, x, y, 0x3),
+            Instruction::AddReg(x, y) => xyn(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xyn(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xyn(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xyn(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xyn(0x8, x, y, 0xE),
+            Instruction::SneReg(x, y) => xyn(0x9, x, y, 0x0),
+            Instruction::LdI(addr) => nnn(0xA, addr),
+            Instruction::JpV0(addr) => nnn(0xB, addr),
+            Instruction::Rnd(x, nn) => xnn(0xC, x, nn),
+            Instruction::Drw { x, y, n } => xyn(0xD, x, y, n),
+            Instruction::Skp(x) => xnn(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xnn(0xE, x, 0xA1),
+            Instruction::LdILong(_) => Instruction::LONG_PREFIX,
+            Instruction::Plane(n) => xnn(0xF, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdFromDelay(x) => xnn(0xF, x, 0x07),
+            Instruction::LdKey(x) => xnn(0xF, x, 0x0A),
+            Instruction::LdDelay(x) => xnn(0xF, x, 0x15),
+            Instruction::LdSound(x) => xnn(0xF, x, 0x18),
+            Instruction::AddI(x) => xnn(0xF, x, 0x1E),
+            Instruction::LdFont(x) => xnn(0xF, x, 0x29),
+            Instruction::LdHiFont(x) => xnn(0xF, x, 0x30),
+            Instruction::LdBcd(x) => xnn(0xF, x, 0x33),
+            Instruction::LdPitch(x) => xnn(0xF, x, 0x3A),
+            Instruction::Store(x) => xnn(0xF, x, 0x55),
+            Instruction::Load(x) => xnn(0xF, x, 0x65),
+            Instruction::StoreFlags(x) => xnn(0xF, x, 0x75),
+            Instruction::LoadFlags(x) => xnn(0xF, x, 0x85),
+        }
+    }
+
+    /// The first platform that supports this instruction
+    pub fn platform(&self) -> Platform {
+        match self {
+            Instruction::Scd(_)
+            | Instruction::Scr
+            | Instruction::Scl
+            | Instruction::Exit
+            | Instruction::Low
+            | Instruction::High
+            | Instruction::LdHiFont(_)
+            | Instruction::StoreFlags(_)
+            | Instruction::LoadFlags(_) => Platform::SuperChip,
+            Instruction::Scu(_)
+            | Instruction::SaveRange(..)
+            | Instruction::LoadRange(..)
+            | Instruction::LdILong(_)
+            | Instruction::Plane(_)
+            | Instruction::Audio
+            | Instruction::LdPitch(_) => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scu(n) => write!(f, "SCU {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Sys(addr) => write!(f, "SYS 0x{:03X}", addr),
+            Instruction::Jp(addr) => write!(f, "JP 0x{:03X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL 0x{:03X}", addr),
+            Instruction::Se(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Instruction::Sne(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::Ld(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Instruction::Add(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Instruction::LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I, 0x{:03X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0, 0x{:03X}", addr),
+            Instruction::Rnd(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong(addr) => write!(f, "LD I, LONG 0x{:04X}", addr),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdFromDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdSound(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFont(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHiFont(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdBcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::LdPitch(x) => write!(f, "LD PITCH, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Ok(Instruction::Cls));
+        assert_eq!(Instruction::decode(0x00EE), Ok(Instruction::Ret));
+        assert_eq!(Instruction::decode(0x0123), Ok(Instruction::Sys(0x123)));
+        assert_eq!(Instruction::decode(0x1234), Ok(Instruction::Jp(0x234)));
+        assert_eq!(Instruction::decode(0x3A12), Ok(Instruction::Se(0xA, 0x12)));
+        assert_eq!(
+            Instruction::decode(0x8AB4),
+            Ok(Instruction::AddReg(0xA, 0xB))
+        );
+        assert_eq!(
+            Instruction::decode(0xD125),
+            Ok(Instruction::Drw { x: 1, y: 2, n: 5 })
+        );
+        assert_eq!(Instruction::decode(0xF365), Ok(Instruction::Load(3)));
+        assert_eq!(Instruction::decode(0x00C4), Ok(Instruction::Scd(4)));
+        assert_eq!(Instruction::decode(0x00FF), Ok(Instruction::High));
+        assert_eq!(Instruction::decode(0xF275), Ok(Instruction::StoreFlags(2)));
+        assert_eq!(
+            Instruction::decode(0x5AB2),
+            Ok(Instruction::SaveRange(0xA, 0xB))
+        );
+        assert_eq!(Instruction::decode(0xF201), Ok(Instruction::Plane(2)));
+        assert_eq!(Instruction::decode(0xF002), Ok(Instruction::Audio));
+    }
+
+    #[test]
+    fn test_decode_for() {
+        assert_eq!(
+            Instruction::decode_for(0x00FF, Platform::Chip8),
+            Ok(Instruction::Sys(0x0FF))
+        );
+        assert_eq!(
+            Instruction::decode_for(0x00C4, Platform::Chip8),
+            Ok(Instruction::Sys(0x0C4))
+        );
+        assert_eq!(
+            Instruction::decode_for(0x00FF, Platform::SuperChip),
+            Ok(Instruction::High)
+        );
+        assert_eq!(
+            Instruction::decode_for(0x00D4, Platform::SuperChip),
+            Ok(Instruction::Sys(0x0D4))
+        );
+        assert_eq!(
+            Instruction::decode_for(0x00D4, Platform::XoChip),
+            Ok(Instruction::Scu(4))
+        );
+        assert_eq!(
+            Instruction::decode_for(0xF030, Platform::Chip8),
+            Err(DecodeError { opcode: 0xF030 })
+        );
+        assert_eq!(
+            Instruction::decode_for(0xF201, Platform::SuperChip),
+            Err(DecodeError { opcode: 0xF201 })
+        );
+    }
+
+    #[test]
+    fn test_decode_long() {
+        assert_eq!(
+            Instruction::decode(Instruction::LONG_PREFIX),
+            Err(DecodeError { opcode: 0xF000 })
+        );
+        assert_eq!(
+            Instruction::decode_long(0xF000, 0x1234),
+            Ok(Instruction::LdILong(0x1234))
+        );
+        assert_eq!(
+            Instruction::decode_long(0x00E0, 0x1234),
+            Ok(Instruction::Cls)
+        );
+
+        let instruction = Instruction::LdILong(0xABCD);
+        assert_eq!(instruction.size(), 4);
+        assert_eq!(instruction.to_bytes(), vec![0xF0, 0x00, 0xAB, 0xCD]);
+        assert_eq!(Instruction::Cls.to_bytes(), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        for opcode in [
+            0x5001, 0x8008, 0x800F, 0x9001, 0xE000, 0xEF9F, 0xF099, 0xF102,
+        ] {
+            assert_eq!(Instruction::decode(opcode), Err(DecodeError { opcode }));
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::Cls.to_string(), "CLS");
+        assert_eq!(Instruction::Jp(0x234).to_string(), "JP 0x234");
+        assert_eq!(Instruction::Se(0xA, 0x12).to_string(), "SE VA, 0x12");
+        assert_eq!(Instruction::Shr(0x1, 0x2).to_string(), "SHR V1, V2");
+        assert_eq!(
+            Instruction::Drw { x: 1, y: 2, n: 5 }.to_string(),
+            "DRW V1, V2, 5"
+        );
+        assert_eq!(Instruction::Store(0xF).to_string(), "LD [I], VF");
+        assert_eq!(Instruction::Load(0x3).to_string(), "LD V3, [I]");
+        assert_eq!(Instruction::Scd(0xA).to_string(), "SCD 10");
+        assert_eq!(Instruction::LdHiFont(0x1).to_string(), "LD HF, V1");
+        assert_eq!(Instruction::LoadFlags(0x7).to_string(), "LD V7, R");
+        assert_eq!(
+            Instruction::LdILong(0x1234).to_string(),
+            "LD I, LONG 0x1234"
+        );
+        assert_eq!(Instruction::SaveRange(0x1, 0x4).to_string(), "SAVE V1, V4");
+    }
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(Instruction::Cls.platform(), Platform::Chip8);
+        assert_eq!(
+            Instruction::Drw { x: 0, y: 0, n: 0 }.platform(),
+            Platform::Chip8
+        );
+        assert_eq!(Instruction::High.platform(), Platform::SuperChip);
+        assert_eq!(Instruction::LdHiFont(0).platform(), Platform::SuperChip);
+        assert_eq!(Instruction::Plane(1).platform(), Platform::XoChip);
+        assert_eq!(Instruction::LdILong(0).platform(), Platform::XoChip);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for opcode in 0x0000..=0xFFFF {
+            if let Ok(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{:?}", instruction);
+            }
+        }
+    }
+}
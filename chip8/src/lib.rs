@@ -1,8 +1,27 @@
-mod cpu;
-mod data;
+pub mod asm;
+pub mod audio;
+pub mod cartridge;
+pub mod cpu;
+pub mod data;
+pub mod database;
+pub mod debugger;
+pub mod disasm;
 pub mod display;
+pub mod error;
+pub mod gdb;
+mod gif;
+pub mod instruction;
 mod keyboard;
 mod memory;
+pub mod movie;
+pub mod octo;
+pub mod platform;
+pub mod quirks;
 mod registers;
+pub mod rewind;
+pub mod rom;
+pub mod scheduler;
+pub mod state;
 pub mod system;
 mod timer;
+pub mod trace;
@@ -1,22 +1,78 @@
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+
 use super::data::Address;
 
-const MEMORY_SIZE: usize = 0x1000;
+const MEMORY_SIZE: usize = 0x10000;
+const DEFAULT_SIZE: usize = 0x1000;
 pub const PROGRAM_START: Address = 0x200;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A single byte read or written by a program, recorded while tracing is enabled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: Address,
+    pub access: Access,
+}
+
+/// The full 64 KiB address space, of which only the first `size` bytes are addressable
+#[derive(Serialize, Deserialize)]
 pub struct MemoryBus {
-    memory: [u8; MEMORY_SIZE],
+    memory: Vec<u8>,
+    size: usize,
+    /// Accesses since the last [`MemoryBus::take_accesses`], `None` unless tracing is enabled.
+    /// Reads only borrow the bus, hence the `RefCell`.
+    #[serde(skip)]
+    accesses: Option<RefCell<Vec<MemoryAccess>>>,
 }
 
 impl Default for MemoryBus {
     fn default() -> MemoryBus {
         MemoryBus {
-            memory: [0; MEMORY_SIZE],
+            memory: vec![0; MEMORY_SIZE],
+            size: DEFAULT_SIZE,
+            accesses: None,
         }
     }
 }
 
 impl MemoryBus {
+    /// Limits the addressable memory to `size` bytes, at most 64 KiB
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size.min(MEMORY_SIZE);
+    }
+
+    pub fn in_bounds(&self, addr: usize, len: usize) -> bool {
+        addr + len <= self.size
+    }
+
+    /// Starts or stops recording the reads and writes made through the bus
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.accesses = enabled.then(Default::default);
+    }
+
+    /// Returns and clears the accesses recorded since the last call
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        self.accesses
+            .as_mut()
+            .map(|accesses| std::mem::take(accesses.get_mut()))
+            .unwrap_or_default()
+    }
+
+    fn record(&self, addr: Address, access: Access) {
+        if let Some(accesses) = &self.accesses {
+            accesses.borrow_mut().push(MemoryAccess { addr, access });
+        }
+    }
+
     pub fn write(&mut self, addr: Address, data: u8) {
+        self.record(addr, Access::Write);
         self.memory[addr as usize] = data;
     }
 
@@ -27,6 +83,12 @@ impl MemoryBus {
     }
 
     pub fn read(&self, addr: Address) -> u8 {
+        self.record(addr, Access::Read);
+        self.memory[addr as usize]
+    }
+
+    /// Reads a byte without recording the access, for instruction fetches and inspection
+    pub fn peek(&self, addr: Address) -> u8 {
         self.memory[addr as usize]
     }
 
@@ -71,6 +133,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_in_bounds() {
+        let mem = MemoryBus::default();
+
+        assert!(mem.in_bounds(0x000, 1));
+        assert!(mem.in_bounds(0xFFF, 1));
+        assert!(mem.in_bounds(0xFF0, 16));
+        assert!(!mem.in_bounds(0xFFF, 2));
+        assert!(!mem.in_bounds(0x1000, 0x1));
+    }
+
+    #[test]
+    fn test_set_size() {
+        let mut mem = MemoryBus::default();
+
+        mem.set_size(0x10000);
+        assert!(mem.in_bounds(0x1000, 1));
+        assert!(mem.in_bounds(0xFFFE, 2));
+        assert!(!mem.in_bounds(0xFFFF, 2));
+
+        mem.set_size(0x20000);
+        assert!(!mem.in_bounds(0x10000, 1));
+    }
+
+    #[test]
+    fn test_tracing() {
+        let mut mem = MemoryBus::default();
+        mem.write(0x300, 1);
+        assert!(mem.take_accesses().is_empty());
+
+        mem.set_tracing(true);
+        mem.write(0x300, 1);
+        mem.read(0x301);
+        mem.peek(0x302);
+        assert_eq!(
+            mem.take_accesses(),
+            [
+                MemoryAccess {
+                    addr: 0x300,
+                    access: Access::Write
+                },
+                MemoryAccess {
+                    addr: 0x301,
+                    access: Access::Read
+                },
+            ]
+        );
+        assert!(mem.take_accesses().is_empty());
+    }
+
     #[test]
     fn test_read() {
         let mut mem = MemoryBus::default();
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::MovieError;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::state::RomHash;
+
+/// Bumped whenever the layout of a movie changes
+pub const MOVIE_VERSION: u16 = 2;
+const MAGIC: [u8; 4] = *b"C8MV";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u16,
+    rom_hash: RomHash,
+}
+
+/// A recorded run: everything needed to start the machine the same way, plus the keypad state
+/// of every frame.
+///
+/// Replaying a movie with [`crate::system::SystemBuilder::replay`] reproduces the run exactly.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Movie {
+    #[serde(skip)]
+    pub rom_hash: RomHash,
+    /// Seed of the random number generator behind `CXNN`
+    pub seed: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    /// Nested subroutine calls allowed, see [`crate::cpu::Cpu::stack_depth`]
+    pub stack_depth: usize,
+    /// The keys held during each frame, bit N for key N
+    pub frames: Vec<u16>,
+}
+
+impl Movie {
+    /// The number of recorded frames
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Serializes the movie behind a header identifying the format version and the ROM
+    pub fn encode(&self) -> Vec<u8> {
+        let header = Header {
+            magic: MAGIC,
+            version: MOVIE_VERSION,
+            rom_hash: self.rom_hash,
+        };
+        let mut bytes = bincode::serialize(&header).expect("Unable to serialize header");
+        bincode::serialize_into(&mut bytes, self).expect("Unable to serialize movie");
+        bytes
+    }
+
+    /// Reads a movie written by [`Movie::encode`], refusing movies recorded with another ROM
+    pub fn decode(bytes: &[u8], rom_hash: RomHash) -> Result<Movie, MovieError> {
+        let mut reader = bytes;
+        let header: Header =
+            bincode::deserialize_from(&mut reader).map_err(|_| MovieError::InvalidFormat)?;
+        if header.magic != MAGIC {
+            return Err(MovieError::InvalidFormat);
+        }
+        if header.version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion {
+                version: header.version,
+            });
+        }
+        if header.rom_hash != rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+
+        let movie: Movie = bincode::deserialize_from(&mut reader)
+            .map_err(|err| MovieError::Corrupt(err.to_string()))?;
+        Ok(Movie {
+            rom_hash: header.rom_hash,
+            ..movie
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Pixel;
+    use crate::error::{CpuError, StateError};
+    use crate::rom::RomImage;
+    use crate::state::rom_hash;
+    use crate::system::{System, SystemBuilder};
+
+    // LD I, sprite; loop: RND V0, 0x3F; RND V1, 0x1F; LD V2, 5; SKNP V2; DRW V0, V1, 1; JP loop
+    const ROM: [u8; 18] = [
+        0xA2, 0x10, 0xC0, 0x3F, 0xC1, 0x1F, 0x62, 0x05, 0xE2, 0xA1, 0xD0, 0x11, 0x12, 0x02, 0x00,
+        0x00, 0x80, 0x00,
+    ];
+
+    fn run(system: &mut System, frames: u64) -> Vec<Pixel> {
+        while system.frame() < frames {
+            // Draws random dots while key 5 is held
+            match system.frame() {
+                5 => system.key_down(0x5),
+                20 => system.key_up(0x5),
+                _ => {}
+            }
+            system.run_frame();
+        }
+        system.pixels()
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut recording = SystemBuilder::new(&RomImage::new(ROM, Platform::Chip8).unwrap())
+            .seed(7)
+            .record_movie()
+            .run();
+        let recorded = run(&mut recording, 30);
+        assert!(recorded.contains(&Pixel::On));
+
+        let movie = recording.movie().unwrap();
+        assert_eq!(movie.seed, 7);
+        assert_eq!(movie.len(), 30);
+        assert_eq!(movie.frames[4], 0);
+        assert_eq!(movie.frames[5], 1 << 5);
+
+        let movie = Movie::decode(&movie.encode(), rom_hash(&ROM)).unwrap();
+        let mut replay = SystemBuilder::new(&RomImage::new(ROM, Platform::Chip8).unwrap())
+            .seed(99)
+            .replay(movie)
+            .run();
+        assert!(replay.replaying());
+        assert_eq!(run(&mut replay, 30), recorded);
+        assert!(!replay.replaying());
+    }
+
+    #[test]
+    fn test_replay_stack_depth() {
+        // Waits for key 5, then nests 14 calls deep, past the 12 CHIP-8 normally allows
+        #[rustfmt::skip]
+        const ROM: [u8; 20] = [
+            0x62, 0x05, // 0x200: LD V2, 5
+            0xE2, 0xA1, // 0x202: SKNP V2
+            0x12, 0x0A, // 0x204: JP 0x20A
+            0x12, 0x02, // 0x206: JP 0x202
+            0x00, 0x00,
+            0x33, 0x0E, // 0x20A: SE V3, 14
+            0x12, 0x10, // 0x20C: JP 0x210
+            0x12, 0x0E, // 0x20E: JP 0x20E
+            0x73, 0x01, // 0x210: ADD V3, 1
+            0x22, 0x0A, // 0x212: CALL 0x20A
+        ];
+        let rom = RomImage::new(ROM, Platform::Chip8).unwrap();
+        let run = |system: &mut System| {
+            system.key_down(0x5);
+            for _ in 0..10 {
+                system.run_frame();
+            }
+        };
+        let mut plain = SystemBuilder::new(&rom).run();
+        run(&mut plain);
+        assert!(matches!(
+            plain.fault(),
+            Some(CpuError::StackOverflow { depth: 12, .. })
+        ));
+
+        let mut recording = SystemBuilder::new(&rom)
+            .stack_depth(16)
+            .record_movie()
+            .run();
+        run(&mut recording);
+        assert_eq!(recording.fault(), None);
+
+        let movie = recording.movie().unwrap();
+        assert_eq!(movie.stack_depth, 16);
+        let movie = Movie::decode(&movie.encode(), rom_hash(&ROM)).unwrap();
+        let mut replay = SystemBuilder::new(&rom).replay(movie).run();
+        assert_eq!(replay.cpu().stack_depth(), 16);
+        for _ in 0..10 {
+            replay.run_frame();
+        }
+        assert_eq!(replay.fault(), None);
+        assert_eq!(replay.cpu().stack().len(), 14);
+    }
+
+    #[test]
+    fn test_replay_tap_between_frames() {
+        // LD V0, K; loop: JP loop
+        const ROM: [u8; 4] = [0xF0, 0x0A, 0x12, 0x02];
+        let rom = RomImage::new(ROM, Platform::Chip8).unwrap();
+        let mut recording = SystemBuilder::new(&rom).record_movie().run();
+        recording.run_frame();
+        assert!(recording.cpu().waiting_for_key());
+
+        // A tap while paused only reaches the program once the next frame starts
+        recording.key_down(0x7);
+        recording.key_down(0x3);
+        recording.key_up(0x7);
+        recording.key_up(0x3);
+        assert!(recording.cpu().waiting_for_key());
+        recording.run_frame();
+        recording.run_frame();
+        assert_eq!(recording.cpu().registers.v[0], 0x3);
+
+        let movie = recording.movie().unwrap();
+        assert_eq!(movie.frames, [0, 1 << 7 | 1 << 3, 0]);
+        let mut replay = SystemBuilder::new(&rom).replay(movie).run();
+        for _ in 0..3 {
+            replay.run_frame();
+        }
+        assert_eq!(replay.cpu().registers.v[0], 0x3);
+        assert_eq!(replay.cpu().keypad(), 0);
+    }
+
+    #[test]
+    fn test_rewind_truncates_recording() {
+        let mut system = SystemBuilder::new(&RomImage::new(ROM, Platform::Chip8).unwrap())
+            .record_movie()
+            .run();
+        run(&mut system, 10);
+        system.rewind(4).unwrap();
+        system.run_frame();
+        assert_eq!(system.movie().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn test_load_state_refused() {
+        let rom = RomImage::new(ROM, Platform::Chip8).unwrap();
+        let mut recording = SystemBuilder::new(&rom).record_movie().run();
+        run(&mut recording, 10);
+        let state = recording.save_state();
+        run(&mut recording, 20);
+        assert_eq!(recording.load_state(&state), Err(StateError::MovieActive));
+        assert_eq!(recording.frame(), 20);
+        assert_eq!(recording.movie().unwrap().len(), 20);
+
+        let movie = recording.movie().unwrap();
+        let mut replay = SystemBuilder::new(&rom).replay(movie).run();
+        run(&mut replay, 5);
+        assert_eq!(replay.load_state(&state), Err(StateError::MovieActive));
+
+        // Once the replay is over the keypad is live again, like without a movie
+        run(&mut replay, 20);
+        assert_eq!(replay.load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let movie = SystemBuilder::new(&RomImage::new(ROM, Platform::Chip8).unwrap())
+            .record_movie()
+            .run()
+            .movie()
+            .unwrap();
+        let bytes = movie.encode();
+
+        assert_eq!(
+            Movie::decode(&bytes, rom_hash(b"other rom")),
+            Err(MovieError::RomMismatch)
+        );
+        assert_eq!(
+            Movie::decode(b"not a movie", rom_hash(&ROM)),
+            Err(MovieError::InvalidFormat)
+        );
+
+        let mut bytes = bytes;
+        bytes[4] = 0xFF;
+        assert_eq!(
+            Movie::decode(&bytes, rom_hash(&ROM)),
+            Err(MovieError::UnsupportedVersion { version: 0x00FF })
+        );
+    }
+}
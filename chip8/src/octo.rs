@@ -0,0 +1,1256 @@
+//! A compiler for Octo, the structured assembly language of the Octo IDE. Octo cartridges carry
+//! their program as Octo source, see [`crate::cartridge`].
+//!
+//! ```text
+//! # Comments start with a hash
+//! :const SPEED 2
+//! :alias x v1
+//!
+//! : main
+//!     i := ball
+//!     loop
+//!         sprite x x 4
+//!         x += SPEED
+//!         if x >= 32 then x := 0
+//!     again
+//!
+//! : ball 0x60 0xF0 0xF0 0x60
+//! ```
+//!
+//! Everything in the Octo manual is supported apart from `:string` and `:stringmode`. Like in
+//! Octo, `:calc` expressions are evaluated right to left and every operator binds equally.
+
+use std::collections::HashMap;
+
+use crate::asm::{AsmError, Span};
+use crate::data::{Address, Register};
+use crate::instruction::Instruction;
+use crate::memory::PROGRAM_START;
+
+/// Upper bound on macro expansions, which is only reached by macros that expand themselves
+const MAX_EXPANSIONS: usize = 100_000;
+/// Upper bound on parentheses and unary operators around a value in `:calc`, so hostile
+/// cartridges can't overflow the stack
+const MAX_EXPRESSION_DEPTH: usize = 256;
+const MEMORY_SIZE: usize = 0x10000;
+const VF: Register = 0xF;
+
+/// Compiles Octo source into a ROM image loaded at [`PROGRAM_START`].
+///
+/// The program has to define `main`, which a jump at the start of the ROM leads to unless `main`
+/// comes first.
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut compiler = Compiler::new(tokenize(source));
+    while let Some(token) = compiler.next() {
+        compiler.statement(token)?;
+    }
+    compiler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+/// Octo tokens are separated by whitespace, comments run from `#` to the end of the line
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (line, text) in source.lines().enumerate() {
+        let text = text.split('#').next().unwrap_or_default();
+        let mut start = None;
+        for (column, c) in text.char_indices().chain([(text.len(), ' ')]) {
+            match (start, c.is_whitespace()) {
+                (None, false) => start = Some(column),
+                (Some(from), true) => {
+                    tokens.push(Token {
+                        text: text[from..column].to_string(),
+                        span: Span {
+                            line: line + 1,
+                            column: from + 1,
+                            len: column - from,
+                        },
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+fn error(token: &Token, message: impl Into<String>) -> AsmError {
+    AsmError {
+        file: None,
+        span: token.span,
+        message: message.into(),
+    }
+}
+
+/// How a reference to a label that isn't defined yet gets filled in
+#[derive(Copy, Clone, Debug)]
+enum Fixup {
+    /// The low 12 bits of an instruction
+    Address,
+    /// A whole 16 bit word
+    Long,
+    /// The immediates of the two `vx := NN` of `:unpack`, `None` for `:unpack long`
+    Unpack(Option<u8>),
+}
+
+/// The right hand side of a comparison
+#[derive(Copy, Clone, Debug)]
+enum Operand {
+    Register(Register),
+    Byte(u8),
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Program bytes, starting at [`PROGRAM_START`]
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, Register>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// Uses of labels that aren't defined yet
+    pending: HashMap<String, Vec<(usize, Fixup, Token)>>,
+    /// Start and `while` jumps of the open loops
+    loops: Vec<(usize, Vec<usize>, Token)>,
+    /// Jumps of the open `if ... begin` blocks
+    branches: Vec<(usize, Token)>,
+    /// Whether the ROM starts with a jump to `main`
+    main_jump: bool,
+}
+
+impl Compiler {
+    fn new(tokens: Vec<Token>) -> Compiler {
+        Compiler {
+            tokens,
+            pos: 0,
+            // Room for the jump to main
+            rom: vec![0; 2],
+            here: PROGRAM_START as usize + 2,
+            labels: HashMap::new(),
+            constants: HashMap::from([
+                ("PI".to_string(), std::f64::consts::PI),
+                ("E".to_string(), std::f64::consts::E),
+            ]),
+            aliases: HashMap::from([("unpack-hi".to_string(), 0), ("unpack-lo".to_string(), 1)]),
+            macros: HashMap::new(),
+            expansions: 0,
+            pending: HashMap::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            main_jump: true,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// The token following `after`, e.g. the operand of an instruction
+    fn expect(&mut self, after: &Token) -> Result<Token, AsmError> {
+        self.next()
+            .ok_or_else(|| error(after, format!("expected something after '{}'", after.text)))
+    }
+
+    fn expect_text(&mut self, after: &Token, text: &str) -> Result<Token, AsmError> {
+        let token = self.expect(after)?;
+        if token.text != text {
+            return Err(error(&token, format!("expected '{}'", text)));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        let simple = match token.text.as_str() {
+            ";" | "return" => Some(Instruction::Ret),
+            "clear" => Some(Instruction::Cls),
+            "hires" => Some(Instruction::High),
+            "lores" => Some(Instruction::Low),
+            "scroll-right" => Some(Instruction::Scr),
+            "scroll-left" => Some(Instruction::Scl),
+            "exit" => Some(Instruction::Exit),
+            "audio" => Some(Instruction::Audio),
+            _ => None,
+        };
+        if let Some(instruction) = simple {
+            return self.emit(&instruction.to_bytes(), &token);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect(&token)?;
+                if name.text == "main"
+                    && self.main_jump
+                    && self.here == PROGRAM_START as usize + 2
+                    && self.rom.len() == 2
+                {
+                    // No need to jump over nothing
+                    self.rom.clear();
+                    self.here = PROGRAM_START as usize;
+                    self.main_jump = false;
+                }
+                self.define_label(&name, self.here)
+            }
+            ":next" => {
+                let name = self.expect(&token)?;
+                self.define_label(&name, self.here + 1)
+            }
+            ":alias" => {
+                let name = self.expect(&token)?;
+                // Aliases can be moved to another register
+                if !self.aliases.contains_key(&name.text) {
+                    self.check_name(&name)?;
+                }
+                let value = self.expect(&name)?;
+                let register = if value.text == "{" {
+                    let index = self.calc(&value)?;
+                    if !(0.0..16.0).contains(&index) {
+                        return Err(error(&value, "register index out of range"));
+                    }
+                    index as Register
+                } else {
+                    self.register(&value)?
+                };
+                self.aliases.insert(name.text, register);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.expect(&token)?;
+                self.check_name(&name)?;
+                let value = self.expect(&name)?;
+                let value = self.value(&value)?;
+                self.constants.insert(name.text, value);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.expect(&token)?;
+                self.check_name(&name)?;
+                let open = self.expect_text(&name, "{")?;
+                let value = self.calc(&open)?;
+                self.constants.insert(name.text, value);
+                Ok(())
+            }
+            ":byte" => {
+                let value = self.expect(&token)?;
+                let byte = if value.text == "{" {
+                    let result = self.calc(&value)?;
+                    to_byte(result, &value)?
+                } else {
+                    self.byte(&value)?
+                };
+                self.emit(&[byte], &token)
+            }
+            ":pointer" => {
+                let value = self.expect(&token)?;
+                let addr = self.address(&value, Fixup::Long, self.here)?;
+                self.emit(&addr.to_be_bytes(), &token)
+            }
+            ":org" => {
+                let value = self.expect(&token)?;
+                let addr = self.value(&value)?;
+                if !(PROGRAM_START as f64..MEMORY_SIZE as f64).contains(&addr) {
+                    return Err(error(&value, "address out of range"));
+                }
+                self.here = addr as usize;
+                Ok(())
+            }
+            ":unpack" => {
+                let high = self.expect(&token)?;
+                let high = match high.text.as_str() {
+                    "long" => None,
+                    _ => match self.value(&high)? {
+                        value @ 0.0..16.0 => Some(value as u8),
+                        _ => return Err(error(&high, "expected a nibble or 'long'")),
+                    },
+                };
+                let label = self.expect(&token)?;
+                let at = self.here;
+                let addr = self.address(&label, Fixup::Unpack(high), at)?;
+                let (hi, lo) = (self.aliases["unpack-hi"], self.aliases["unpack-lo"]);
+                self.emit(&Instruction::Ld(hi, 0).to_bytes(), &token)?;
+                self.emit(&Instruction::Ld(lo, 0).to_bytes(), &token)?;
+                if self.try_value(&label).is_some() {
+                    self.patch(at, Fixup::Unpack(high), addr as usize);
+                }
+                Ok(())
+            }
+            ":call" => {
+                let target = self.expect(&token)?;
+                let addr = self.address(&target, Fixup::Address, self.here)?;
+                self.emit(&Instruction::Call(addr).to_bytes(), &token)
+            }
+            ":macro" => self.define_macro(&token),
+            ":breakpoint" | ":proto" => self.expect(&token).map(|_| ()),
+            ":monitor" => {
+                let address = self.expect(&token)?;
+                self.expect(&address).map(|_| ())
+            }
+            ":assert" => {
+                let mut open = self.expect(&token)?;
+                let message = match open.text.as_str() {
+                    "{" => "assertion failed".to_string(),
+                    text => {
+                        let message = text.trim_matches('"').to_string();
+                        open = self.expect_text(&open, "{")?;
+                        message
+                    }
+                };
+                match self.calc(&open)? {
+                    0.0 => Err(error(&token, message)),
+                    _ => Ok(()),
+                }
+            }
+            ":string" | ":stringmode" => {
+                Err(error(&token, format!("'{}' is not supported", token.text)))
+            }
+            "bcd" => self.register_instruction(&token, Instruction::LdBcd),
+            "saveflags" => self.register_instruction(&token, Instruction::StoreFlags),
+            "loadflags" => self.register_instruction(&token, Instruction::LoadFlags),
+            "save" | "load" => {
+                let from = self.expect(&token)?;
+                let x = self.register(&from)?;
+                let instruction = if self.peek_is("-") {
+                    let dash = self.expect(&from)?;
+                    let to = self.expect(&dash)?;
+                    let y = self.register(&to)?;
+                    match token.text.as_str() {
+                        "save" => Instruction::SaveRange(x, y),
+                        _ => Instruction::LoadRange(x, y),
+                    }
+                } else {
+                    match token.text.as_str() {
+                        "save" => Instruction::Store(x),
+                        _ => Instruction::Load(x),
+                    }
+                };
+                self.emit(&instruction.to_bytes(), &token)
+            }
+            "sprite" => {
+                let x = self.expect(&token)?;
+                let y = self.expect(&x)?;
+                let n = self.expect(&y)?;
+                let instruction = Instruction::Drw {
+                    x: self.register(&x)?,
+                    y: self.register(&y)?,
+                    n: self.nibble(&n)?,
+                };
+                self.emit(&instruction.to_bytes(), &token)
+            }
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.expect(&token)?;
+                let n = self.nibble(&n)?;
+                let instruction = match token.text.as_str() {
+                    "scroll-down" => Instruction::Scd(n),
+                    "scroll-up" => Instruction::Scu(n),
+                    _ => Instruction::Plane(n),
+                };
+                self.emit(&instruction.to_bytes(), &token)
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.expect(&token)?;
+                let addr = self.address(&target, Fixup::Address, self.here)?;
+                let instruction = match token.text.as_str() {
+                    "jump" => Instruction::Jp(addr),
+                    "jump0" => Instruction::JpV0(addr),
+                    _ => Instruction::Sys(addr),
+                };
+                self.emit(&instruction.to_bytes(), &token)
+            }
+            "delay" | "buzzer" | "pitch" => {
+                let op = self.expect_text(&token, ":=")?;
+                let source = self.expect(&op)?;
+                let x = self.register(&source)?;
+                let instruction = match token.text.as_str() {
+                    "delay" => Instruction::LdDelay(x),
+                    "buzzer" => Instruction::LdSound(x),
+                    _ => Instruction::LdPitch(x),
+                };
+                self.emit(&instruction.to_bytes(), &token)
+            }
+            "i" => self.assign_i(&token),
+            "if" => {
+                let condition = self.condition(&token)?;
+                let body = self.expect(&token)?;
+                match body.text.as_str() {
+                    "then" => self.skip_unless(condition, &token),
+                    "begin" => {
+                        self.skip_unless(negate(condition), &token)?;
+                        self.branches.push((self.here, token.clone()));
+                        self.emit(&Instruction::Jp(0).to_bytes(), &token)
+                    }
+                    _ => Err(error(&body, "expected 'then' or 'begin'")),
+                }
+            }
+            "else" => {
+                let (jump, begin) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| error(&token, "'else' without 'if ... begin'"))?;
+                self.branches.push((self.here, begin));
+                self.emit(&Instruction::Jp(0).to_bytes(), &token)?;
+                self.patch_jump(jump, &token)
+            }
+            "end" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| error(&token, "'end' without 'if ... begin'"))?;
+                self.patch_jump(jump, &token)
+            }
+            "loop" => {
+                self.loops.push((self.here, Vec::new(), token));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(error(&token, "'while' outside of a loop"));
+                }
+                let condition = self.condition(&token)?;
+                self.skip_unless(negate(condition), &token)?;
+                let jump = self.here;
+                self.emit(&Instruction::Jp(0).to_bytes(), &token)?;
+                if let Some((_, exits, _)) = self.loops.last_mut() {
+                    exits.push(jump);
+                }
+                Ok(())
+            }
+            "again" => {
+                let (start, exits, _) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| error(&token, "'again' without 'loop'"))?;
+                let start = short_address(start, &token)?;
+                self.emit(&Instruction::Jp(start).to_bytes(), &token)?;
+                for exit in exits {
+                    self.patch_jump(exit, &token)?;
+                }
+                Ok(())
+            }
+            "{" | "}" | "then" | "begin" => {
+                Err(error(&token, format!("unexpected '{}'", token.text)))
+            }
+            _ => self.bare(token),
+        }
+    }
+
+    /// A statement that doesn't start with a keyword: an assignment, a macro, a subroutine call
+    /// or a byte of data
+    fn bare(&mut self, token: Token) -> Result<(), AsmError> {
+        if let Some(x) = self.try_register(&token) {
+            return self.assign_register(x, &token);
+        }
+        if self.macros.contains_key(&token.text) {
+            return self.expand(&token);
+        }
+        if let Some(value) = self.try_value(&token) {
+            if self.labels.contains_key(&token.text) {
+                let addr = self.address(&token, Fixup::Address, self.here)?;
+                return self.emit(&Instruction::Call(addr).to_bytes(), &token);
+            }
+            let byte = to_byte(value, &token)?;
+            return self.emit(&[byte], &token);
+        }
+        if is_name(&token.text) {
+            // A subroutine defined further down
+            let addr = self.address(&token, Fixup::Address, self.here)?;
+            return self.emit(&Instruction::Call(addr).to_bytes(), &token);
+        }
+        Err(error(&token, format!("unexpected '{}'", token.text)))
+    }
+
+    fn assign_register(&mut self, x: Register, target: &Token) -> Result<(), AsmError> {
+        let op = self.expect(target)?;
+        let source = self.expect(&op)?;
+        let y = self.try_register(&source);
+        let instruction = match (op.text.as_str(), y) {
+            (":=", Some(y)) => Instruction::LdReg(x, y),
+            (":=", None) => match source.text.as_str() {
+                "key" => Instruction::LdKey(x),
+                "delay" => Instruction::LdFromDelay(x),
+                "random" => {
+                    let mask = self.expect(&source)?;
+                    Instruction::Rnd(x, self.byte(&mask)?)
+                }
+                _ => Instruction::Ld(x, self.byte(&source)?),
+            },
+            ("+=", Some(y)) => Instruction::AddReg(x, y),
+            ("+=", None) => Instruction::Add(x, self.byte(&source)?),
+            ("-=", Some(y)) => Instruction::Sub(x, y),
+            ("-=", None) => Instruction::Add(x, self.byte(&source)?.wrapping_neg()),
+            ("|=", Some(y)) => Instruction::Or(x, y),
+            ("&=", Some(y)) => Instruction::And(x, y),
+            ("^=", Some(y)) => Instruction::Xor(x, y),
+            ("=-", Some(y)) => Instruction::Subn(x, y),
+            (">>=", Some(y)) => Instruction::Shr(x, y),
+            ("<<=", Some(y)) => Instruction::Shl(x, y),
+            ("|=" | "&=" | "^=" | "=-" | ">>=" | "<<=", None) => {
+                return Err(error(&source, "expected a register"))
+            }
+            _ => return Err(error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        self.emit(&instruction.to_bytes(), target)
+    }
+
+    fn assign_i(&mut self, target: &Token) -> Result<(), AsmError> {
+        let op = self.expect(target)?;
+        let source = self.expect(&op)?;
+        let instruction = match (op.text.as_str(), source.text.as_str()) {
+            (":=", "hex") => {
+                let digit = self.expect(&source)?;
+                Instruction::LdFont(self.register(&digit)?)
+            }
+            (":=", "bighex") => {
+                let digit = self.expect(&source)?;
+                Instruction::LdHiFont(self.register(&digit)?)
+            }
+            (":=", "long") => {
+                let value = self.expect(&source)?;
+                Instruction::LdILong(self.address(&value, Fixup::Long, self.here + 2)?)
+            }
+            (":=", _) => Instruction::LdI(self.address(&source, Fixup::Address, self.here)?),
+            ("+=", _) => Instruction::AddI(self.register(&source)?),
+            _ => return Err(error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        self.emit(&instruction.to_bytes(), target)
+    }
+
+    fn register_instruction(
+        &mut self,
+        token: &Token,
+        instruction: fn(Register) -> Instruction,
+    ) -> Result<(), AsmError> {
+        let operand = self.expect(token)?;
+        let x = self.register(&operand)?;
+        self.emit(&instruction(x).to_bytes(), token)
+    }
+
+    /// Reads `vx op operand` or `vx key`/`vx -key` after `if` or `while`
+    fn condition(&mut self, keyword: &Token) -> Result<(Register, String, Operand), AsmError> {
+        let left = self.expect(keyword)?;
+        let x = self.register(&left)?;
+        let op = self.expect(&left)?;
+        match op.text.as_str() {
+            "key" | "-key" => Ok((x, op.text, Operand::Byte(0))),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let right = self.expect(&op)?;
+                let operand = match self.try_register(&right) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte(&right)?),
+                };
+                Ok((x, op.text, operand))
+            }
+            _ => Err(error(&op, format!("unknown comparison '{}'", op.text))),
+        }
+    }
+
+    /// Emits the instructions that skip the next one unless `condition` holds
+    fn skip_unless(
+        &mut self,
+        (x, op, right): (Register, String, Operand),
+        token: &Token,
+    ) -> Result<(), AsmError> {
+        let mut instructions = Vec::new();
+        match (op.as_str(), right) {
+            ("key", _) => instructions.push(Instruction::Sknp(x)),
+            ("-key", _) => instructions.push(Instruction::Skp(x)),
+            ("==", Operand::Register(y)) => instructions.push(Instruction::SneReg(x, y)),
+            ("==", Operand::Byte(n)) => instructions.push(Instruction::Sne(x, n)),
+            ("!=", Operand::Register(y)) => instructions.push(Instruction::SeReg(x, y)),
+            ("!=", Operand::Byte(n)) => instructions.push(Instruction::Se(x, n)),
+            (op, right) => {
+                // VF becomes 1 when `a >= b`, which is all four comparisons with the operands
+                // swapped or the result inverted
+                let (a, b) = match op {
+                    "<" | ">=" => (Operand::Register(x), right),
+                    _ => (right, Operand::Register(x)),
+                };
+                match (a, b) {
+                    (Operand::Register(a), Operand::Register(b)) => {
+                        instructions.push(Instruction::LdReg(VF, a));
+                        instructions.push(Instruction::Sub(VF, b));
+                    }
+                    (Operand::Register(a), Operand::Byte(b)) => {
+                        instructions.push(Instruction::Ld(VF, b));
+                        instructions.push(Instruction::Subn(VF, a));
+                    }
+                    (Operand::Byte(a), Operand::Register(b)) => {
+                        instructions.push(Instruction::Ld(VF, a));
+                        instructions.push(Instruction::Sub(VF, b));
+                    }
+                    (Operand::Byte(_), Operand::Byte(_)) => unreachable!(),
+                }
+                let skip_when = match op {
+                    "<" | ">" => 1,
+                    _ => 0,
+                };
+                instructions.push(Instruction::Se(VF, skip_when));
+            }
+        }
+        for instruction in instructions {
+            self.emit(&instruction.to_bytes(), token)?;
+        }
+        Ok(())
+    }
+
+    fn define_macro(&mut self, token: &Token) -> Result<(), AsmError> {
+        let name = self.expect(token)?;
+        self.check_name(&name)?;
+        let mut args = Vec::new();
+        let mut last = name.clone();
+        loop {
+            let arg = self.expect(&last)?;
+            if arg.text == "{" {
+                break;
+            }
+            args.push(arg.text.clone());
+            last = arg;
+        }
+        let body = self.block(&last)?;
+        self.macros.insert(
+            name.text,
+            Macro {
+                args,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Replaces a macro invocation with the body of the macro, its arguments substituted
+    fn expand(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(error(token, "macros expand without end"));
+        }
+
+        let count = self.macros[&token.text].args.len();
+        let mut values = Vec::new();
+        let mut last = token.clone();
+        for _ in 0..count {
+            last = self.expect(&last)?;
+            values.push(last.text.clone());
+        }
+
+        let definition = self
+            .macros
+            .get_mut(&token.text)
+            .expect("only called for macros");
+        let calls = definition.calls;
+        definition.calls += 1;
+        let body = definition
+            .body
+            .iter()
+            .map(|body_token| {
+                let text = match definition.args.iter().position(|a| *a == body_token.text) {
+                    Some(index) => values[index].clone(),
+                    None if body_token.text == "CALLS" => calls.to_string(),
+                    None => body_token.text.clone(),
+                };
+                Token {
+                    text,
+                    span: body_token.span,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.tokens.splice(self.pos..self.pos, body);
+        Ok(())
+    }
+
+    /// Collects the tokens up to the `}` matching an already read `{`
+    fn block(&mut self, open: &Token) -> Result<Vec<Token>, AsmError> {
+        let mut depth = 0;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self
+                .next()
+                .ok_or_else(|| error(open, "'{' without matching '}'"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    /// Evaluates the expression following an already read `{`
+    fn calc(&mut self, open: &Token) -> Result<f64, AsmError> {
+        let tokens = self.block(open)?;
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos, open, 0)?;
+        match tokens.get(pos) {
+            Some(token) => Err(error(token, "unexpected token in expression")),
+            None => Ok(value),
+        }
+    }
+
+    /// Evaluates binary operators right to left without precedence, like Octo. `depth` counts
+    /// the parentheses and unary operators around the expression.
+    fn expression(
+        &self,
+        tokens: &[Token],
+        pos: &mut usize,
+        open: &Token,
+        depth: usize,
+    ) -> Result<f64, AsmError> {
+        let mut operands = vec![self.term(tokens, pos, open, depth)?];
+        let mut ops = Vec::new();
+        while let Some(op) = tokens.get(*pos).filter(|t| is_binary(&t.text)) {
+            *pos += 1;
+            ops.push(op);
+            operands.push(self.term(tokens, pos, open, depth)?);
+        }
+
+        let mut right = operands.pop().unwrap_or_default();
+        while let (Some(op), Some(left)) = (ops.pop(), operands.pop()) {
+            right = binary(op, left, right)?;
+        }
+        Ok(right)
+    }
+
+    fn term(
+        &self,
+        tokens: &[Token],
+        pos: &mut usize,
+        open: &Token,
+        depth: usize,
+    ) -> Result<f64, AsmError> {
+        let token = tokens
+            .get(*pos)
+            .ok_or_else(|| error(open, "unexpected end of expression"))?;
+        *pos += 1;
+        if depth > MAX_EXPRESSION_DEPTH {
+            return Err(error(token, "expression is nested too deeply"));
+        }
+        let depth = depth + 1;
+
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.term(tokens, pos, open, depth)?));
+        }
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens, pos, open, depth)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(error(token, "unclosed parenthesis")),
+                }
+            }
+            "@" => {
+                let addr = self.term(tokens, pos, open, depth)? as usize;
+                let byte = addr
+                    .checked_sub(PROGRAM_START as usize)
+                    .and_then(|index| self.rom.get(index))
+                    .copied()
+                    .unwrap_or(0);
+                Ok(byte as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            _ => self.value(token),
+        }
+    }
+
+    /// A number, constant or label that is already defined
+    fn try_value(&self, token: &Token) -> Option<f64> {
+        parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&addr| addr as f64))
+    }
+
+    fn value(&self, token: &Token) -> Result<f64, AsmError> {
+        self.try_value(token)
+            .ok_or_else(|| error(token, format!("undefined name '{}'", token.text)))
+    }
+
+    fn byte(&self, token: &Token) -> Result<u8, AsmError> {
+        to_byte(self.value(token)?, token)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u8, AsmError> {
+        match self.value(token)? {
+            value @ 0.0..16.0 => Ok(value as u8),
+            _ => Err(error(token, "value out of range 0-15")),
+        }
+    }
+
+    /// A jump target or pointer, filled in once the label is defined if it isn't yet
+    fn address(&mut self, token: &Token, fixup: Fixup, at: usize) -> Result<Address, AsmError> {
+        let max = match fixup {
+            Fixup::Address | Fixup::Unpack(Some(_)) => 0xFFF,
+            Fixup::Long | Fixup::Unpack(None) => 0xFFFF,
+        };
+        match self.try_value(token) {
+            Some(value) if (0.0..=max as f64).contains(&value) => Ok(value as Address),
+            Some(_) => Err(error(token, format!("address out of range 0-0x{:X}", max))),
+            None if is_name(&token.text) && self.try_register(token).is_none() => {
+                self.pending.entry(token.text.clone()).or_default().push((
+                    at,
+                    fixup,
+                    token.clone(),
+                ));
+                Ok(0)
+            }
+            None => Err(error(token, "expected an address")),
+        }
+    }
+
+    fn try_register(&self, token: &Token) -> Option<Register> {
+        if let Some(&register) = self.aliases.get(&token.text) {
+            return Some(register);
+        }
+        let digit = token.text.strip_prefix(['v', 'V'])?;
+        match digit.len() {
+            1 => u8::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+
+    fn register(&self, token: &Token) -> Result<Register, AsmError> {
+        self.try_register(token).ok_or_else(|| {
+            error(
+                token,
+                format!("expected a register, found '{}'", token.text),
+            )
+        })
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.get(self.pos).is_some_and(|t| t.text == text)
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AsmError> {
+        if !is_name(&name.text) || self.try_register(name).is_some() {
+            return Err(error(name, format!("'{}' is a reserved name", name.text)));
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, addr: usize) -> Result<(), AsmError> {
+        self.check_name(name)?;
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(error(name, format!("'{}' is already defined", name.text)));
+        }
+        self.labels.insert(name.text.clone(), addr);
+        for (at, fixup, token) in self.pending.remove(&name.text).unwrap_or_default() {
+            if matches!(fixup, Fixup::Address | Fixup::Unpack(Some(_))) {
+                short_address(addr, &token)?;
+            }
+            self.patch(at, fixup, addr);
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: &Token) -> Result<(), AsmError> {
+        let end = self.here + bytes.len();
+        if end > MEMORY_SIZE {
+            return Err(error(token, "program doesn't fit in memory"));
+        }
+        let start = self.here - PROGRAM_START as usize;
+        if self.rom.len() < end - PROGRAM_START as usize {
+            self.rom.resize(end - PROGRAM_START as usize, 0);
+        }
+        self.rom[start..start + bytes.len()].copy_from_slice(bytes);
+        self.here = end;
+        Ok(())
+    }
+
+    /// Points the jump at `at` to the current address, which `token` needs to be able to reach
+    fn patch_jump(&mut self, at: usize, token: &Token) -> Result<(), AsmError> {
+        let addr = short_address(self.here, token)?;
+        self.patch(at, Fixup::Address, addr as usize);
+        Ok(())
+    }
+
+    fn patch(&mut self, at: usize, fixup: Fixup, addr: usize) {
+        let index = at - PROGRAM_START as usize;
+        let rom = &mut self.rom;
+        match fixup {
+            Fixup::Address => {
+                rom[index] = (rom[index] & 0xF0) | (addr >> 8) as u8;
+                rom[index + 1] = addr as u8;
+            }
+            Fixup::Long => {
+                rom[index] = (addr >> 8) as u8;
+                rom[index + 1] = addr as u8;
+            }
+            Fixup::Unpack(high) => {
+                rom[index + 1] = match high {
+                    Some(high) => high << 4 | (addr >> 8) as u8,
+                    None => (addr >> 8) as u8,
+                };
+                rom[index + 3] = addr as u8;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, AsmError> {
+        if let Some((_, _, token)) = self.loops.last() {
+            return Err(error(token, "'loop' without 'again'"));
+        }
+        if let Some((_, token)) = self.branches.last() {
+            return Err(error(token, "'if ... begin' without 'end'"));
+        }
+        if let Some((_, _, token)) = self.pending.values().flatten().next() {
+            return Err(error(token, format!("undefined name '{}'", token.text)));
+        }
+
+        if self.main_jump {
+            let program_error = |message: &str| AsmError {
+                file: None,
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    len: 0,
+                },
+                message: message.into(),
+            };
+            let Some(&main) = self.labels.get("main") else {
+                return Err(program_error("the program doesn't define 'main'"));
+            };
+            if main > 0xFFF {
+                return Err(program_error("'main' is out of range 0-0xFFF"));
+            }
+            self.rom[0] = 0x10;
+            self.patch(PROGRAM_START as usize, Fixup::Address, main);
+        }
+        Ok(self.rom)
+    }
+}
+
+fn negate((x, op, right): (Register, String, Operand)) -> (Register, String, Operand) {
+    let op = match op.as_str() {
+        "==" => "!=",
+        "!=" => "==",
+        "<" => ">=",
+        ">=" => "<",
+        ">" => "<=",
+        "<=" => ">",
+        "key" => "-key",
+        _ => "key",
+    };
+    (x, op.to_string(), right)
+}
+
+fn is_binary(op: &str) -> bool {
+    matches!(
+        op,
+        "+" | "-"
+            | "*"
+            | "/"
+            | "%"
+            | "&"
+            | "|"
+            | "^"
+            | "<<"
+            | ">>"
+            | "pow"
+            | "min"
+            | "max"
+            | "<"
+            | ">"
+            | "<="
+            | ">="
+            | "=="
+            | "!="
+    )
+}
+
+/// Names can't look like numbers or clash with the keywords
+fn is_name(text: &str) -> bool {
+    const KEYWORDS: [&str; 14] = [
+        ":=", "+=", "-=", "|=", "&=", "^=", "=-", ">>=", "<<=", "i", "key", "-key", "random",
+        "delay",
+    ];
+    parse_number(text).is_none()
+        && !text.starts_with(':')
+        && !KEYWORDS.contains(&text)
+        && !"{}();".contains(text)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+/// Applies the binary operator `op` of a `:calc` expression
+fn binary(op: &Token, left: f64, right: f64) -> Result<f64, AsmError> {
+    let (a, b) = (left as i64, right as i64);
+    let truth = |value: bool| if value { 1.0 } else { 0.0 };
+    Ok(match op.text.as_str() {
+        "+" => left + right,
+        "-" => left - right,
+        "*" => left * right,
+        "/" | "%" if right == 0.0 => return Err(error(op, "division by zero")),
+        "/" => left / right,
+        "%" => left % right,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+        ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "<" => truth(left < right),
+        ">" => truth(left > right),
+        "<=" => truth(left <= right),
+        ">=" => truth(left >= right),
+        "==" => truth(left == right),
+        _ => truth(left != right),
+    })
+}
+
+/// Checks that `addr` fits in the 12 bits of a jump or call
+fn short_address(addr: usize, token: &Token) -> Result<Address, AsmError> {
+    if addr > 0xFFF {
+        return Err(error(token, "address out of range 0-0xFFF"));
+    }
+    Ok(addr as Address)
+}
+
+fn to_byte(value: f64, token: &Token) -> Result<u8, AsmError> {
+    let value = value.floor();
+    if !(-128.0..=255.0).contains(&value) {
+        return Err(error(
+            token,
+            format!("value {} doesn't fit in a byte", value),
+        ));
+    }
+    Ok(value as i64 as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        let source = "
+            # draw a ball forever
+            :const SPEED 2
+            :alias x v1
+
+            : main
+                i := ball
+                loop
+                    sprite x x 4
+                    x += SPEED
+                    if x >= 32 then x := 0
+                again
+
+            : ball 0x60 0xF0 0xF0 0x60
+        ";
+        assert_eq!(
+            compile(source),
+            Ok(vec![
+                0xA2, 0x10, // LD I, ball
+                0xD1, 0x14, // DRW V1, V1, 4
+                0x71, 0x02, // ADD V1, 2
+                0x6F, 0x20, // LD VF, 32
+                0x8F, 0x17, // SUBN VF, V1
+                0x3F, 0x00, // SE VF, 0
+                0x61, 0x00, // LD V1, 0
+                0x12, 0x02, // JP loop
+                0x60, 0xF0, 0xF0, 0x60, // ball
+            ])
+        );
+    }
+
+    #[test]
+    fn test_main_jump() {
+        // Anything before main needs a jump over it
+        assert_eq!(
+            compile(": draw sprite v0 v0 1 ; : main draw"),
+            Ok(vec![0x12, 0x06, 0xD0, 0x01, 0x00, 0xEE, 0x22, 0x02])
+        );
+        assert_eq!(
+            compile(": helper ;").unwrap_err().message,
+            "the program doesn't define 'main'"
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            : main
+                if v0 == v1 begin
+                    v2 := key
+                else
+                    v2 := delay
+                end
+                loop
+                    while v3 != 5
+                    if v4 -key then v3 += 1
+                again
+        ";
+        assert_eq!(
+            compile(source),
+            Ok(vec![
+                0x50, 0x10, // SE V0, V1
+                0x12, 0x08, // JP else
+                0xF2, 0x0A, // LD V2, K
+                0x12, 0x0A, // JP end
+                0xF2, 0x07, // else: LD V2, DT
+                0x43, 0x05, // end: loop: SNE V3, 5
+                0x12, 0x14, // JP after the loop
+                0xE4, 0x9E, // SKP V4
+                0x73, 0x01, // ADD V3, 1
+                0x12, 0x0A, // JP loop
+            ])
+        );
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+            :macro twice op { op op }
+            :calc DOUBLE { 2 * 1 + 3 }
+            : main
+                twice clear
+                :unpack 0xA data
+                i := long data
+                v0 -= 1
+                save v1 - v2
+                jump0 data
+            : data
+                :byte { DOUBLE }
+                :pointer data
+                -1
+        ";
+        assert_eq!(
+            compile(source),
+            Ok(vec![
+                0x00, 0xE0, 0x00, 0xE0, // twice clear
+                0x60, 0xA2, 0x61, 0x12, // :unpack 0xA data
+                0xF0, 0x00, 0x02, 0x12, // i := long data
+                0x70, 0xFF, // v0 -= 1
+                0x51, 0x22, // save v1 - v2
+                0xB2, 0x12, // jump0 data
+                0x08, 0x02, 0x12, 0xFF, // data
+            ])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = compile(": main\n  v0 := 256").unwrap_err();
+        assert_eq!(
+            err.span,
+            Span {
+                line: 2,
+                column: 9,
+                len: 3
+            }
+        );
+        assert_eq!(err.message, "value 256 doesn't fit in a byte");
+
+        let err = compile(": main jump nowhere").unwrap_err();
+        assert_eq!(err.message, "undefined name 'nowhere'");
+
+        let err = compile(": main loop").unwrap_err();
+        assert_eq!(err.message, "'loop' without 'again'");
+
+        let err = compile(": main : main").unwrap_err();
+        assert_eq!(err.message, "'main' is already defined");
+
+        let err = compile(": main v0 += i").unwrap_err();
+        assert_eq!(err.span.column, 14);
+    }
+
+    #[test]
+    fn test_expression_depth() {
+        let calc = |expression: String| compile(&format!(": main :byte {{ {} }}", expression));
+        // Right to left without precedence
+        assert_eq!(calc("1 + 2 * 3 - 1".into()), Ok(vec![5]));
+        assert_eq!(calc("0 + ".repeat(200_000) + "1"), Ok(vec![1]));
+        assert_eq!(calc("- ".repeat(200) + "1"), Ok(vec![1]));
+
+        let err = calc("- ".repeat(200_000) + "1").unwrap_err();
+        assert_eq!(err.message, "expression is nested too deeply");
+        let err = calc("( ".repeat(200_000) + "1").unwrap_err();
+        assert_eq!(err.message, "expression is nested too deeply");
+    }
+
+    #[test]
+    fn test_address_range() {
+        // Jumps and calls only reach 0xFFF, anything above needs `i := long`
+        let out_of_range = |body: &str| {
+            let source = format!(": main :org 0x1000 : far {}", body);
+            compile(&source).unwrap_err().message
+        };
+        for body in [
+            "loop again",
+            "if v0 == 1 begin v1 := 2 end",
+            "if v0 == 1 begin v1 := 2 else v1 := 3 end",
+            "loop while v0 != 1 v0 += 1 again",
+            "; far",
+            "; jump far",
+            "; :call far",
+        ] {
+            assert_eq!(
+                out_of_range(body),
+                "address out of range 0-0xFFF",
+                "{}",
+                body
+            );
+        }
+        assert_eq!(
+            compile("jump main :org 0x1000 : main").unwrap_err().message,
+            "address out of range 0-0xFFF"
+        );
+        assert_eq!(
+            compile(":org 0x1000 : main ;").unwrap_err().message,
+            "'main' is out of range 0-0xFFF"
+        );
+        assert!(compile(": main i := long far :org 0x1000 : far 1").is_ok());
+    }
+}
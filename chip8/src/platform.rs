@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+use crate::memory::PROGRAM_START;
+
+/// The CHIP-8 variant the CPU emulates, deciding which instructions are available.
+///
+/// Platforms are ordered so that each one supports every instruction of the platforms before it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Platform {
+    /// The original CHIP-8 instruction set
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1, adding the 128x64 high resolution mode, scrolling, 16x16 sprites, a large
+    /// font and the RPL user flags
+    SuperChip,
+    /// XO-CHIP, adding 64 KiB of memory, a second bitplane and programmable audio
+    XoChip,
+}
+
+impl Platform {
+    /// The number of addressable bytes of memory
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// The largest ROM that fits in memory after [`PROGRAM_START`]
+    pub fn max_rom_size(&self) -> usize {
+        self.memory_size() - PROGRAM_START as usize
+    }
+
+    /// The number of subroutine calls that can be nested: the COSMAC VIP interpreter reserves
+    /// room for 12 return addresses, the HP 48 ports for 16
+    pub fn stack_depth(&self) -> usize {
+        match self {
+            Platform::Chip8 => 12,
+            Platform::SuperChip | Platform::XoChip => 16,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// Behaviour of instructions that differ between CHIP-8 interpreters.
+///
+/// The default leaves every quirk disabled, which matches the behaviour most modern ROMs expect.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// `FX55`/`FX65` leave I pointing just past the last register transferred
+    pub load_store_increments_i: bool,
+    /// `BNNN` jumps to NNN + VX, where X is the high nibble of NNN, instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to 0
+    pub vf_reset: bool,
+    /// Sprites are clipped at the edges of the screen instead of wrapping around
+    pub clip_sprites: bool,
+    /// `DXYN` waits for the next 60 Hz frame, limiting drawing to one sprite per frame
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter
+    pub const VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        vf_reset: true,
+        clip_sprites: true,
+        display_wait: true,
+    };
+
+    /// CHIP-48 and SUPER-CHIP on the HP 48 calculators
+    pub const SCHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// XO-CHIP as implemented by Octo
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        vf_reset: false,
+        clip_sprites: false,
+        display_wait: false,
+    };
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+use crate::scheduler::FRAME_RATE;
+
+/// Ten seconds of history
+pub const DEFAULT_REWIND_FRAMES: usize = 10 * FRAME_RATE as usize;
+
+/// Changed runs closer together than this are stored as one patch, so scattered writes don't
+/// cost an offset each
+const MERGE_GAP: usize = 8;
+
+/// Bytes to write at `offset`
+struct Patch {
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
+/// Turns a state back into the one recorded before it
+enum Delta {
+    /// Only the bytes that differ, which for a frame is usually a few registers, some sprite
+    /// rows on the display and a handful of memory bytes
+    Patches(Vec<Patch>),
+    /// The whole state, used when the layout changed in between, e.g. after switching to hi-res
+    Full(Vec<u8>),
+}
+
+impl Delta {
+    /// The delta that turns `from` into `to`
+    fn between(from: &[u8], to: &[u8]) -> Delta {
+        if from.len() != to.len() {
+            return Delta::Full(to.to_vec());
+        }
+
+        let mut patches = Vec::new();
+        let mut i = 0;
+        while i < to.len() {
+            if from[i] == to[i] {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            let mut end = i + 1;
+            i = end;
+            while i < to.len() && i - end < MERGE_GAP {
+                if from[i] != to[i] {
+                    end = i + 1;
+                }
+                i += 1;
+            }
+            patches.push(Patch {
+                offset: start,
+                bytes: to[start..end].to_vec(),
+            });
+        }
+        Delta::Patches(patches)
+    }
+
+    fn apply(self, state: &mut Vec<u8>) {
+        match self {
+            Delta::Patches(patches) => {
+                for patch in patches {
+                    state[patch.offset..patch.offset + patch.bytes.len()]
+                        .copy_from_slice(&patch.bytes);
+                }
+            }
+            Delta::Full(bytes) => *state = bytes,
+        }
+    }
+}
+
+/// A bounded history of save states, one per frame.
+///
+/// Only the newest state is kept in full. Every older state is stored as the delta that turns
+/// its successor back into it, so stepping backwards is cheap and the oldest entry can be
+/// dropped without touching the rest.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer that can step back at most `capacity` states
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of states that can currently be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Records `state` as the newest state, dropping the oldest one if the buffer is full
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(Delta::between(&state, &latest));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    /// Steps back one state and returns it, it becomes the newest state
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        delta.apply(latest);
+        Some(latest)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let from = vec![0u8; 64];
+        let mut to = from.clone();
+        to[1] = 1;
+        to[4] = 2;
+        to[40] = 3;
+
+        let delta = Delta::between(&from, &to);
+        match &delta {
+            Delta::Patches(patches) => {
+                assert_eq!(patches.len(), 2);
+                assert_eq!(patches[0].offset, 1);
+                assert_eq!(patches[0].bytes, [1, 0, 0, 2]);
+                assert_eq!(patches[1].offset, 40);
+            }
+            Delta::Full(_) => panic!("Expected patches"),
+        }
+
+        let mut state = from.clone();
+        delta.apply(&mut state);
+        assert_eq!(state, to);
+
+        let mut state = from.clone();
+        Delta::between(&from, &[1, 2, 3]).apply(&mut state);
+        assert_eq!(state, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut buffer = RewindBuffer::new(10);
+        assert_eq!(buffer.pop(), None);
+
+        buffer.push(vec![1, 1, 1]);
+        buffer.push(vec![1, 2, 1]);
+        buffer.push(vec![3, 2, 1, 0]);
+        assert_eq!(buffer.len(), 2);
+
+        assert_eq!(buffer.pop(), Some(&[1, 2, 1][..]));
+        buffer.push(vec![1, 2, 2]);
+        assert_eq!(buffer.pop(), Some(&[1, 2, 1][..]));
+        assert_eq!(buffer.pop(), Some(&[1, 1, 1][..]));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut buffer = RewindBuffer::new(2);
+        for i in 0..5 {
+            buffer.push(vec![i]);
+        }
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(), Some(&[3][..]));
+        assert_eq!(buffer.pop(), Some(&[2][..]));
+        assert!(buffer.is_empty());
+    }
+}
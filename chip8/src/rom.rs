@@ -0,0 +1,199 @@
+use std::fmt;
+
+use crate::database::Database;
+use crate::error::RomError;
+use crate::platform::Platform;
+use crate::state::{self, RomHash};
+
+/// Signatures of common files that end up passed as ROMs by mistake
+const SIGNATURES: [(&[u8], &str); 7] = [
+    (b"PK\x03\x04", "a ZIP archive"),
+    (b"GIF87a", "a GIF image"),
+    (b"GIF89a", "a GIF image"),
+    (b"\x89PNG", "a PNG image"),
+    (b"\x1F\x8B\x08", "a gzip archive"),
+    (b"\x7FELF", "an executable"),
+    (b"%PDF", "a PDF document"),
+];
+
+/// Something unusual about a ROM that still loads
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RomWarning {
+    /// Instructions are two bytes, so the last byte can only be data
+    OddLength { size: usize },
+}
+
+impl fmt::Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomWarning::OddLength { size } => write!(
+                f,
+                "ROM has an odd length of {} bytes, the last byte can only be data",
+                size
+            ),
+        }
+    }
+}
+
+/// A program checked to fit the memory map of a platform, with its checksums.
+///
+/// [`crate::system::SystemBuilder::new`] runs it on the platform it was checked for unless told
+/// otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomImage {
+    bytes: Vec<u8>,
+    platform: Platform,
+    sha1: RomHash,
+    crc32: u32,
+}
+
+impl RomImage {
+    pub fn new(bytes: impl Into<Vec<u8>>, platform: Platform) -> Result<RomImage, RomError> {
+        let bytes = bytes.into();
+        if bytes.is_empty() {
+            return Err(RomError::Empty);
+        }
+
+        let max = platform.max_rom_size();
+        if bytes.len() > max {
+            return Err(RomError::TooLarge {
+                size: bytes.len(),
+                max,
+                platform,
+            });
+        }
+
+        if let Some(format) = SIGNATURES
+            .iter()
+            .find_map(|(magic, format)| bytes.starts_with(magic).then_some(*format))
+        {
+            return Err(RomError::NotChip8 { format });
+        }
+        if is_text(&bytes) {
+            return Err(RomError::NotChip8 {
+                format: "a text file",
+            });
+        }
+
+        Ok(RomImage {
+            platform,
+            sha1: state::rom_hash(&bytes),
+            crc32: crc32fast::hash(&bytes),
+            bytes,
+        })
+    }
+
+    /// Like [`RomImage::new`], on the platform the bundled ROM database lists for the ROM or
+    /// CHIP-8 if it isn't listed
+    pub fn detect(bytes: impl Into<Vec<u8>>) -> Result<RomImage, RomError> {
+        let bytes = bytes.into();
+        let platform = Database::bundled()
+            .lookup(&state::rom_hash(&bytes))
+            .map_or(Platform::Chip8, |info| info.platform);
+        RomImage::new(bytes, platform)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The platform the size was checked against
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn sha1(&self) -> RomHash {
+        self.sha1
+    }
+
+    /// The SHA-1 as lowercase hex, see [`state::rom_hash_hex`]
+    pub fn sha1_hex(&self) -> String {
+        state::rom_hash_hex(&self.sha1)
+    }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    /// Things that are unusual about the ROM but don't stop it from running
+    pub fn warnings(&self) -> Vec<RomWarning> {
+        let mut warnings = Vec::new();
+        if !self.bytes.len().is_multiple_of(2) {
+            warnings.push(RomWarning::OddLength {
+                size: self.bytes.len(),
+            });
+        }
+        warnings
+    }
+}
+
+/// Source code and other text files are all printable with line breaks, which no real program is
+fn is_text(bytes: &[u8]) -> bool {
+    bytes.len() >= 16
+        && bytes.contains(&b'\n')
+        && bytes
+            .iter()
+            .all(|&b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let rom = RomImage::new(vec![0x00, 0xE0, 0x12, 0x00], Platform::Chip8).unwrap();
+        assert_eq!(rom.len(), 4);
+        assert_eq!(rom.crc32(), crc32fast::hash(&[0x00, 0xE0, 0x12, 0x00]));
+        assert_eq!(rom.sha1(), state::rom_hash(rom.bytes()));
+        assert_eq!(rom.sha1_hex().len(), 40);
+        assert!(rom.warnings().is_empty());
+
+        let rom = RomImage::new(vec![0x00, 0xE0, 0x42], Platform::Chip8).unwrap();
+        assert_eq!(rom.warnings(), [RomWarning::OddLength { size: 3 }]);
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(RomImage::new(vec![], Platform::Chip8), Err(RomError::Empty));
+
+        let max = 0x1000 - 0x200;
+        assert!(RomImage::new(vec![0; max], Platform::Chip8).is_ok());
+        assert_eq!(
+            RomImage::new(vec![0; max + 1], Platform::SuperChip),
+            Err(RomError::TooLarge {
+                size: max + 1,
+                max,
+                platform: Platform::SuperChip
+            })
+        );
+        assert!(RomImage::new(vec![0; max + 1], Platform::XoChip).is_ok());
+    }
+
+    #[test]
+    fn test_not_chip8() {
+        assert_eq!(
+            RomImage::new(b"PK\x03\x04rest of the archive".to_vec(), Platform::Chip8),
+            Err(RomError::NotChip8 {
+                format: "a ZIP archive"
+            })
+        );
+        assert_eq!(
+            RomImage::new(
+                b": main\n\tv0 := 1\n\tloop again\n".to_vec(),
+                Platform::Chip8
+            ),
+            Err(RomError::NotChip8 {
+                format: "a text file"
+            })
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use std::time::Duration;
+
+use crate::audio::{AudioFrame, AudioSink};
+use crate::cpu::{self, Cpu, StepOutcome};
+use crate::debugger::Debugger;
+use crate::error::{CpuError, StateError};
+use crate::rewind::RewindBuffer;
+use crate::trace::Tracer;
+
+pub const FRAME_RATE: u32 = 60; // 60 Hz
+pub const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / FRAME_RATE as u64);
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = cpu::FREQUENCY / FRAME_RATE;
+
+/// How far [`Scheduler::advance`] is allowed to fall behind before dropping frames, so a stall
+/// doesn't turn into a burst of fast-forwarding
+const MAX_CATCH_UP_FRAMES: u32 = 10;
+
+/// Drives a [`Cpu`] in whole 60 Hz frames.
+///
+/// Each frame executes a fixed number of instructions and then ticks the timers once, so the
+/// same inputs always produce the same run no matter how fast the host is. Frames can be run
+/// back to back with [`Scheduler::run_frame`], or paced against the wall clock with
+/// [`Scheduler::advance`].
+///
+/// With rewind enabled, the state after every frame is recorded so [`Scheduler::rewind`] can
+/// step back through recent frames.
+///
+/// With a [`Debugger`] attached, every instruction goes through it, and a frame interrupted by
+/// a pause picks up where it left off once the debugger resumes.
+///
+/// An [`AudioSink`] hears the state of the speaker once at the end of every frame.
+///
+/// The keypad state can be recorded at the start of every frame, or replayed from an earlier
+/// recording, which together with a fixed RNG seed reproduces a run exactly. While recording,
+/// key presses are held back until the next frame starts, the same way a replay applies them.
+pub struct Scheduler {
+    cpu: Cpu,
+    instructions_per_frame: u32,
+    frame: u64,
+    /// Instructions already run in the current frame
+    cycle: u32,
+    lag: Duration,
+    rewind: RewindBuffer,
+    debugger: Option<Debugger>,
+    tracer: Option<Tracer>,
+    audio: Option<Box<dyn AudioSink>>,
+    input: Input,
+}
+
+/// Where the keypad state of each frame comes from
+enum Input {
+    /// Whatever the frontend pressed
+    Live,
+    /// Live, with the state at the start of every frame recorded and only applied then
+    Record {
+        frames: Vec<u16>,
+        /// The keys the frontend holds right now
+        held: u16,
+        /// The keys pressed since the current frame started, held for the next frame even if
+        /// they were released again
+        pressed: u16,
+    },
+    /// Set at the start of every frame, overriding live input until it runs out
+    Replay(Vec<u16>),
+}
+
+impl Scheduler {
+    pub fn new(cpu: Cpu, instructions_per_frame: u32) -> Scheduler {
+        Scheduler {
+            cpu,
+            instructions_per_frame,
+            frame: 0,
+            cycle: 0,
+            lag: Duration::ZERO,
+            rewind: RewindBuffer::new(0),
+            debugger: None,
+            tracer: None,
+            audio: None,
+            input: Input::Live,
+        }
+    }
+
+    pub fn attach_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_ref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    /// Records every instruction executed from now on
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Sends the state of the speaker to `sink` at the end of every frame
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.audio = Some(sink);
+    }
+
+    /// Detaches the audio sink and flushes its output
+    pub fn finish_audio(&mut self) -> std::io::Result<()> {
+        match self.audio.take() {
+            Some(mut sink) => sink.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the keypad state at the start of every frame from now on, see
+    /// [`Scheduler::recording`]
+    pub fn record_input(&mut self) {
+        self.input = Input::Record {
+            frames: Vec::new(),
+            held: self.cpu.keypad(),
+            pressed: 0,
+        };
+    }
+
+    /// The keypad states recorded so far, one per frame. Rewinding drops the rewound frames.
+    pub fn recording(&self) -> Option<&[u16]> {
+        match &self.input {
+            Input::Record { frames, .. } => Some(frames),
+            _ => None,
+        }
+    }
+
+    /// Sets the keypad to `frames[n]` at the start of frame `n`, see [`Cpu::set_keypad`]
+    pub fn replay_input(&mut self, frames: Vec<u16>) {
+        self.input = Input::Replay(frames);
+    }
+
+    /// Returns whether a replay is still driving the keypad
+    pub fn replaying(&self) -> bool {
+        matches!(&self.input, Input::Replay(frames) if (self.frame as usize) < frames.len())
+    }
+
+    /// Presses a key, see [`Cpu::key_down`]. Ignored while replaying, and held back until the
+    /// next frame starts while recording.
+    pub fn key_down(&mut self, key: u8) {
+        if self.replaying() {
+            return;
+        }
+        match &mut self.input {
+            Input::Record { held, pressed, .. } => {
+                *held |= 1 << key;
+                *pressed |= 1 << key;
+            }
+            _ => self.cpu.key_down(key),
+        }
+    }
+
+    /// Releases a key, see [`Cpu::key_up`]. Ignored while replaying, and held back until the
+    /// next frame starts while recording.
+    pub fn key_up(&mut self, key: u8) {
+        if self.replaying() {
+            return;
+        }
+        match &mut self.input {
+            Input::Record { held, .. } => *held &= !(1 << key),
+            _ => self.cpu.key_up(key),
+        }
+    }
+
+    /// Returns whether an attached debugger is holding the CPU
+    pub fn paused(&self) -> bool {
+        self.debugger.as_ref().is_some_and(Debugger::is_paused)
+    }
+
+    /// Keeps the last `frames` frames around for [`Scheduler::rewind`], 0 disables recording
+    pub fn set_rewind_frames(&mut self, frames: usize) {
+        self.rewind = RewindBuffer::new(frames);
+    }
+
+    /// The number of frames that can currently be rewound
+    pub fn rewind_frames(&self) -> usize {
+        self.rewind.len()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    /// Returns whether the program stopped itself with `00FD`
+    pub fn exited(&self) -> bool {
+        self.cpu.exited()
+    }
+
+    /// Runs a single frame.
+    ///
+    /// The frame ends early if the CPU is waiting for a key press or for the next frame, the
+    /// timers are still ticked. Once the program has exited this does nothing. If the debugger
+    /// pauses the CPU the frame is left unfinished until the next call after it resumes.
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        if self.cpu.exited() || self.paused() {
+            return Ok(());
+        }
+
+        let recording = self.rewind.capacity() > 0;
+        if recording && self.frame == 0 && self.rewind.is_empty() {
+            self.rewind.push(self.cpu.save_state());
+        }
+
+        if self.cycle == 0 {
+            match &mut self.input {
+                Input::Live => {}
+                Input::Record {
+                    frames,
+                    held,
+                    pressed,
+                } => {
+                    // Exactly what a replay of this frame will do
+                    self.cpu.set_keypad(*held | *pressed);
+                    *pressed = 0;
+                    frames.truncate(self.frame as usize);
+                    frames.push(self.cpu.keypad());
+                }
+                Input::Replay(frames) => {
+                    if let Some(&keys) = frames.get(self.frame as usize) {
+                        self.cpu.set_keypad(keys);
+                    }
+                }
+            }
+        }
+
+        while self.cycle < self.instructions_per_frame {
+            if self.paused() {
+                return Ok(());
+            }
+
+            let outcome = self.step()?;
+            self.cycle += 1;
+            match outcome {
+                StepOutcome::Executed => {}
+                StepOutcome::WaitingForKey
+                | StepOutcome::WaitingForVBlank
+                | StepOutcome::Exited => break,
+            }
+        }
+
+        self.cycle = 0;
+        if let Some(sink) = &mut self.audio {
+            sink.frame(&AudioFrame::of(&self.cpu));
+        }
+        self.cpu.tick_timers();
+        self.frame += 1;
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush();
+        }
+
+        if recording {
+            self.rewind.push(self.cpu.save_state());
+        }
+        Ok(())
+    }
+
+    /// Runs a single instruction, through the debugger and tracer if there are any
+    fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.before(&self.cpu);
+        }
+
+        let outcome = match &mut self.debugger {
+            Some(debugger) => debugger.tick(&mut self.cpu)?,
+            None => self.cpu.tick()?,
+        };
+
+        if let Some(tracer) = &mut self.tracer {
+            if outcome == StepOutcome::Executed {
+                tracer.after(&self.cpu);
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Steps back up to `frames` frames, restoring the CPU to the state it had back then.
+    ///
+    /// Returns the number of frames that were actually rewound, which is fewer than requested
+    /// once the recorded history runs out.
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, StateError> {
+        let rewound = frames.min(self.rewind.len() as u32);
+        if rewound == 0 {
+            return Ok(0);
+        }
+
+        for _ in 1..rewound {
+            self.rewind.pop();
+        }
+        if let Some(state) = self.rewind.pop() {
+            self.cpu.load_state(state)?;
+        }
+        self.frame = self.frame.saturating_sub(rewound as u64);
+        self.cycle = 0;
+        self.lag = Duration::ZERO;
+        Ok(rewound)
+    }
+
+    /// Steps back as many frames as fit in `elapsed` time, carrying the remainder over to the
+    /// next call like [`Scheduler::advance`] does.
+    ///
+    /// Returns the number of frames that were rewound.
+    pub fn rewind_elapsed(&mut self, elapsed: Duration) -> Result<u32, StateError> {
+        let lag = (self.lag + elapsed).min(FRAME_DURATION * MAX_CATCH_UP_FRAMES);
+        let frames = (lag.as_nanos() / FRAME_DURATION.as_nanos()) as u32;
+        let rewound = self.rewind(frames)?;
+        self.lag = lag - FRAME_DURATION * frames;
+        Ok(rewound)
+    }
+
+    /// Starts over from `state`, e.g. the power-on state, forgetting the frame count and the
+    /// rewind history
+    pub fn restart(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.rewind.clear();
+        self.frame = 0;
+        self.cycle = 0;
+        self.lag = Duration::ZERO;
+        Ok(())
+    }
+
+    /// Runs as many frames as fit in `elapsed` time, carrying the remainder over to the next call.
+    ///
+    /// Returns the number of frames that were run.
+    pub fn advance(&mut self, elapsed: Duration) -> Result<u32, CpuError> {
+        self.lag += elapsed;
+        let max_lag = FRAME_DURATION * MAX_CATCH_UP_FRAMES;
+        if self.lag > max_lag {
+            self.lag = max_lag;
+        }
+
+        let mut frames = 0;
+        while self.lag >= FRAME_DURATION {
+            if self.paused() {
+                self.lag = Duration::ZERO;
+                break;
+            }
+
+            self.lag -= FRAME_DURATION;
+            self.run_frame()?;
+            frames += 1;
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM that counts executed instructions in V0, after 3 instructions of setup
+    fn counter() -> Cpu {
+        let mut rom = vec![0x60, 0xFF, 0xF0, 0x15, 0x60, 0x00];
+        rom.extend([0x70, 0x01].repeat(100));
+        Cpu::init(&rom)
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut scheduler = Scheduler::new(counter(), 10);
+
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.frame(), 1);
+        assert_eq!(scheduler.cpu().registers.v[0], 7);
+        assert_eq!(scheduler.cpu().registers.pc, 0x200 + 2 * 10);
+
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.frame(), 2);
+        assert_eq!(scheduler.cpu().registers.v[0], 17);
+    }
+
+    #[test]
+    fn test_timers_tick_once_per_frame() {
+        // LD V1, 5; LD DT, V1; loop: LD V2, DT; JP loop
+        let cpu = Cpu::init(&[0x61, 0x05, 0xF1, 0x15, 0xF2, 0x07, 0x12, 0x04]);
+        let mut scheduler = Scheduler::new(cpu, 3);
+
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.v[2], 5);
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.v[2], 4);
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.v[2], 3);
+    }
+
+    #[test]
+    fn test_display_wait_ends_frame() {
+        let mut cpu = Cpu::init(&[0xD0, 0x01, 0x70, 0x01, 0x12, 0x00]);
+        cpu.quirks.display_wait = true;
+        let mut scheduler = Scheduler::new(cpu, 10);
+
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.pc, 0x202);
+
+        // One sprite per frame, with the rest of the loop in between
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.pc, 0x202);
+        assert_eq!(scheduler.cpu().registers.v[0], 1);
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut cpu = Cpu::init(&[0x00, 0xFD]);
+        cpu.set_platform(crate::platform::Platform::SuperChip);
+        let mut scheduler = Scheduler::new(cpu, 10);
+
+        scheduler.run_frame().unwrap();
+        assert!(scheduler.exited());
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.frame(), 1);
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut scheduler = Scheduler::new(Cpu::init(&[0xFF, 0xFF]), 10);
+
+        assert_eq!(
+            scheduler.run_frame(),
+            Err(CpuError::InvalidOpcode {
+                addr: 0x200,
+                opcode: 0xFFFF
+            })
+        );
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut scheduler = Scheduler::new(counter(), 10);
+        scheduler.set_rewind_frames(2);
+
+        for _ in 0..4 {
+            scheduler.run_frame().unwrap();
+        }
+        assert_eq!(scheduler.cpu().registers.v[0], 37);
+        assert_eq!(scheduler.rewind_frames(), 2);
+
+        assert_eq!(scheduler.rewind(1), Ok(1));
+        assert_eq!(scheduler.frame(), 3);
+        assert_eq!(scheduler.cpu().registers.v[0], 27);
+
+        // Only two frames were kept
+        assert_eq!(scheduler.rewind(5), Ok(1));
+        assert_eq!(scheduler.frame(), 2);
+        assert_eq!(scheduler.cpu().registers.v[0], 17);
+        assert_eq!(scheduler.rewind(1), Ok(0));
+
+        // Running again picks up from the rewound state
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.cpu().registers.v[0], 27);
+        assert_eq!(scheduler.rewind(1), Ok(1));
+        assert_eq!(scheduler.cpu().registers.v[0], 17);
+    }
+
+    #[test]
+    fn test_debugger_pauses_mid_frame() {
+        let mut scheduler = Scheduler::new(counter(), 10);
+        let mut debugger = Debugger::new();
+        debugger.set_breakpoint(0x208, None);
+        scheduler.attach_debugger(debugger);
+
+        scheduler.run_frame().unwrap();
+        assert!(scheduler.paused());
+        assert_eq!(scheduler.frame(), 0);
+        assert_eq!(scheduler.cpu().registers.pc, 0x208);
+        assert_eq!(scheduler.advance(FRAME_DURATION * 2), Ok(0));
+
+        // The rest of the frame runs after resuming
+        scheduler.debugger_mut().unwrap().resume();
+        scheduler.run_frame().unwrap();
+        assert_eq!(scheduler.frame(), 1);
+        assert_eq!(scheduler.cpu().registers.pc, 0x200 + 2 * 10);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut scheduler = Scheduler::new(counter(), 1);
+
+        assert_eq!(scheduler.advance(FRAME_DURATION / 2), Ok(0));
+        assert_eq!(scheduler.advance(FRAME_DURATION / 2), Ok(1));
+        assert_eq!(scheduler.advance(FRAME_DURATION * 3), Ok(3));
+
+        // Long stalls are capped instead of replayed
+        assert_eq!(
+            scheduler.advance(FRAME_DURATION * 100),
+            Ok(MAX_CATCH_UP_FRAMES)
+        );
+        assert_eq!(scheduler.frame(), 4 + MAX_CATCH_UP_FRAMES as u64);
+    }
+
+    #[test]
+    fn test_rewind_elapsed() {
+        let mut scheduler = Scheduler::new(counter(), 1);
+        scheduler.set_rewind_frames(100);
+        for _ in 0..50 {
+            scheduler.run_frame().unwrap();
+        }
+
+        assert_eq!(scheduler.rewind_elapsed(FRAME_DURATION / 2), Ok(0));
+        assert_eq!(scheduler.rewind_elapsed(FRAME_DURATION / 2), Ok(1));
+        assert_eq!(scheduler.rewind_elapsed(FRAME_DURATION * 3), Ok(3));
+        assert_eq!(
+            scheduler.rewind_elapsed(FRAME_DURATION * 100),
+            Ok(MAX_CATCH_UP_FRAMES)
+        );
+        assert_eq!(scheduler.frame(), 46 - MAX_CATCH_UP_FRAMES as u64);
+    }
+}
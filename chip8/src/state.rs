@@ -0,0 +1,100 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::StateError;
+
+/// Bumped whenever the layout of a save state changes
+pub const STATE_VERSION: u16 = 2;
+const MAGIC: [u8; 4] = *b"C8ST";
+
+/// SHA-1 of the ROM a save state belongs to
+pub type RomHash = [u8; 20];
+
+pub fn rom_hash(rom: &[u8]) -> RomHash {
+    sha1_smol::Sha1::from(rom).digest().bytes()
+}
+
+/// The hash as lowercase hex, the usual way ROM databases identify a program
+pub fn rom_hash_hex(hash: &RomHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u16,
+    rom_hash: RomHash,
+}
+
+/// Serializes `body` behind a header identifying the format version and the ROM
+pub(crate) fn encode<T: Serialize>(rom_hash: RomHash, body: &T) -> Vec<u8> {
+    let header = Header {
+        magic: MAGIC,
+        version: STATE_VERSION,
+        rom_hash,
+    };
+    let mut bytes = bincode::serialize(&header).expect("Unable to serialize header");
+    bincode::serialize_into(&mut bytes, body).expect("Unable to serialize state");
+    bytes
+}
+
+/// Checks the header written by [`encode`] and deserializes the body
+pub(crate) fn decode<T: DeserializeOwned>(
+    bytes: &[u8],
+    rom_hash: RomHash,
+) -> Result<T, StateError> {
+    let mut reader = bytes;
+    let header: Header =
+        bincode::deserialize_from(&mut reader).map_err(|_| StateError::InvalidFormat)?;
+    if header.magic != MAGIC {
+        return Err(StateError::InvalidFormat);
+    }
+    if header.version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion {
+            version: header.version,
+        });
+    }
+    if header.rom_hash != rom_hash {
+        return Err(StateError::RomMismatch);
+    }
+
+    bincode::deserialize_from(&mut reader).map_err(|err| StateError::Corrupt(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let hash = rom_hash(b"rom");
+        let bytes = encode(hash, &(1u8, 0x1234u16));
+        assert_eq!(decode::<(u8, u16)>(&bytes, hash), Ok((1, 0x1234)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let hash = rom_hash(b"rom");
+        let bytes = encode(hash, &0u8);
+
+        assert_eq!(
+            decode::<u8>(&bytes, rom_hash(b"other rom")),
+            Err(StateError::RomMismatch)
+        );
+        assert_eq!(
+            decode::<u8>(b"not a state", hash),
+            Err(StateError::InvalidFormat)
+        );
+        assert!(matches!(
+            decode::<u64>(&bytes, hash),
+            Err(StateError::Corrupt(_))
+        ));
+
+        let mut bytes = bytes;
+        bytes[4] = 0xFF;
+        assert_eq!(
+            decode::<u8>(&bytes, hash),
+            Err(StateError::UnsupportedVersion { version: 0x00FF })
+        );
+    }
+}
@@ -1,82 +1,527 @@
-use crate::cpu;
+use crate::audio::AudioSink;
 use crate::cpu::Cpu;
-use std::sync::{Arc, Mutex};
-use std::thread;
-
-const CPU_CYCLE_TIME: std::time::Duration =
-    std::time::Duration::from_millis((1.0 / cpu::FREQUENCY as f32 * 1000.0) as u64);
-
-const TIMER_CYCLE_TIME: std::time::Duration =
-    std::time::Duration::from_millis((1.0 / 60.0 * 1000.0) as u64); // 60 Hz
+use crate::database::{Database, RomInfo};
+use crate::debugger::Debugger;
+use crate::display::Pixel;
+use crate::error::{CpuError, StateError};
+use crate::movie::Movie;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::rewind::DEFAULT_REWIND_FRAMES;
+use crate::rom::RomImage;
+use crate::scheduler::{Scheduler, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::trace::Tracer;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
 
 pub struct SystemBuilder<'a> {
-    rom: &'a [u8],
+    rom: &'a RomImage,
+    quirks: Quirks,
+    platform: Platform,
+    instructions_per_frame: u32,
+    stack_depth: Option<usize>,
+    rewind_frames: usize,
+    debugger: Option<Debugger>,
+    tracer: Option<Tracer>,
+    audio: Option<Box<dyn AudioSink>>,
+    seed: Option<u64>,
+    record: bool,
+    replay: Option<Movie>,
 }
 
 impl<'a> SystemBuilder<'a> {
-    pub fn new(rom: &'a [u8]) -> SystemBuilder<'a> {
-        SystemBuilder { rom }
+    /// Runs `rom` on the platform it was checked against, with the settings the bundled ROM
+    /// database lists for it, see [`SystemBuilder::rom_info`]
+    pub fn new(rom: &'a RomImage) -> SystemBuilder<'a> {
+        let builder = SystemBuilder {
+            rom,
+            quirks: Quirks::default(),
+            platform: rom.platform(),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            stack_depth: None,
+            rewind_frames: DEFAULT_REWIND_FRAMES,
+            debugger: None,
+            tracer: None,
+            audio: None,
+            seed: None,
+            record: false,
+            replay: None,
+        };
+        match Database::bundled().lookup(&rom.sha1()) {
+            Some(info) => {
+                info!("Found {} in the ROM database", info.title);
+                builder.rom_info(&info)
+            }
+            None => {
+                info!(
+                    "ROM {} is not in the ROM database, using the default settings",
+                    rom.sha1_hex()
+                );
+                builder
+            }
+        }
+    }
+
+    /// Takes the platform, quirks and speed from a ROM database entry, the setters called
+    /// afterwards override them
+    pub fn rom_info(mut self, info: &RomInfo) -> SystemBuilder<'a> {
+        self.platform = info.platform;
+        self.quirks = info.quirks;
+        if let Some(instructions_per_frame) = info.instructions_per_frame {
+            self.instructions_per_frame = instructions_per_frame;
+        }
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> SystemBuilder<'a> {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn platform(mut self, platform: Platform) -> SystemBuilder<'a> {
+        self.platform = platform;
+        self
+    }
+
+    /// The number of instructions executed in every 60 Hz frame
+    pub fn instructions_per_frame(mut self, instructions_per_frame: u32) -> SystemBuilder<'a> {
+        self.instructions_per_frame = instructions_per_frame;
+        self
+    }
+
+    /// The number of nested subroutine calls allowed, instead of the platform's
+    /// [`Platform::stack_depth`]
+    pub fn stack_depth(mut self, stack_depth: usize) -> SystemBuilder<'a> {
+        self.stack_depth = Some(stack_depth);
+        self
+    }
+
+    /// The number of frames kept for [`System::rewind`], 0 disables rewinding
+    pub fn rewind_frames(mut self, rewind_frames: usize) -> SystemBuilder<'a> {
+        self.rewind_frames = rewind_frames;
+        self
+    }
+
+    /// Runs every instruction through `debugger`, see [`System::debugger_mut`]
+    pub fn debugger(mut self, debugger: Debugger) -> SystemBuilder<'a> {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    /// Records every executed instruction with `tracer`
+    pub fn tracer(mut self, tracer: Tracer) -> SystemBuilder<'a> {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Plays the speaker through `sink`, see [`System::finish_audio`]
+    pub fn audio_sink(mut self, sink: impl AudioSink + 'static) -> SystemBuilder<'a> {
+        self.audio = Some(Box::new(sink));
+        self
+    }
+
+    /// Seeds the random number generator behind `CXNN`, a random seed is picked by default
+    pub fn seed(mut self, seed: u64) -> SystemBuilder<'a> {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Records the keypad from the first frame on, see [`System::movie`]
+    pub fn record_movie(mut self) -> SystemBuilder<'a> {
+        self.record = true;
+        self
+    }
+
+    /// Plays back `movie`, taking the seed, platform, quirks, speed and stack depth from it
+    /// instead.
+    ///
+    /// The movie should have been decoded with the hash of this ROM, see [`Movie::decode`].
+    pub fn replay(mut self, movie: Movie) -> SystemBuilder<'a> {
+        self.replay = Some(movie);
+        self
     }
 
     pub fn run(self) -> System {
-        let cpu = Arc::new(Mutex::new(Cpu::init(self.rom)));
-
-        let tick_thread_cpu = Arc::clone(&cpu);
-        let cpu_thread = thread::spawn(move || loop {
-            tick_thread_cpu.lock().expect("Unable to lock CPU").tick();
-            thread::sleep(CPU_CYCLE_TIME); // TODO: Implement proper timing
-        });
-
-        let timer_thread_cpu = Arc::clone(&cpu);
-        let timer_thread = thread::spawn(move || loop {
-            timer_thread_cpu
-                .lock()
-                .expect("Unable to lock CPU")
-                .tick_timers();
-            thread::sleep(TIMER_CYCLE_TIME); // TODO: Implement proper timing
-        });
+        let (seed, quirks, platform, instructions_per_frame, stack_depth) = match &self.replay {
+            Some(movie) => (
+                movie.seed,
+                movie.quirks,
+                movie.platform,
+                movie.instructions_per_frame,
+                Some(movie.stack_depth),
+            ),
+            None => (
+                self.seed.unwrap_or_else(rand::random),
+                self.quirks,
+                self.platform,
+                self.instructions_per_frame,
+                self.stack_depth,
+            ),
+        };
+
+        let mut cpu = Cpu::init(self.rom.bytes());
+        cpu.quirks = quirks;
+        cpu.set_platform(platform);
+        if let Some(stack_depth) = stack_depth {
+            cpu.set_stack_depth(stack_depth);
+        }
+        cpu.seed(seed);
+
+        let mut scheduler = Scheduler::new(cpu, instructions_per_frame);
+        scheduler.set_rewind_frames(self.rewind_frames);
+        if let Some(debugger) = self.debugger {
+            scheduler.attach_debugger(debugger);
+        }
+        if let Some(tracer) = self.tracer {
+            scheduler.set_tracer(tracer);
+        }
+        if let Some(sink) = self.audio {
+            scheduler.set_audio_sink(sink);
+        }
+        if let Some(movie) = self.replay {
+            scheduler.replay_input(movie.frames);
+        } else if self.record {
+            scheduler.record_input();
+        }
 
         System {
-            cpu,
-            cpu_thread,
-            timer_thread,
+            initial_state: scheduler.cpu().save_state(),
+            scheduler,
+            fault: None,
+            last_update: None,
+            speed: 1.0,
+            seed,
         }
     }
 }
 
+/// A running machine, advanced by the caller through [`System::update`] or [`System::run_frame`]
 pub struct System {
-    pub cpu: Arc<Mutex<Cpu>>,
-    cpu_thread: thread::JoinHandle<()>,
-    timer_thread: thread::JoinHandle<()>,
+    scheduler: Scheduler,
+    fault: Option<CpuError>,
+    last_update: Option<Instant>,
+    /// Emulated time per unit of real time in [`System::update`]
+    speed: f32,
+    /// The machine as built, restored by [`System::reset`]
+    initial_state: Vec<u8>,
+    seed: u64,
 }
 
 impl System {
-    pub fn pixels(&self) -> [crate::display::Pixel; crate::display::DISPLAY_SIZE] {
-        self.cpu
-            .lock()
-            .expect("Unable to lock CPU")
-            .pixels()
-            .to_owned()
+    pub fn cpu(&self) -> &Cpu {
+        self.scheduler.cpu()
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        self.scheduler.cpu_mut()
+    }
+
+    /// Runs the frames that have become due since the previous call, pacing the system in real time
+    pub fn update(&mut self) {
+        let elapsed = self.elapsed();
+        if self.fault.is_none() {
+            let result = self
+                .scheduler
+                .advance(elapsed.mul_f32(self.speed))
+                .map(|_| ());
+            self.record(result);
+        }
+    }
+
+    /// Rewinds as many frames as fit in the real time since the last update, at the same pace
+    /// [`System::update`] runs them forwards. Clears any fault like [`System::rewind`].
+    ///
+    /// Returns the number of frames that were rewound.
+    pub fn update_rewind(&mut self) -> Result<u32, StateError> {
+        let elapsed = self.elapsed();
+        let rewound = self.scheduler.rewind_elapsed(elapsed)?;
+        if rewound > 0 {
+            self.fault = None;
+        }
+        Ok(rewound)
+    }
+
+    /// Real time since the last update
+    fn elapsed(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = self
+            .last_update
+            .map_or_else(Default::default, |last| now - last);
+        self.last_update = Some(now);
+        elapsed
+    }
+
+    /// Scales how fast [`System::update`] runs, 0 stops the clock and 2 runs at double speed
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Restarts the program from the state it was built with, clearing any fault
+    pub fn reset(&mut self) {
+        self.scheduler
+            .restart(&self.initial_state)
+            .expect("Initial state is always valid");
+        self.fault = None;
+        self.last_update = None;
+    }
+
+    /// Runs a single frame immediately, regardless of how much time has passed
+    pub fn run_frame(&mut self) {
+        if self.fault.is_none() {
+            let result = self.scheduler.run_frame();
+            self.record(result);
+        }
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.scheduler.debugger()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.scheduler.debugger_mut()
+    }
+
+    /// Returns whether the debugger is holding the CPU
+    pub fn paused(&self) -> bool {
+        self.scheduler.paused()
+    }
+
+    fn record(&mut self, result: Result<(), CpuError>) {
+        if let Err(err) = result {
+            error!("CPU halted: {}", err);
+            self.fault = Some(err);
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.scheduler.cpu().save_state()
+    }
+
+    /// Restores a state from [`System::save_state`], clearing any fault.
+    ///
+    /// Refused while a movie is recording or replaying, the movie would no longer match the run.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        if self.scheduler.recording().is_some() || self.scheduler.replaying() {
+            return Err(StateError::MovieActive);
+        }
+        self.scheduler.cpu_mut().load_state(bytes)?;
+        self.fault = None;
+        Ok(())
+    }
+
+    /// Steps back up to `frames` frames, clearing any fault.
+    ///
+    /// Returns the number of frames that were actually rewound. Real time pacing restarts from
+    /// the next [`System::update`], so the rewound frames aren't immediately replayed.
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, StateError> {
+        let rewound = self.scheduler.rewind(frames)?;
+        if rewound > 0 {
+            self.fault = None;
+        }
+        self.last_update = None;
+        Ok(rewound)
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.scheduler.frame()
+    }
+
+    pub fn pixels(&mut self) -> Vec<Pixel> {
+        self.scheduler.cpu_mut().pixels()
+    }
+
+    /// The current width and height of the display in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        self.scheduler.cpu().resolution()
     }
 
     pub fn has_new_frame(&self) -> bool {
-        self.cpu.lock().expect("Unable to lock CPU").drawing
+        self.scheduler.cpu().drawing
+    }
+
+    pub fn clear_new_frame(&mut self) {
+        self.scheduler.cpu_mut().drawing = false;
+    }
+
+    /// Presses a key, see [`Scheduler::key_down`] for how movies affect this
+    pub fn key_down(&mut self, key: u8) {
+        self.scheduler.key_down(key);
+    }
+
+    /// Releases a key, see [`Scheduler::key_up`] for how movies affect this
+    pub fn key_up(&mut self, key: u8) {
+        self.scheduler.key_up(key);
+    }
+
+    /// The seed of the random number generator behind `CXNN`
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The run so far, if the system was built with [`SystemBuilder::record_movie`]
+    pub fn movie(&self) -> Option<Movie> {
+        let frames = self.scheduler.recording()?;
+        let cpu = self.scheduler.cpu();
+        Some(Movie {
+            rom_hash: cpu.rom_hash(),
+            seed: self.seed,
+            platform: cpu.platform(),
+            quirks: cpu.quirks,
+            instructions_per_frame: self.scheduler.instructions_per_frame(),
+            stack_depth: cpu.stack_depth(),
+            frames: frames.to_vec(),
+        })
+    }
+
+    /// Returns whether a movie from [`SystemBuilder::replay`] is still playing
+    pub fn replaying(&self) -> bool {
+        self.scheduler.replaying()
+    }
+
+    /// Returns the fault that stopped the CPU, if any
+    pub fn fault(&self) -> Option<CpuError> {
+        self.fault
+    }
+
+    /// Returns whether the program stopped itself with `00FD`
+    pub fn exited(&self) -> bool {
+        self.scheduler.exited()
+    }
+
+    /// Detaches the audio sink and flushes its output, e.g. to finish writing a WAV file
+    pub fn finish_audio(&mut self) -> std::io::Result<()> {
+        self.scheduler.finish_audio()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_frame() {
+        // LD V0, 1; loop: ADD V0, 1; JP loop
+        let mut system = SystemBuilder::new(
+            &RomImage::new([0x60, 0x01, 0x70, 0x01, 0x12, 0x02], Platform::Chip8).unwrap(),
+        )
+        .instructions_per_frame(5)
+        .run();
+
+        system.run_frame();
+        system.run_frame();
+        assert_eq!(system.frame(), 2);
+        assert_eq!(system.cpu().registers.v[0], 6);
+        assert_eq!(system.fault(), None);
     }
 
-    pub fn clear_new_frame(&self) {
-        self.cpu.lock().expect("Unable to lock CPU").drawing = false;
+    #[test]
+    fn test_rewind_clears_fault() {
+        // LD V0, 1; ADD V0, 1; invalid
+        let mut system = SystemBuilder::new(
+            &RomImage::new([0x60, 0x01, 0x70, 0x01, 0xFF, 0xFF], Platform::Chip8).unwrap(),
+        )
+        .instructions_per_frame(1)
+        .run();
+
+        system.run_frame();
+        system.run_frame();
+        system.run_frame();
+        assert!(system.fault().is_some());
+        assert_eq!(system.frame(), 2);
+
+        assert_eq!(system.rewind(1), Ok(1));
+        assert_eq!(system.fault(), None);
+        assert_eq!(system.cpu().registers.v[0], 1);
+        assert_eq!(system.cpu().registers.pc, 0x202);
+    }
+
+    #[test]
+    fn test_reset() {
+        // LD V0, 1; ADD V0, 1; invalid
+        let mut system = SystemBuilder::new(
+            &RomImage::new([0x60, 0x01, 0x70, 0x01, 0xFF, 0xFF], Platform::Chip8).unwrap(),
+        )
+        .instructions_per_frame(1)
+        .run();
+
+        for _ in 0..3 {
+            system.run_frame();
+        }
+        assert!(system.fault().is_some());
+
+        system.reset();
+        assert_eq!(system.fault(), None);
+        assert_eq!(system.frame(), 0);
+        assert_eq!(system.cpu().registers.pc, 0x200);
+        assert_eq!(system.cpu().registers.v[0], 0);
     }
 
-    pub fn key_down(&self, key: u8) {
-        self.cpu.lock().expect("Unable to lock CPU").key_down(key);
+    #[test]
+    fn test_rom_info() {
+        let info = RomInfo {
+            title: "Test".into(),
+            platform: Platform::XoChip,
+            quirks: Quirks::XO_CHIP,
+            instructions_per_frame: Some(2),
+            colors: None,
+            keys: Default::default(),
+            screen_rotation: 0,
+        };
+        // LD V0, 1; ADD V0, 1; JP 0x202
+        let rom = RomImage::new([0x60, 0x01, 0x70, 0x01, 0x12, 0x02], Platform::Chip8).unwrap();
+        let mut system = SystemBuilder::new(&rom)
+            .rom_info(&info)
+            .quirks(Quirks::VIP)
+            .run();
+
+        system.run_frame();
+        assert_eq!(system.cpu().platform(), Platform::XoChip);
+        assert_eq!(system.cpu().quirks, Quirks::VIP);
+        assert_eq!(system.cpu().registers.v[0], 2);
     }
 
-    pub fn key_up(&self, key: u8) {
-        self.cpu.lock().expect("Unable to lock CPU").key_up(key);
+    #[test]
+    fn test_fault_stops_system() {
+        let mut system =
+            SystemBuilder::new(&RomImage::new([0x00, 0xE0, 0xFF, 0xFF], Platform::Chip8).unwrap())
+                .run();
+
+        system.run_frame();
+        assert_eq!(
+            system.fault(),
+            Some(CpuError::InvalidOpcode {
+                addr: 0x202,
+                opcode: 0xFFFF
+            })
+        );
+
+        system.run_frame();
+        assert_eq!(system.frame(), 0);
     }
 
-    pub fn stop(self) {
-        let _ = self.cpu_thread.join();
-        let _ = self.timer_thread.join();
+    #[test]
+    fn test_stack_depth() {
+        // CALL 0x200, recursing until the stack runs out
+        let rom = RomImage::new([0x22, 0x00], Platform::SuperChip).unwrap();
+        let mut system = SystemBuilder::new(&rom).stack_depth(4).run();
+        assert_eq!(system.cpu().stack_depth(), 4);
+
+        system.run_frame();
+        assert_eq!(
+            system.fault(),
+            Some(CpuError::StackOverflow {
+                addr: 0x200,
+                depth: 4
+            })
+        );
+        assert_eq!(system.cpu().stack().len(), 4);
+
+        let system = SystemBuilder::new(&rom).run();
+        assert_eq!(system.cpu().stack_depth(), 16);
     }
 }
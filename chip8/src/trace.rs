@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, trace};
+
+use crate::cpu::Cpu;
+use crate::data::{Address, OpCode};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One `PC | OPCODE | MNEMONIC | BEFORE | AFTER` line per instruction
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// The registers and timers on either side of an instruction
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterState {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl RegisterState {
+    pub fn of(cpu: &Cpu) -> RegisterState {
+        RegisterState {
+            v: cpu.registers.v,
+            i: cpu.registers.i,
+            sp: cpu.registers.sp as u8,
+            dt: cpu.delay_timer(),
+            st: cpu.sound_timer(),
+        }
+    }
+}
+
+impl fmt::Display for RegisterState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "V=")?;
+        for v in self.v {
+            write!(f, "{:02X}", v)?;
+        }
+        write!(
+            f,
+            " I={:04X} SP={:02X} DT={:02X} ST={:02X}",
+            self.i, self.sp, self.dt, self.st
+        )
+    }
+}
+
+/// A single executed instruction.
+///
+/// `opcode` is the first word of the instruction, the mnemonic includes the operand of long
+/// XO-CHIP instructions.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    pub pc: Address,
+    pub opcode: OpCode,
+    pub mnemonic: String,
+    pub before: RegisterState,
+    pub after: RegisterState,
+}
+
+impl TraceEntry {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Unable to serialize trace entry")
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04X} | {:04X} | {} | {} | {}",
+            self.pc, self.opcode, self.mnemonic, self.before, self.after
+        )
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write>),
+    /// `trace` level events on the `chip8::trace` target
+    Log,
+    /// Writing failed, tracing stopped
+    Closed,
+}
+
+/// Records every executed instruction, see [`crate::system::SystemBuilder::tracer`]
+pub struct Tracer {
+    output: Output,
+    format: TraceFormat,
+    range: Option<RangeInclusive<Address>>,
+    /// The instruction about to run, filled in by [`Tracer::before`] except for the state after
+    /// it
+    pending: Option<TraceEntry>,
+}
+
+impl Tracer {
+    /// Writes the trace to `writer`, which is flushed at the end of every frame
+    pub fn new(writer: impl Write + 'static, format: TraceFormat) -> Tracer {
+        Tracer {
+            output: Output::Writer(Box::new(writer)),
+            format,
+            range: None,
+            pending: None,
+        }
+    }
+
+    /// Emits the trace as `tracing` events instead, on the `chip8::trace` target
+    pub fn log(format: TraceFormat) -> Tracer {
+        Tracer {
+            output: Output::Log,
+            format,
+            range: None,
+            pending: None,
+        }
+    }
+
+    /// Only traces instructions with an address in `range`
+    pub fn range(mut self, range: RangeInclusive<Address>) -> Tracer {
+        self.range = Some(range);
+        self
+    }
+
+    /// Captures the instruction at the PC and the state before it runs. The instruction is read
+    /// now since it might overwrite itself.
+    pub(crate) fn before(&mut self, cpu: &Cpu) {
+        let pc = cpu.registers.pc;
+        let traced = self.range.as_ref().is_none_or(|range| range.contains(&pc));
+        self.pending = match cpu.read_memory(pc, 2).as_slice() {
+            [high, low] if traced => Some(TraceEntry {
+                pc,
+                opcode: u16::from_be_bytes([*high, *low]),
+                mnemonic: cpu
+                    .instruction_at(pc)
+                    .map_or_else(|| "???".to_string(), |instr| instr.to_string()),
+                before: RegisterState::of(cpu),
+                after: RegisterState::default(),
+            }),
+            _ => None,
+        };
+    }
+
+    /// Emits the instruction captured by [`Tracer::before`], once it has run
+    pub(crate) fn after(&mut self, cpu: &Cpu) {
+        let Some(mut entry) = self.pending.take() else {
+            return;
+        };
+        entry.after = RegisterState::of(cpu);
+
+        let line = match self.format {
+            TraceFormat::Text => entry.to_string(),
+            TraceFormat::Json => entry.to_json(),
+        };
+        match &mut self.output {
+            Output::Writer(writer) => {
+                if let Err(err) = writeln!(writer, "{}", line) {
+                    error!("Could not write trace, tracing stopped: {}", err);
+                    self.output = Output::Closed;
+                }
+            }
+            Output::Log => trace!(target: "chip8::trace", "{}", line),
+            Output::Closed => {}
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Output::Writer(writer) = &mut self.output {
+            if let Err(err) = writer.flush() {
+                error!("Could not write trace, tracing stopped: {}", err);
+                self.output = Output::Closed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::instruction::Instruction;
+
+    /// A writer that can still be read after it was handed to a tracer
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(mut tracer: Tracer, rom: &[u8], ticks: usize) {
+        let mut cpu = Cpu::init(rom);
+        for _ in 0..ticks {
+            tracer.before(&cpu);
+            cpu.tick().unwrap();
+            tracer.after(&cpu);
+        }
+        tracer.flush();
+    }
+
+    #[test]
+    fn test_text() {
+        let output = Shared::default();
+        // LD V1, 0x42; LD I, 0x300
+        run(
+            Tracer::new(output.clone(), TraceFormat::Text),
+            &[0x61, 0x42, 0xA3, 0x00],
+            2,
+        );
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "0200 | 6142 | LD V1, 0x42 \
+            | V=00000000000000000000000000000000 I=0000 SP=00 DT=00 ST=00 \
+            | V=00420000000000000000000000000000 I=0000 SP=00 DT=00 ST=00"
+        );
+        assert!(lines[1].starts_with("0202 | A300 | LD I, 0x300 | "));
+        assert!(lines[1].ends_with("I=0300 SP=00 DT=00 ST=00"));
+    }
+
+    #[test]
+    fn test_json_and_range() {
+        let output = Shared::default();
+        run(
+            Tracer::new(output.clone(), TraceFormat::Json).range(0x202..=0x2FF),
+            &[0x61, 0x42, 0xA3, 0x00],
+            2,
+        );
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+
+        let entry: TraceEntry = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry.pc, 0x202);
+        assert_eq!(entry.opcode, 0xA300);
+        assert_eq!(entry.before.v[1], 0x42);
+        assert_eq!(entry.after.i, 0x300);
+    }
+
+    #[test]
+    fn test_self_modifying() {
+        let output = Shared::default();
+        // LD I, 0x206; LD V0, 0x00; LD V1, 0xE0; LD [I], V0-V1 over itself
+        let rom = [0xA2, 0x06, 0x60, 0x00, 0x61, 0xE0, 0xF1, 0x55];
+        run(Tracer::new(output.clone(), TraceFormat::Json), &rom, 4);
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let entry: TraceEntry = serde_json::from_str(text.lines().last().unwrap()).unwrap();
+        assert_eq!(entry.pc, 0x206);
+        assert_eq!(entry.opcode, 0xF155);
+        assert_eq!(entry.mnemonic, Instruction::Store(1).to_string());
+    }
+}